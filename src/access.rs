@@ -0,0 +1,237 @@
+
+use std::path::Path;
+
+use rocket::http::Status;
+use rocket::request::Outcome;
+use rocket::request::Request;
+use rocket::request::FromRequest;
+use rocket::serde::Serialize;
+use rocket::serde::Deserialize;
+use rocket::tokio::sync::Mutex;
+
+use crate::util::Util;
+use crate::config::Config;
+use crate::config::Storage;
+use crate::model::User;
+use crate::model::Grant;
+use crate::model::Permission;
+use crate::model::FileDefinition;
+use crate::auth::Authenticated;
+
+
+/// Users and grants shared by the `Caller` guard and the access admin routes.
+pub type Access = Mutex<AccessStore>;
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(crate = "rocket::serde")]
+struct AccessState {
+    users: Vec<User>,
+    grants: Vec<Grant>
+}
+
+/// Users, their groups and the grants given to either, kept in `.access.json` under the state path.
+pub struct AccessStore {
+    path: Option<String>,       // Where users and grants are saved on every change, if anywhere.
+    state: AccessState
+}
+impl AccessStore {
+    pub fn open(config: &Config) -> Result<Self, String> {
+        if matches!(config.storage, Storage::Memory) {
+            return Ok(Self::in_memory());
+        }
+        let path = Self::get_default_path(config.get_state_path());
+        let state = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).map_err(|e| format!("Unable to read {path}: {e}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => AccessState::default(),
+            Err(e) => return Err(e.to_string()),
+        };
+        Ok(Self { path: Some(path), state })
+    }
+    pub fn in_memory() -> Self {
+        Self { path: None, state: AccessState::default() }
+    }
+
+    pub fn get_users(&self) -> &[User] {
+        &self.state.users
+    }
+
+    pub fn get_user(&self, name: &str) -> Option<&User> {
+        self.state.users.iter().find(|u| u.name == name)
+    }
+
+    /// Creates the user, or replaces its groups if it exists.
+    pub fn put_user(&mut self, user: User) -> Result<(), String> {
+        if user.name.is_empty() || user.groups.iter().any(|group| group.is_empty()) {
+            return Err("User and group names can't be empty".to_string());
+        }
+        let previous = self.state.users.iter().position(|u| u.name == user.name)
+                .map(|index| self.state.users.remove(index));
+        self.state.users.push(user.clone());
+        if let Err(e) = self.save() {
+            self.state.users.retain(|u| u.name != user.name);
+            self.state.users.extend(previous);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Removes the user along with the grants given to it. Returns false if there is no such user.
+    pub fn delete_user(&mut self, name: &str) -> Result<bool, String> {
+        if self.get_user(name).is_none() {
+            return Ok(false);
+        }
+        let users = self.state.users.clone();
+        let grants = self.state.grants.clone();
+        self.state.users.retain(|u| u.name != name);
+        self.state.grants.retain(|g| g.user.as_deref() != Some(name));
+        if let Err(e) = self.save() {
+            self.state.users = users;
+            self.state.grants = grants;
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    pub fn get_grants(&self) -> &[Grant] {
+        &self.state.grants
+    }
+
+    /// Adds the grant under a new id, which is returned along with it.
+    pub fn add_grant(&mut self, grant: &Grant) -> Result<Grant, String> {
+        if grant.user.is_some() == grant.group.is_some() {
+            return Err("A grant is given to either a user or a group".to_string());
+        }
+        if grant.repository.is_empty() {
+            return Err("A grant needs a repository, or * for all of them".to_string());
+        }
+        let Some(path) = Self::normalize(&grant.path) else {
+            return Err("Grant paths can't contain '..'".to_string());
+        };
+        let mut grant = grant.clone();
+        grant.id = Some(Util::new_id());
+        grant.path = path;
+        self.state.grants.push(grant.clone());
+        if let Err(e) = self.save() {
+            self.state.grants.pop();
+            return Err(e);
+        }
+        Ok(grant)
+    }
+
+    /// Returns false if there is no grant with that id.
+    pub fn revoke_grant(&mut self, id: &str) -> Result<bool, String> {
+        let Some(index) = self.state.grants.iter().position(|g| g.id.as_deref() == Some(id)) else {
+            return Ok(false);
+        };
+        let revoked = self.state.grants.remove(index);
+        if let Err(e) = self.save() {
+            self.state.grants.insert(index, revoked);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Grants applying to the user, directly or through one of its groups.
+    fn grants_for(&self, name: &str) -> Vec<Grant> {
+        let groups = self.get_user(name).map(|u| u.groups.clone()).unwrap_or_default();
+        self.state.grants.iter()
+                .filter(|g| g.user.as_deref() == Some(name)
+                        || g.group.as_ref().is_some_and(|group| groups.contains(group)))
+                .cloned()
+                .collect()
+    }
+
+    /// `path` without empty or `.` segments, or `None` if it has a `..` one.
+    fn normalize(path: &str) -> Option<String> {
+        let mut segments = Vec::new();
+        for segment in path.split(['/', '\\']) {
+            match segment {
+                "" | "." => {},
+                ".." => return None,
+                segment => segments.push(segment),
+            }
+        }
+        Some(segments.join("/"))
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let content = serde_json::to_vec_pretty(&self.state).map_err(|e| e.to_string())?;
+        Util::write_file_atomic(path, &content).map_err(|e| format!("Unable to save users and grants: {e}"))
+    }
+
+    pub fn get_default_path(state_path: &str) -> String {
+        let binding = Path::new(state_path).join(".access.json");
+        binding.to_str().unwrap().to_string()
+    }
+}
+
+
+/// Who a request comes from and what it may access. Requests with auth disabled and admin tokens
+/// are unrestricted; the others get their user's grants only, and none without a user.
+pub struct Caller {
+    grants: Option<Vec<Grant>>     // `None` when unrestricted.
+}
+impl Caller {
+    pub fn unrestricted() -> Self {
+        Self { grants: None }
+    }
+    pub fn with_grants(grants: Vec<Grant>) -> Self {
+        Self { grants: Some(grants) }
+    }
+
+    /// Highest permission on `path` in `repository`, if any. Paths with `..` get none.
+    pub fn permission(&self, repository: &str, path: &str) -> Option<Permission> {
+        let path = AccessStore::normalize(path)?;
+        let Some(grants) = &self.grants else {
+            return Some(Permission::Admin);
+        };
+        grants.iter()
+                .filter(|g| g.repository == "*" || g.repository == repository)
+                .filter(|g| g.path.is_empty() || path == g.path || path.starts_with(&format!("{}/", g.path)))
+                .map(|g| g.permission)
+                .max()
+    }
+
+    pub fn can(&self, repository: &str, path: &str, needed: Permission) -> bool {
+        self.permission(repository, path).is_some_and(|permission| permission >= needed)
+    }
+
+    pub fn can_read(&self, repository: &str, file_def: &FileDefinition) -> bool {
+        self.can(repository, &file_def.path, Permission::Read)
+    }
+
+    /// Whether any grant reaches into the repository, to list it.
+    pub fn can_see(&self, repository: &str) -> bool {
+        match &self.grants {
+            Some(grants) => grants.iter().any(|g| g.repository == "*" || g.repository == repository),
+            None => true,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Caller {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = match request.guard::<Authenticated>().await {
+            Outcome::Success(Authenticated(token)) => token,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        let Some(token) = token.filter(|token| !token.admin) else {
+            return Outcome::Success(Caller::unrestricted());
+        };
+        let Some(user) = token.user else {
+            return Outcome::Success(Caller::with_grants(Vec::new()));
+        };
+        let Some(access) = request.rocket().state::<Access>() else {
+            return Outcome::Error((Status::InternalServerError, "No access store".to_string()));
+        };
+        let grants = access.lock().await.grants_for(&user);
+        Outcome::Success(Caller::with_grants(grants))
+    }
+}
@@ -0,0 +1,218 @@
+
+use std::fs::File;
+use std::path::Path;
+use std::time::SystemTime;
+
+use rocket::http::Status;
+use rocket::request::Outcome;
+use rocket::request::Request;
+use rocket::request::FromRequest;
+use rocket::serde::Serialize;
+use rocket::serde::Deserialize;
+use rocket::tokio::sync::Mutex;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::util::Util;
+use crate::config::Config;
+use crate::config::Storage;
+use crate::model::User;
+use crate::model::ApiToken;
+use crate::model::MintedToken;
+
+
+/// Token store shared by the auth guards and the token admin routes.
+pub type Tokens = Mutex<TokenStore>;
+
+/// Token as kept on disk, with only the SHA-256 of the secret.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+struct StoredToken {
+    #[serde(flatten)]
+    details: ApiToken,
+    hash: String
+}
+
+/// Bearer tokens accepted by the API, kept in `.tokens.json` under the state path.
+/// Tokens are random, so a plain hash is enough to keep a leaked file from being replayed.
+/// The file is shared with `--mint-token` and `--revoke-token` run alongside the server: it is read
+/// again whenever it changed, and changes are made under `.tokens.json.lock` on the latest content.
+pub struct TokenStore {
+    enabled: bool,              // With `auth = false` every request is let through.
+    path: Option<String>,       // Where tokens are saved on every change, if anywhere.
+    loaded: Option<(SystemTime, u64)>,      // Modification time and length of the file as last read.
+    tokens: Vec<StoredToken>
+}
+impl TokenStore {
+    pub fn open(config: &Config) -> Result<Self, String> {
+        if matches!(config.storage, Storage::Memory) {
+            return Ok(Self::in_memory(config.auth));
+        }
+        let mut store = Self { enabled: config.auth, path: Some(Self::get_default_path(config.get_state_path())), loaded: None, tokens: Vec::new() };
+        store.reload()?;
+        Ok(store)
+    }
+    pub fn in_memory(enabled: bool) -> Self {
+        Self { enabled, path: None, loaded: None, tokens: Vec::new() }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn get_tokens(&self) -> Vec<ApiToken> {
+        self.tokens.iter().map(|t| t.details.clone()).collect()
+    }
+
+    /// Token presented as `secret`, if it is a current one.
+    pub fn authenticate(&mut self, secret: &str) -> Option<ApiToken> {
+        if let Err(e) = self.reload() {
+            println!("[Error [authenticate]: Unable to reload tokens: {e}");
+        }
+        let hash = Self::hash(secret);
+        self.tokens.iter()
+                .find(|t| t.hash == hash)
+                .map(|t| t.details.clone())
+    }
+
+    /// Mints a token acting as `user`, limited to that user's grants unless it is an admin token.
+    /// Other tokens without a user get no access to repositories.
+    pub fn mint(&mut self, name: &str, admin: bool, user: Option<&User>) -> Result<MintedToken, String> {
+        if name.is_empty() {
+            return Err("Token name can't be empty".to_string());
+        }
+        let _lock = self.lock_file()?;
+        self.reload()?;
+        let token = hex::encode(rand::random::<[u8; 32]>());
+        let details = ApiToken {
+            id: Util::new_id(),
+            name: name.to_string(),
+            admin,
+            user: user.map(|user| user.name.clone()),
+            created: SystemTime::now()
+        };
+        self.tokens.push(StoredToken { details: details.clone(), hash: Self::hash(&token) });
+        if let Err(e) = self.save() {
+            self.tokens.pop();
+            return Err(format!("Unable to save tokens: {e}"));
+        }
+        Ok(MintedToken { token, details })
+    }
+
+    /// Returns false if there is no token with that id.
+    pub fn revoke(&mut self, id: &str) -> Result<bool, String> {
+        let _lock = self.lock_file()?;
+        self.reload()?;
+        let Some(index) = self.tokens.iter().position(|t| t.details.id == id) else {
+            return Ok(false);
+        };
+        let revoked = self.tokens.remove(index);
+        if let Err(e) = self.save() {
+            self.tokens.insert(index, revoked);
+            return Err(format!("Unable to save tokens: {e}"));
+        }
+        Ok(true)
+    }
+
+    fn hash(secret: &str) -> String {
+        hex::encode(Sha256::digest(secret.as_bytes()))
+    }
+
+    /// Reads the tokens file again if it changed since it was last read.
+    fn reload(&mut self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.tokens.clear();
+                self.loaded = None;
+                return Ok(());
+            },
+            Err(e) => return Err(e.to_string()),
+        };
+        let stamp = (metadata.modified().map_err(|e| e.to_string())?, metadata.len());
+        if self.loaded == Some(stamp) {
+            return Ok(());
+        }
+        let content = std::fs::read(path).map_err(|e| e.to_string())?;
+        self.tokens = serde_json::from_slice(&content).map_err(|e| format!("Unable to read {path}: {e}"))?;
+        self.loaded = Some(stamp);
+        Ok(())
+    }
+
+    fn save(&mut self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let content = serde_json::to_vec_pretty(&self.tokens).map_err(|e| e.to_string())?;
+        Util::write_file_atomic(path, &content)?;
+        let metadata = std::fs::metadata(path).map_err(|e| e.to_string())?;
+        self.loaded = Some((metadata.modified().map_err(|e| e.to_string())?, metadata.len()));
+        Ok(())
+    }
+
+    /// Exclusive lock on the tokens file, held while it is read, changed and saved.
+    fn lock_file(&self) -> Result<Option<File>, String> {
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        if let Some(parent) = Path::new(path).parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let file = File::create(format!("{path}.lock")).map_err(|e| e.to_string())?;
+        file.lock().map_err(|e| e.to_string())?;
+        Ok(Some(file))
+    }
+
+    pub fn get_default_path(state_path: &str) -> String {
+        let binding = Path::new(state_path).join(".tokens.json");
+        binding.to_str().unwrap().to_string()
+    }
+}
+
+
+/// Request carrying a current `Authorization: Bearer <token>`, 401 otherwise.
+/// Holds no token when auth is disabled.
+pub struct Authenticated(pub Option<ApiToken>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authenticated {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(tokens) = request.rocket().state::<Tokens>() else {
+            return Outcome::Error((Status::InternalServerError, "No token store".to_string()));
+        };
+        let mut tokens = tokens.lock().await;
+        if !tokens.is_enabled() {
+            return Outcome::Success(Authenticated(None));
+        }
+        let secret = request.headers().get_one("Authorization")
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(|secret| secret.trim());
+        match secret.and_then(|secret| tokens.authenticate(secret)) {
+            Some(token) => Outcome::Success(Authenticated(Some(token))),
+            None => Outcome::Error((Status::Unauthorized, "Missing or invalid bearer token".to_string())),
+        }
+    }
+}
+
+/// Request authenticated with an admin token, 403 for other tokens.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.guard::<Authenticated>().await {
+            Outcome::Success(Authenticated(Some(token))) if !token.admin =>
+                Outcome::Error((Status::Forbidden, "Admin token required".to_string())),
+            Outcome::Success(_) => Outcome::Success(Admin),
+            Outcome::Error(e) => Outcome::Error(e),
+            Outcome::Forward(status) => Outcome::Forward(status),
+        }
+    }
+}
@@ -0,0 +1,431 @@
+
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::future::Future;
+use std::task::Context;
+use std::task::Poll;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+
+use fastcdc::v2020::FastCDC;
+use serde::Serialize;
+use serde::Deserialize;
+use rocket::tokio::fs;
+use rocket::tokio::fs::File;
+use rocket::tokio::io::ReadBuf;
+use rocket::tokio::io::AsyncRead;
+use rocket::tokio::io::AsyncReadExt;
+use rocket::tokio::io::AsyncSeekExt;
+use rocket::tokio::io::AsyncWriteExt;
+use sha2::Digest;
+use sha2::Sha256;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::util::Util;
+use crate::model::FileDefinition;
+use crate::io_manager::IOManager;
+use crate::io_manager::FolderIOManager;
+use crate::io_manager::ContentReader;
+use crate::io_manager::StoredContent;
+
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ChunkRef {
+    hash: String,
+    size: u64
+}
+
+/// Content of a file as the ordered list of chunks it is made of.
+#[derive(Serialize, Deserialize, Default)]
+struct ChunkManifest {
+    size: u64,
+    chunks: Vec<ChunkRef>
+}
+
+
+/// Which chunks are in use, so unreferenced ones can be removed without reading every manifest.
+#[derive(Default)]
+struct ChunkRefs {
+    counts: HashMap<String, u64>,       // References from manifests, live, versions and trash alike.
+    pins: HashMap<String, u64>,         // Open readers of each chunk.
+    deferred: HashSet<String>           // Unreferenced but pinned, removed once their readers are done.
+}
+impl ChunkRefs {
+    fn add(&mut self, manifest: &ChunkManifest) {
+        for chunk in &manifest.chunks {
+            *self.counts.entry(chunk.hash.clone()).or_default() += 1;
+        }
+    }
+    /// Drops the references of `manifest`, returning the chunks it left unreferenced.
+    fn remove(&mut self, manifest: &ChunkManifest) -> Vec<String> {
+        let mut unreferenced = Vec::new();
+        for chunk in &manifest.chunks {
+            if let Some(count) = self.counts.get_mut(&chunk.hash) {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(&chunk.hash);
+                    unreferenced.push(chunk.hash.clone());
+                }
+            }
+        }
+        unreferenced
+    }
+}
+
+/// Chunks held by a reader, which garbage collection leaves in place until it is dropped.
+struct ChunkPin {
+    hashes: Vec<String>,
+    refs: Arc<Mutex<ChunkRefs>>
+}
+impl Drop for ChunkPin {
+    fn drop(&mut self) {
+        let mut refs = self.refs.lock().expect("Chunk references poisoned");
+        for hash in &self.hashes {
+            if let Some(pins) = refs.pins.get_mut(hash) {
+                *pins -= 1;
+                if *pins == 0 {
+                    refs.pins.remove(hash);
+                }
+            }
+        }
+    }
+}
+
+
+/// Content-addressed storage: file content is split with FastCDC and every distinct chunk
+/// is stored once under `.chunks`, while `.manifests/<id>.json` lists the chunks of each file.
+/// Chunk references are counted from the manifests when opened and kept up to date from then on.
+pub struct ChunkedIOManager {
+    base_path: String,
+    refs: Arc<Mutex<ChunkRefs>>
+}
+impl ChunkedIOManager {
+    const MIN_CHUNK: u32 = 16 * 1024;
+    const AVG_CHUNK: u32 = 64 * 1024;
+    const MAX_CHUNK: u32 = 256 * 1024;
+
+    pub fn new(base_path: &str) -> Self {
+        let io_manager = Self { base_path: base_path.to_string(), refs: Arc::default() };
+        let mut refs = io_manager.refs();
+        if let Ok(entries) = std::fs::read_dir(io_manager.get_manifests_path()) {
+            for entry in entries.flatten() {
+                if entry.path().extension().is_none_or(|ext| ext != "json") {
+                    continue;
+                }
+                let Ok(data) = std::fs::read(entry.path()) else { continue };
+                let Ok(manifest) = serde_json::from_slice::<ChunkManifest>(&data) else { continue };
+                refs.add(&manifest);
+            }
+        }
+        drop(refs);
+        io_manager
+    }
+
+    async fn read_manifest(&self, id: &str) -> Result<ChunkManifest, String> {
+        let data = fs::read(self.get_manifest_path(id)).await
+                .map_err(|_| "File not found.".to_string())?;
+        serde_json::from_slice(&data).map_err(|e| e.to_string())
+    }
+
+    /// Writes the manifest of `id`, replacing any previous one and releasing the chunks only that one used.
+    async fn write_manifest(&self, id: &str, manifest: &ChunkManifest) -> Result<(), String> {
+        let previous = self.read_manifest(id).await.ok();
+        let data = serde_json::to_vec(manifest).expect("Chunk manifest serialization error.");
+        self.refs().add(manifest);
+        if let Err(e) = Self::write_replacing(&self.get_manifest_path(id), &data).await {
+            self.refs().remove(manifest);
+            return Err(e);
+        }
+        let unreferenced = match previous {
+            Some(previous) => self.refs().remove(&previous),
+            None => Vec::new(),
+        };
+        self.collect_garbage(unreferenced).await;
+        Ok(())
+    }
+
+    async fn remove_manifest(&self, id: &str) -> Result<(), String> {
+        let manifest = self.read_manifest(id).await?;
+        fs::remove_file(self.get_manifest_path(id)).await.map_err(|e| e.to_string())?;
+        let unreferenced = self.refs().remove(&manifest);
+        self.collect_garbage(unreferenced).await;
+        Ok(())
+    }
+
+    /// Stores the chunk under its SHA-256, unless a chunk with that hash is already there.
+    /// Chunks written before were keyed by xxh3-128; their manifests still find them by that key.
+    async fn store_chunk(&self, data: &[u8]) -> Result<ChunkRef, String> {
+        let hash = hex::encode(Sha256::digest(data));
+        let path = self.get_chunk_path(&hash);
+        if !path.exists() {
+            fs::create_dir_all(path.parent().expect("Chunk without parent dir")).await
+                    .map_err(|e| e.to_string())?;
+            Self::write_replacing(path.to_str().expect("Invalid path"), data).await?;
+        }
+        Ok(ChunkRef { hash, size: data.len() as u64 })
+    }
+
+    /// Splits `content` into chunks and stores them, listing every chunk in `written` as it goes,
+    /// so that a caller can release them should the store fail. Returns the manifest and checksum.
+    async fn write_chunks(&self, content: &mut (dyn AsyncRead + Send + Unpin), max_size: u64,
+                written: &mut Vec<String>) -> Result<(ChunkManifest, String), String> {
+        let mut manifest = ChunkManifest::default();
+        let mut hasher = Xxh3::new();
+        let mut buffer = Vec::new();
+        let mut read_buffer = vec![0u8; Self::MAX_CHUNK as usize];
+        let mut eof = false;
+        while !eof || !buffer.is_empty() {
+            while !eof && buffer.len() < 2 * Self::MAX_CHUNK as usize {
+                let read = content.read(&mut read_buffer).await.map_err(|e| e.to_string())?;
+                if read == 0 {
+                    eof = true;
+                }
+                manifest.size += read as u64;
+                if manifest.size > max_size {
+                    return Err("Content exceeds size limit.".to_string());
+                }
+                hasher.update(&read_buffer[..read]);
+                buffer.extend_from_slice(&read_buffer[..read]);
+            }
+
+            let mut consumed = 0;
+            for chunk in FastCDC::new(&buffer, Self::MIN_CHUNK, Self::AVG_CHUNK, Self::MAX_CHUNK) {
+                    // A chunk touching the end of the buffer may continue in data not read yet.
+                if !eof && chunk.offset + chunk.length == buffer.len() {
+                    break;
+                }
+                let chunk_ref = self.store_chunk(&buffer[chunk.offset..chunk.offset + chunk.length]).await?;
+                written.push(chunk_ref.hash.clone());
+                manifest.chunks.push(chunk_ref);
+                consumed = chunk.offset + chunk.length;
+            }
+            buffer.drain(..consumed);
+        }
+        Ok((manifest, Util::format_checksum(hasher.digest())))
+    }
+
+    /// Removes `unreferenced` chunks, along with those deferred earlier whose readers are done.
+    /// Chunks still being read are deferred to a later collection.
+    async fn collect_garbage(&self, unreferenced: Vec<String>) {
+        let removable = {
+            let mut refs = self.refs();
+            refs.deferred.extend(unreferenced);
+            let deferred = std::mem::take(&mut refs.deferred);
+            let (pinned, removable): (HashSet<String>, HashSet<String>) = deferred.into_iter()
+                    .filter(|hash| !refs.counts.contains_key(hash))
+                    .partition(|hash| refs.pins.contains_key(hash));
+            refs.deferred = pinned;
+            removable
+        };
+        for hash in removable {
+            let _ = fs::remove_file(self.get_chunk_path(&hash)).await;
+        }
+    }
+
+    /// Writes `data` through a temp file of its own, committed over `path` as `FolderIOManager` does for content.
+    async fn write_replacing(path: &str, data: &[u8]) -> Result<(), String> {
+        let temp_path = format!("{path}.{}.tmp", Util::new_id());
+        let written = match File::create(&temp_path).await {
+            Ok(mut file) => match file.write_all(data).await {
+                Ok(_) => FolderIOManager::commit(file, &temp_path, path).await,
+                Err(e) => Err(e.to_string()),
+            },
+            Err(e) => return Err(e.to_string()),
+        };
+        if written.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+        written
+    }
+
+    fn refs(&self) -> MutexGuard<'_, ChunkRefs> {
+        self.refs.lock().expect("Chunk references poisoned")
+    }
+
+    fn get_id(file_def: &FileDefinition) -> &str {
+        file_def.id.as_ref().expect("No id in File Definition")
+    }
+    fn get_version_id(file_def: &FileDefinition, revision: u64) -> String {
+        format!("{}@{revision}", Self::get_id(file_def))
+    }
+    fn get_trash_id(file_def: &FileDefinition) -> String {
+        format!("{}@trash", Self::get_id(file_def))
+    }
+    fn get_chunks_path(&self) -> PathBuf {
+        Path::new(&self.base_path).join(".chunks")
+    }
+    fn get_chunk_path(&self, hash: &str) -> PathBuf {
+        self.get_chunks_path().join(&hash[..2]).join(hash)
+    }
+    fn get_manifests_path(&self) -> PathBuf {
+        Path::new(&self.base_path).join(".manifests")
+    }
+    fn get_manifest_path(&self, id: &str) -> String {
+        let binding = self.get_manifests_path().join(format!("{id}.json"));
+        binding.to_str().unwrap().to_string()
+    }
+}
+
+impl IOManager for ChunkedIOManager {
+    async fn open_file_content(&self, file: &FileDefinition, offset: u64) -> Result<(ContentReader, u64), String> {
+        let manifest = self.read_manifest(Self::get_id(file)).await?;
+
+        let mut chunks = VecDeque::new();
+        let mut hashes = Vec::new();
+        let mut chunk_start = 0;
+        for chunk in &manifest.chunks {
+            let chunk_end = chunk_start + chunk.size;
+            if chunk_end > offset {
+                let skip = offset.saturating_sub(chunk_start);
+                chunks.push_back((self.get_chunk_path(&chunk.hash), skip));
+                hashes.push(chunk.hash.clone());
+            }
+            chunk_start = chunk_end;
+        }
+
+        let mut refs = self.refs();
+        for hash in &hashes {
+            *refs.pins.entry(hash.clone()).or_default() += 1;
+        }
+        drop(refs);
+        let pin = ChunkPin { hashes, refs: self.refs.clone() };
+        Ok((Box::pin(ChunkReader { chunks, current: ChunkState::Idle, _pin: pin }), manifest.size))
+    }
+
+    async fn store_file_stream(&self, file_def: &FileDefinition, content: &mut (dyn AsyncRead + Send + Unpin),
+                max_size: u64) -> Result<StoredContent, String> {
+        fs::create_dir_all(self.get_manifests_path()).await.map_err(|e| e.to_string())?;
+
+        let mut written = Vec::new();
+        let stored = match self.write_chunks(content, max_size, &mut written).await {
+            Ok((manifest, checksum)) => self.write_manifest(Self::get_id(file_def), &manifest).await
+                    .map(|_| StoredContent { size: manifest.size, checksum }),
+            Err(e) => Err(e),
+        };
+        if stored.is_err() {
+                // Only chunks no manifest refers to go, those already there for other files stay.
+            self.collect_garbage(written).await;
+        }
+        stored
+    }
+
+    async fn create_empty(&self, file_def: &FileDefinition) -> Result<bool, String> {
+        fs::create_dir_all(self.get_manifests_path()).await.map_err(|e| e.to_string())?;
+        self.write_manifest(Self::get_id(file_def), &ChunkManifest::default()).await?;
+        Ok(true)
+    }
+
+    async fn delete_file(&self, file_def: &FileDefinition) -> Result<bool, String> {
+        self.remove_manifest(Self::get_id(file_def)).await?;
+        Ok(true)
+    }
+
+    async fn store_version(&self, file: &FileDefinition, revision: u64) -> Result<(), String> {
+            // A version is just another manifest, referencing the same chunks.
+        let manifest = self.read_manifest(Self::get_id(file)).await?;
+        self.write_manifest(&Self::get_version_id(file, revision), &manifest).await
+    }
+
+    async fn open_version_content(&self, file: &FileDefinition, revision: u64, offset: u64) -> Result<(ContentReader, u64), String> {
+        let mut version = file.clone();
+        version.id = Some(Self::get_version_id(file, revision));
+        self.open_file_content(&version, offset).await
+                .map_err(|_| "Version not found.".to_string())
+    }
+
+    async fn delete_version(&self, file: &FileDefinition, revision: u64) -> Result<(), String> {
+        let mut version = file.clone();
+        version.id = Some(Self::get_version_id(file, revision));
+        self.delete_file(&version).await.map(|_| ())
+    }
+
+    async fn trash_file(&self, file: &FileDefinition) -> Result<(), String> {
+            // The trashed manifest still references its chunks, so nothing is collected.
+        fs::rename(self.get_manifest_path(Self::get_id(file)), self.get_manifest_path(&Self::get_trash_id(file))).await
+                .map_err(|e| e.to_string())
+    }
+
+    async fn restore_trashed(&self, file: &FileDefinition) -> Result<(), String> {
+        fs::rename(self.get_manifest_path(&Self::get_trash_id(file)), self.get_manifest_path(Self::get_id(file))).await
+                .map_err(|e| e.to_string())
+    }
+
+    async fn purge_trashed(&self, file: &FileDefinition) -> Result<(), String> {
+        let mut trashed = file.clone();
+        trashed.id = Some(Self::get_trash_id(file));
+        self.delete_file(&trashed).await.map(|_| ())
+    }
+
+    async fn list_files(&self) -> Result<Vec<String>, String> {
+        let mut ids = Vec::new();
+        let mut entries = match fs::read_dir(self.get_manifests_path()).await {
+            Ok(entries) => entries,
+            Err(_) => return Ok(ids),
+        };
+        while let Some(entry) = entries.next_entry().await.map_err(|e| e.to_string())? {
+            let name = entry.file_name().to_string_lossy().to_string();
+                // Versions and trash are `<id>@...`, only live manifests count.
+            if let Some(id) = name.strip_suffix(".json").filter(|id| !id.contains('@')) {
+                ids.push(id.to_string());
+            }
+        }
+        Ok(ids)
+    }
+}
+
+
+type OpenChunk = Pin<Box<dyn Future<Output = std::io::Result<File>> + Send>>;
+
+enum ChunkState {
+    Idle,
+    Opening(OpenChunk),
+    Reading(File)
+}
+
+/// Reads a sequence of chunk files back to back, opening each one only when reached.
+/// Its chunks are pinned for as long as it lives, so they can't be collected mid-read.
+struct ChunkReader {
+    chunks: VecDeque<(PathBuf, u64)>,
+    current: ChunkState,
+    _pin: ChunkPin
+}
+impl AsyncRead for ChunkReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        loop {
+            match &mut self.current {
+                ChunkState::Idle => {
+                    let Some((path, skip)) = self.chunks.pop_front() else {
+                        return Poll::Ready(Ok(()));
+                    };
+                    self.current = ChunkState::Opening(Box::pin(async move {
+                        let mut file = File::open(&path).await?;
+                        file.seek(SeekFrom::Start(skip)).await?;
+                        Ok(file)
+                    }));
+                },
+                ChunkState::Opening(opening) => {
+                    match opening.as_mut().poll(cx) {
+                        Poll::Ready(Ok(file)) => self.current = ChunkState::Reading(file),
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                },
+                ChunkState::Reading(file) => {
+                    let filled = buf.filled().len();
+                    match Pin::new(file).poll_read(cx, buf) {
+                        Poll::Ready(Ok(())) if buf.filled().len() == filled && buf.remaining() > 0 => {
+                            self.current = ChunkState::Idle;    // Chunk exhausted, move on to the next.
+                        },
+                        other => return other,
+                    }
+                },
+            }
+        }
+    }
+}
@@ -1,7 +1,225 @@
 
-pub struct Config;
+use std::path::Path;
+
+use rocket::data::Limits;
+use rocket::data::ToByteUnit;
+use rocket::figment::Figment;
+use rocket::figment::Profile;
+use rocket::figment::value::Value;
+use rocket::figment::providers::Env;
+use rocket::figment::providers::Toml;
+use rocket::figment::providers::Format;
+use rocket::figment::providers::Serialized;
+use rocket::serde::Deserialize;
+
+
+/// Server settings, read from `Rocket.toml` (or the file given with `--config`), `ROCKET_*` environment
+/// variables and `--<key> <value>` command line flags, each overriding the one before.
+/// Unset keys keep their defaults; `validate` rejects values the server can't run with.
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde", default)]
+pub struct Config {
+    pub base_path: String,              // Storage root, holding content for the folder and chunked backends.
+    pub state_path: Option<String>,     // Where repository metadata is kept, `base_path` if unset.
+    pub storage: Storage,
+    pub metadata: Metadata,
+    pub journal_compaction: u64,        // Journal records appended before compacting into a snapshot.
+    pub version_retention: usize,       // Prior versions kept per file.
+    pub trash_retention: u64,           // Seconds a deleted file stays in the trash.
+    pub read_verification: ReadVerification,
+    pub read_sample_rate: f64,          // Fraction of reads checked when `sampled`.
+    pub scrub_interval: u64,            // Seconds between background scrubs, 0 to never scrub.
+    pub scrub_action: ScrubAction,
+    pub auth: bool,                     // Whether API requests need a bearer token.
+    pub s3: Option<S3Settings>,
+    pub limits: Limits
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            base_path: "tmp".to_string(),
+            state_path: None,
+            storage: Storage::Folder,
+            metadata: Metadata::Sqlite,
+            journal_compaction: 1000,
+            version_retention: 10,
+            trash_retention: 30 * 24 * 60 * 60,
+            read_verification: ReadVerification::Sampled,
+            read_sample_rate: 0.01,
+            scrub_interval: 24 * 60 * 60,
+            scrub_action: ScrubAction::Log,
+            auth: true,
+            s3: None,
+            limits: Limits::default()
+        }
+    }
+}
 impl Config {
-    pub fn get_base_path() -> String {
-        "tmp".to_string()      // TODO
+    /// Flags that select what the binary does rather than set a value.
+    const MODE_FLAGS: [&'static str; 3] = ["--verify", "--repair", "--admin"];
+    /// Flags that select what the binary does, or qualify it, followed by their argument.
+    const ACTION_FLAGS: [&'static str; 3] = ["--mint-token", "--revoke-token", "--user"];
+
+    /// Reads and validates the configuration the server was started with.
+    /// Returns the figment along with it, for Rocket to pick up the same values.
+    pub fn load(args: &[String]) -> Result<(Self, Figment), String> {
+        let figment = Self::figment(args)?;
+        let config: Config = figment.extract().map_err(|e| format!("Invalid configuration: {e}"))?;
+        config.validate()?;
+        Ok((config, figment))
+    }
+
+    /// Rocket's own sources, reading the TOML file from `--config` if given, then the command line flags.
+    pub fn figment(args: &[String]) -> Result<Figment, String> {
+        let mut flags = Vec::new();
+        let mut config_file = Env::var_or("ROCKET_CONFIG", "Rocket.toml");
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if Self::MODE_FLAGS.contains(&arg.as_str()) {
+                continue;
+            }
+            let Some(key) = arg.strip_prefix("--") else {
+                return Err(format!("Unexpected argument: {arg}"));
+            };
+            let value = args.next().ok_or(format!("Missing value for {arg}"))?;
+            if Self::ACTION_FLAGS.contains(&arg.as_str()) {
+                continue;
+            }
+            match key {
+                "config" => config_file = value.clone(),
+                _ => flags.push((key.replace('-', "_"), value.parse::<Value>().expect("Infallible"))),
+            }
+        }
+
+        let mut figment = Figment::from(rocket::Config::default())
+                .merge(Toml::file(config_file).nested())
+                .merge(Env::prefixed("ROCKET_").ignore(&["PROFILE"]).global())
+                .select(Profile::from_env_or("ROCKET_PROFILE", rocket::Config::DEFAULT_PROFILE));
+        for (key, value) in flags {
+            figment = figment.merge(Serialized::global(&key, value));
+        }
+        Ok(figment)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.base_path.is_empty() {
+            return Err("base_path can't be empty".to_string());
+        }
+        if self.state_path.as_ref().is_some_and(|path| path.is_empty()) {
+            return Err("state_path can't be empty".to_string());
+        }
+        for dir in [Some(&self.base_path), self.state_path.as_ref()].into_iter().flatten() {
+            if Path::new(dir).exists() && !Path::new(dir).is_dir() {
+                return Err(format!("{dir} isn't a directory"));
+            }
+        }
+        if self.journal_compaction == 0 {
+            return Err("journal_compaction must be at least 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.read_sample_rate) {
+            return Err(format!("read_sample_rate must be between 0 and 1, got {}", self.read_sample_rate));
+        }
+        match (&self.s3, self.storage) {
+            (Some(s3), _) => s3.validate()?,
+            (None, Storage::S3) => return Err("storage is s3 but there are no s3 settings".to_string()),
+            (None, _) => {},
+        }
+        for limit in ["file", "bytes", "json"] {
+            if self.limits.get(limit).is_some_and(|size| size == 0.bytes()) {
+                return Err(format!("limits.{limit} can't be 0"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Largest file content accepted, in bytes, from `limits.file`.
+    pub fn file_limit(&self) -> u64 {
+        self.limits.get("file").unwrap_or(Limits::FILE).as_u64()
+    }
+
+    /// Directory holding the repository metadata.
+    pub fn get_state_path(&self) -> &str {
+        self.state_path.as_deref().unwrap_or(&self.base_path)
+    }
+
+    /// Settings for the named repository `name`, keeping its content and state under `.repos/<name>`
+    /// and its S3 objects under `<prefix>repos/<name>/`.
+    pub fn for_repository(&self, name: &str) -> Config {
+        let nested = |path: &str| Path::new(path).join(".repos").join(name).to_str().unwrap().to_string();
+        let mut config = self.clone();
+        config.base_path = nested(&self.base_path);
+        config.state_path = self.state_path.as_deref().map(nested);
+        if let Some(s3) = &mut config.s3 {
+            s3.prefix = format!("{}repos/{name}/", s3.prefix);
+        }
+        config
+    }
+}
+
+/// Where file content is kept, `storage` in the configuration.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Storage {
+    Folder,
+    Chunked,
+    Memory,             // Nothing survives a restart, metadata included.
+    S3
+}
+
+/// Where repository metadata is kept, `metadata` in the configuration.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Metadata {
+    Sqlite,
+    Journal
+}
+
+/// Which reads are checked against the stored checksum, `read_verification` in the configuration.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum ReadVerification {
+    Always,
+    Sampled,            // A `read_sample_rate` fraction of them.
+    Never
+}
+
+/// What the scrubber does with a file whose content doesn't match, `scrub_action` in the configuration.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum ScrubAction {
+    Log,
+    Quarantine          // Also stop serving it until it is replaced.
+}
+
+/// Where and how the `s3` backend stores content.
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct S3Settings {
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default = "S3Settings::default_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    #[serde(default = "S3Settings::default_part_size")]
+    pub part_size: u64
+}
+impl S3Settings {
+    fn default_region() -> String {
+        "us-east-1".to_string()
+    }
+    fn default_part_size() -> u64 {
+        8 * 1024 * 1024
+    }
+    fn validate(&self) -> Result<(), String> {
+        if reqwest::Url::parse(&self.endpoint).is_err() {
+            return Err(format!("s3.endpoint isn't a valid URL: {}", self.endpoint));
+        }
+        if self.bucket.is_empty() {
+            return Err("s3.bucket can't be empty".to_string());
+        }
+        Ok(())
     }
 }
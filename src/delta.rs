@@ -0,0 +1,242 @@
+
+use std::collections::HashMap;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rocket::tokio::io::AsyncRead;
+use rocket::tokio::io::AsyncReadExt;
+use rocket::tokio::io::AsyncWrite;
+use rocket::tokio::io::AsyncWriteExt;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::util::Util;
+use crate::model::FileDelta;
+use crate::model::FileSignature;
+use crate::model::BlockSignature;
+use crate::model::DeltaInstruction;
+
+
+/// Rolling weak checksum over a window of bytes, as used by rsync.
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32
+}
+impl RollingChecksum {
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, byte) in window.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*byte as u32));
+        }
+        Self { a, b, len }
+    }
+    fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+
+pub struct Delta;
+impl Delta {
+    pub const DEFAULT_BLOCK_SIZE: u64 = 4096;
+    pub const MIN_BLOCK_SIZE: u64 = 512;
+    pub const MAX_BLOCK_SIZE: u64 = 1024 * 1024;
+    const MAX_LITERAL: usize = 64 * 1024;
+
+    pub fn clamp_block_size(block_size: Option<u64>) -> u64 {
+        block_size.unwrap_or(Self::DEFAULT_BLOCK_SIZE)
+                .clamp(Self::MIN_BLOCK_SIZE, Self::MAX_BLOCK_SIZE)
+    }
+    /// Whether a block size sent by a client is one we would have used, and so is safe to allocate.
+    pub fn is_valid_block_size(block_size: u64) -> bool {
+        (Self::MIN_BLOCK_SIZE..=Self::MAX_BLOCK_SIZE).contains(&block_size)
+    }
+
+    /// Block signatures of `content`, split in `block_size` blocks; the last one may be shorter.
+    pub async fn signature(content: &mut (dyn AsyncRead + Send + Unpin), block_size: u64) -> Result<FileSignature, String> {
+        let mut blocks = Vec::new();
+        let mut hasher = Xxh3::new();
+        let mut size = 0;
+        let mut block = vec![0u8; block_size as usize];
+        loop {
+            let read = Self::read_full(content, &mut block).await?;
+            if read == 0 {
+                break;
+            }
+            let data = &block[..read];
+            hasher.update(data);
+            size += read as u64;
+            blocks.push(BlockSignature {
+                weak: RollingChecksum::new(data).digest(),
+                strong: Util::checksum(data)
+            });
+            if read < block.len() {
+                break;
+            }
+        }
+
+        Ok(FileSignature {
+            block_size,
+            size,
+            checksum: Some(Util::format_checksum(hasher.digest())),
+            blocks
+        })
+    }
+
+    /// Instructions that rebuild `content` from the file described by `signature`.
+    pub async fn diff(signature: &FileSignature, content: &mut (dyn AsyncRead + Send + Unpin)) -> Result<FileDelta, String> {
+        if !Self::is_valid_block_size(signature.block_size) {
+            return Err("Invalid block size".to_string());
+        }
+        let block_size = signature.block_size as usize;
+        let mut by_weak: HashMap<u32, Vec<(u64, &BlockSignature)>> = HashMap::new();
+        for (index, block) in signature.blocks.iter().enumerate() {
+            by_weak.entry(block.weak).or_default().push((index as u64, block));
+        }
+        let last_block_len = match signature.size as usize % block_size {
+            0 => block_size,
+            rem => rem,
+        };
+        let last_index = signature.blocks.len().saturating_sub(1) as u64;
+
+        let mut instructions = Vec::new();
+        let mut hasher = Xxh3::new();
+        let mut literal = Vec::new();
+        let mut window = Vec::new();    // Bytes read but not yet emitted, the candidate block starts at `start`.
+        let mut start = 0;
+        let mut rolling: Option<RollingChecksum> = None;
+        let mut chunk = vec![0u8; block_size.max(64 * 1024)];
+        let mut eof = false;
+
+        loop {
+            while !eof && window.len() < start + block_size + 1 {
+                let read = content.read(&mut chunk).await.map_err(|e| e.to_string())?;
+                if read == 0 {
+                    eof = true;
+                }
+                hasher.update(&chunk[..read]);
+                window.extend_from_slice(&chunk[..read]);
+            }
+            if window.len() < start + block_size {
+                break;
+            }
+
+            let candidate = &window[start..start + block_size];
+            let weak = rolling.get_or_insert_with(|| RollingChecksum::new(candidate)).digest();
+            let matched = by_weak.get(&weak).and_then(|blocks| {
+                let strong = Util::checksum(candidate);
+                blocks.iter()
+                        .find(|(index, block)| block.strong == strong
+                                && (*index != last_index || last_block_len == block_size))
+                        .map(|(index, _)| *index)
+            });
+
+            match matched {
+                Some(index) => {
+                    literal.extend_from_slice(&window[..start]);
+                    Self::push_literal(&mut instructions, &mut literal);
+                    Self::push_copy(&mut instructions, index);
+                    window.drain(..start + block_size);
+                    start = 0;
+                    rolling = None;
+                },
+                None if window.len() > start + block_size => {
+                    let (out, into) = (window[start], window[start + block_size]);
+                    rolling.as_mut().expect("Rolling checksum not initialized").roll(out, into);
+                    start += 1;
+                    if start >= Self::MAX_LITERAL {
+                        literal.extend(window.drain(..start));
+                        start = 0;
+                        if literal.len() >= Self::MAX_LITERAL {
+                            Self::push_literal(&mut instructions, &mut literal);
+                        }
+                    }
+                },
+                None => break,      // Exactly one block left and it doesn't match.
+            }
+        }
+
+            // Remaining tail may still match a shorter last block.
+        let tail_matched = !signature.blocks.is_empty()
+                && last_block_len < block_size
+                && window.len() >= last_block_len
+                && {
+                    let tail = &window[window.len() - last_block_len..];
+                    let block = &signature.blocks[last_index as usize];
+                    RollingChecksum::new(tail).digest() == block.weak && Util::checksum(tail) == block.strong
+                };
+        if tail_matched {
+            literal.extend_from_slice(&window[..window.len() - last_block_len]);
+            Self::push_literal(&mut instructions, &mut literal);
+            Self::push_copy(&mut instructions, last_index);
+        }
+        else {
+            literal.extend_from_slice(&window);
+            Self::push_literal(&mut instructions, &mut literal);
+        }
+
+        Ok(FileDelta {
+            block_size: signature.block_size,
+            base_checksum: signature.checksum.clone(),
+            target_checksum: Some(Util::format_checksum(hasher.digest())),
+            instructions
+        })
+    }
+
+    /// Decodes a literal instruction's payload.
+    pub fn decode_literal(data: &str) -> Result<Vec<u8>, String> {
+        BASE64.decode(data).map_err(|e| e.to_string())
+    }
+
+    /// Copies all of `reader` into `writer`, feeding the bytes to `hasher` on the way.
+    pub async fn copy_hashing(reader: &mut (dyn AsyncRead + Send + Unpin), writer: &mut (dyn AsyncWrite + Send + Unpin),
+                hasher: &mut Xxh3) -> Result<u64, String> {
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut copied = 0;
+        loop {
+            let read = reader.read(&mut buffer).await.map_err(|e| e.to_string())?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            writer.write_all(&buffer[..read]).await.map_err(|e| e.to_string())?;
+            copied += read as u64;
+        }
+        Ok(copied)
+    }
+
+    fn push_literal(instructions: &mut Vec<DeltaInstruction>, literal: &mut Vec<u8>) {
+        if !literal.is_empty() {
+            instructions.push(DeltaInstruction::Literal { data: BASE64.encode(&literal) });
+            literal.clear();
+        }
+    }
+    fn push_copy(instructions: &mut Vec<DeltaInstruction>, index: u64) {
+        if let Some(DeltaInstruction::Copy { block, count }) = instructions.last_mut() {
+            if *block + *count == index {
+                *count += 1;
+                return;
+            }
+        }
+        instructions.push(DeltaInstruction::Copy { block: index, count: 1 });
+    }
+
+    async fn read_full(content: &mut (dyn AsyncRead + Send + Unpin), buffer: &mut [u8]) -> Result<usize, String> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let read = content.read(&mut buffer[filled..]).await.map_err(|e| e.to_string())?;
+            if read == 0 {
+                break;
+            }
+            filled += read;
+        }
+        Ok(filled)
+    }
+}
@@ -0,0 +1,119 @@
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use serde::Serialize;
+use serde::Deserialize;
+
+use crate::model::Snapshot;
+use crate::model::TrashedFile;
+use crate::model::FileDefinition;
+use crate::model::FileRepositoryState;
+
+
+/// Rows of versions, trash, snapshots and quarantined files that changed since they were last persisted,
+/// keyed by file id or snapshot name. `None` marks a row that was removed.
+#[derive(Serialize, Deserialize, Default)]
+pub struct ExtrasDelta {
+    #[serde(default)]
+    pub versions: HashMap<String, Option<Vec<FileDefinition>>>,
+    #[serde(default)]
+    pub trash: HashMap<String, Option<TrashedFile>>,
+    #[serde(default)]
+    pub snapshots: HashMap<String, Option<Snapshot>>,
+    #[serde(default)]
+    pub snapshot_files: HashMap<String, Option<Vec<FileDefinition>>>,
+    #[serde(default)]
+    pub quarantined: HashMap<String, Option<u64>>
+}
+impl ExtrasDelta {
+    pub fn is_empty(&self) -> bool {
+        self.versions.is_empty() && self.trash.is_empty() && self.snapshots.is_empty() && self.snapshot_files.is_empty()
+                && self.quarantined.is_empty()
+    }
+
+    /// Applies the changed rows to `state`. New snapshots go last, as they were taken.
+    pub fn apply(self, state: &mut FileRepositoryState) {
+        Self::apply_rows(&mut state.versions, self.versions);
+        Self::apply_rows(&mut state.trash, self.trash);
+        Self::apply_rows(&mut state.snapshot_files, self.snapshot_files);
+        Self::apply_rows(&mut state.quarantined, self.quarantined);
+        for (name, snapshot) in self.snapshots {
+            let index = state.snapshots.iter().position(|s| s.name == name);
+            match (snapshot, index) {
+                (Some(snapshot), Some(index)) => state.snapshots[index] = snapshot,
+                (Some(snapshot), None) => state.snapshots.push(snapshot),
+                (None, Some(index)) => {
+                    state.snapshots.remove(index);
+                },
+                (None, None) => {},
+            }
+        }
+    }
+
+    fn apply_rows<T>(rows: &mut HashMap<String, T>, changes: HashMap<String, Option<T>>) {
+        for (key, row) in changes {
+            match row {
+                Some(row) => rows.insert(key, row),
+                None => rows.remove(&key),
+            };
+        }
+    }
+}
+
+/// Keys of the extras rows changed since they were last persisted, marked by the repository as it
+/// changes them, so that stores only write those. Snapshots are marked along with their files.
+#[derive(Default)]
+pub struct TouchedExtras {
+    versions: HashSet<String>,
+    trash: HashSet<String>,
+    snapshots: HashSet<String>,
+    quarantined: HashSet<String>
+}
+impl TouchedExtras {
+    /// Every row of `state`, for a store written from scratch.
+    pub fn all(state: &FileRepositoryState) -> Self {
+        let mut touched = Self::default();
+        touched.mark_all(state);
+        touched
+    }
+    pub fn mark_all(&mut self, state: &FileRepositoryState) {
+        self.versions.extend(state.versions.keys().cloned());
+        self.trash.extend(state.trash.keys().cloned());
+        self.snapshots.extend(state.snapshots.iter().map(|s| s.name.clone()));
+        self.snapshots.extend(state.snapshot_files.keys().cloned());
+        self.quarantined.extend(state.quarantined.keys().cloned());
+    }
+
+    pub fn version(&mut self, id: &str) {
+        self.versions.insert(id.to_string());
+    }
+    pub fn trash(&mut self, id: &str) {
+        self.trash.insert(id.to_string());
+    }
+    pub fn snapshot(&mut self, name: &str) {
+        self.snapshots.insert(name.to_string());
+    }
+    pub fn quarantine(&mut self, id: &str) {
+        self.quarantined.insert(id.to_string());
+    }
+
+    /// The marked rows as they are now in `state`, `None` for the ones since removed.
+    pub fn delta(&self, state: &FileRepositoryState) -> ExtrasDelta {
+        ExtrasDelta {
+            versions: Self::rows(&self.versions, &state.versions),
+            trash: Self::rows(&self.trash, &state.trash),
+            snapshots: self.snapshots.iter()
+                    .map(|name| (name.clone(), state.snapshots.iter().find(|s| s.name == *name).cloned()))
+                    .collect(),
+            snapshot_files: Self::rows(&self.snapshots, &state.snapshot_files),
+            quarantined: Self::rows(&self.quarantined, &state.quarantined)
+        }
+    }
+
+    fn rows<T: Clone>(keys: &HashSet<String>, rows: &HashMap<String, T>) -> HashMap<String, Option<T>> {
+        keys.iter()
+                .map(|key| (key.clone(), rows.get(key).cloned()))
+                .collect()
+    }
+}
@@ -0,0 +1,219 @@
+
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use rocket::request::Outcome;
+use rocket::request::Request;
+use rocket::request::FromRequest;
+use rocket::http::Header;
+
+use crate::model::FileDefinition;
+
+
+/// Formats the checksum of `file_def` as a strong entity tag.
+pub fn etag_for(file_def: &FileDefinition) -> Header<'static> {
+    let checksum = file_def.checksum.as_deref().unwrap_or_default();
+    Header::new("ETag", format!("\"{checksum}\""))
+}
+
+/// Entity tag from a conditional header. The revision form is `"rev-<n>"`, apart from any checksum.
+struct Tag {
+    weak: bool,
+    value: String
+}
+
+fn parse_tags(value: &str) -> Vec<Tag> {
+    value.split(',')
+            .map(|tag| tag.trim())
+            .map(|tag| match tag.strip_prefix("W/") {
+                Some(tag) => Tag { weak: true, value: tag.trim_matches('"').to_string() },
+                None => Tag { weak: false, value: tag.trim_matches('"').to_string() },
+            })
+            .filter(|tag| !tag.value.is_empty())
+            .collect()
+}
+
+/// True if any tag is `*`, the current checksum or the current revision of the file.
+/// With `strong`, as `If-Match` requires, weak tags never match.
+fn any_tag_matches(tags: &[Tag], file_def: &FileDefinition, strong: bool) -> bool {
+    let revision = file_def.revision.map(|r| format!("rev-{r}"));
+    tags.iter()
+            .filter(|tag| !(strong && tag.weak))
+            .any(|tag| tag.value == "*"
+                || Some(&tag.value) == file_def.checksum.as_ref()
+                || Some(&tag.value) == revision.as_ref())
+}
+
+
+/// `If-Match` request header; checksums or revisions the client expects the file to be at.
+pub struct IfMatch(Option<Vec<Tag>>);
+impl IfMatch {
+    pub fn from_header(value: Option<&str>) -> Self {
+        IfMatch(value.map(parse_tags))
+    }
+    pub fn matches(&self, file_def: &FileDefinition) -> bool {
+        match &self.0 {
+            Some(tags) => any_tag_matches(tags, file_def, true),
+            None => true,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(IfMatch::from_header(request.headers().get_one("If-Match")))
+    }
+}
+
+
+/// `If-None-Match` request header; checksums or revisions the client already has.
+pub struct IfNoneMatch(Option<Vec<Tag>>);
+impl IfNoneMatch {
+    pub fn from_header(value: Option<&str>) -> Self {
+        IfNoneMatch(value.map(parse_tags))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(IfNoneMatch::from_header(request.headers().get_one("If-None-Match")))
+    }
+}
+
+/// `If-Modified-Since` request header. Unparseable dates are ignored.
+pub struct IfModifiedSince(Option<SystemTime>);
+impl IfModifiedSince {
+    pub fn from_header(value: Option<&str>) -> Self {
+        IfModifiedSince(value.and_then(|v| httpdate::parse_http_date(v).ok()))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfModifiedSince {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(IfModifiedSince::from_header(request.headers().get_one("If-Modified-Since")))
+    }
+}
+
+/// True if the client's cached copy is still current and a 304 can be sent.
+/// `If-None-Match` takes precedence over `If-Modified-Since` when both are present.
+pub fn is_not_modified(file_def: &FileDefinition, if_none_match: &IfNoneMatch, if_modified_since: &IfModifiedSince) -> bool {
+    if let Some(tags) = &if_none_match.0 {
+        return any_tag_matches(tags, file_def, false);
+    }
+    match (if_modified_since.0, file_def.last_update) {
+            // HTTP dates only carry whole seconds.
+        (Some(since), Some(last_update)) => whole_secs(last_update) <= whole_secs(since),
+        _ => false,
+    }
+}
+
+fn whole_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+
+/// Which part of a file to send back for a `Range` request.
+#[derive(Debug, PartialEq)]
+pub enum RangeSelection {
+    Full,
+    Partial(Vec<(u64, u64)>),   // Inclusive start and end offsets.
+    Unsatisfiable
+}
+
+enum ByteRange {
+    FromTo(u64, Option<u64>),
+    Suffix(u64)
+}
+
+/// `Range` request header along with its `If-Range` validator.
+pub struct Range {
+    ranges: Option<Vec<ByteRange>>,
+    if_range: Option<String>
+}
+impl Range {
+    const MAX_RANGES: usize = 64;
+
+    pub fn from_headers(range: Option<&str>, if_range: Option<&str>) -> Self {
+        Range {
+            ranges: range.and_then(parse_ranges),
+            if_range: if_range.map(|v| v.trim().to_string())
+        }
+    }
+
+    /// Resolves the requested ranges against the current file.
+    /// Malformed headers and stale `If-Range` validators fall back to the full content.
+    pub fn resolve(&self, file_def: &FileDefinition, len: u64) -> RangeSelection {
+        let ranges = match &self.ranges {
+            Some(ranges) if self.if_range_matches(file_def) => ranges,
+            _ => return RangeSelection::Full,
+        };
+
+        let resolved: Vec<(u64, u64)> = ranges.iter()
+                .filter_map(|range| match *range {
+                    ByteRange::FromTo(start, _) if start >= len => None,
+                    ByteRange::FromTo(start, end) => Some((start, end.unwrap_or(u64::MAX).min(len - 1))),
+                    ByteRange::Suffix(0) => None,
+                    ByteRange::Suffix(suffix) if len > 0 => Some((len.saturating_sub(suffix), len - 1)),
+                    ByteRange::Suffix(_) => None,
+                })
+                .collect();
+
+        if resolved.is_empty() {
+            RangeSelection::Unsatisfiable
+        }
+        else {
+            RangeSelection::Partial(resolved)
+        }
+    }
+
+    fn if_range_matches(&self, file_def: &FileDefinition) -> bool {
+        match &self.if_range {
+            None => true,
+            Some(value) if value.starts_with('"') || value.starts_with("W/") => {
+                Some(value.trim_matches('"')) == file_def.checksum.as_deref()
+            },
+            Some(value) => match (httpdate::parse_http_date(value), file_def.last_update) {
+                (Ok(date), Some(last_update)) => whole_secs(last_update) <= whole_secs(date),
+                _ => false,
+            },
+        }
+    }
+}
+
+fn parse_ranges(value: &str) -> Option<Vec<ByteRange>> {
+    let specs = value.trim().strip_prefix("bytes=")?;
+    let ranges = specs.split(',')
+            .map(|spec| {
+                let (start, end) = spec.trim().split_once('-')?;
+                match (start.trim(), end.trim()) {
+                    ("", suffix) => suffix.parse().ok().map(ByteRange::Suffix),
+                    (start, "") => start.parse().ok().map(|s| ByteRange::FromTo(s, None)),
+                    (start, end) => {
+                        let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                        (start <= end).then_some(ByteRange::FromTo(start, Some(end)))
+                    }
+                }
+            })
+            .collect::<Option<Vec<ByteRange>>>()?;
+
+    (!ranges.is_empty() && ranges.len() <= Range::MAX_RANGES).then_some(ranges)
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Range {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        Outcome::Success(Range::from_headers(headers.get_one("Range"), headers.get_one("If-Range")))
+    }
+}
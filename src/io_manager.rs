@@ -1,74 +1,406 @@
 
+use std::io::SeekFrom;
+use std::path::Path;
+use std::pin::Pin;
+
 use rocket::tokio;
 use rocket::tokio::fs::File;
+use rocket::tokio::io::AsyncRead;
+use rocket::tokio::io::AsyncSeekExt;
 use rocket::tokio::io::AsyncReadExt;
 use rocket::tokio::io::AsyncWriteExt;
+use xxhash_rust::xxh3::Xxh3;
 
 use crate::util::Util;
-use crate::model::FileData;
+use crate::config::Config;
+use crate::config::Storage;
+use crate::chunk_store::ChunkedIOManager;
+use crate::memory_store::InMemoryIOManager;
+use crate::s3_store::S3IOManager;
 use crate::model::FileDefinition;
 
 
+pub type ContentReader = Pin<Box<dyn AsyncRead + Send>>;
+
+/// Size and checksum of content written by `IOManager::store_file_stream`.
+#[derive(Clone)]
+pub struct StoredContent {
+    pub size: u64,
+    pub checksum: String
+}
+
+/// Content received into a scratch file under the base path, before anything is locked.
+/// See `IOManager::store_staged`; the caller removes the file if it is still there afterwards.
+pub struct StagedContent {
+    pub path: String,
+    pub stored: StoredContent
+}
+impl StagedContent {
+    /// Streams `content` into a new scratch file, hashing it as it goes and failing past `max_size`.
+    pub async fn write(base_path: &str, content: &mut (dyn AsyncRead + Send + Unpin), max_size: u64) -> Result<Self, String> {
+        let path = Util::temp_path(base_path).await?;
+        let mut file = File::create(&path).await.map_err(|e| e.to_string())?;
+        match FolderIOManager::write_stream(&mut file, content, max_size).await {
+            Ok(stored) => Ok(Self { path, stored }),
+            Err(e) => {
+                drop(file);
+                let _ = tokio::fs::remove_file(&path).await;
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn remove(self) {
+        let _ = tokio::fs::remove_file(&self.path).await;
+    }
+}
+
 #[allow(async_fn_in_trait)]
 pub trait IOManager {
-    async fn get_file_content(&self, file: &FileDefinition) -> Result<Vec<u8>, String>;
-    async fn store_file_content(&self, file: &FileData) -> Result<(), String>;
+    /// Opens the content for reading from `offset`, also returning its total length.
+    async fn open_file_content(&self, file: &FileDefinition, offset: u64) -> Result<(ContentReader, u64), String>;
+    /// Streams `content` into storage, failing if it is longer than `max_size`.
+    async fn store_file_stream(&self, file: &FileDefinition, content: &mut (dyn AsyncRead + Send + Unpin),
+                max_size: u64) -> Result<StoredContent, String>;
+    /// Makes the staged content that of the file. By default it is streamed in from the scratch file.
+    async fn store_staged(&self, file: &FileDefinition, staged: &StagedContent) -> Result<StoredContent, String> {
+        let mut content = File::open(&staged.path).await.map_err(|e| e.to_string())?;
+        self.store_file_stream(file, &mut content, staged.stored.size).await
+    }
     async fn create_empty(&self, file: &FileDefinition) -> Result<bool, String>;
     async fn delete_file(&self, file_def: &FileDefinition) -> Result<bool, String>;
+    /// Keeps a copy of the current content as version `revision`, unaffected by later stores.
+    async fn store_version(&self, file: &FileDefinition, revision: u64) -> Result<(), String>;
+    async fn open_version_content(&self, file: &FileDefinition, revision: u64, offset: u64) -> Result<(ContentReader, u64), String>;
+    async fn delete_version(&self, file: &FileDefinition, revision: u64) -> Result<(), String>;
+    /// Moves the content out of the live set, keeping it until purged or restored.
+    async fn trash_file(&self, file: &FileDefinition) -> Result<(), String>;
+    async fn restore_trashed(&self, file: &FileDefinition) -> Result<(), String>;
+    async fn purge_trashed(&self, file: &FileDefinition) -> Result<(), String>;
+    /// Ids of all live content, whether or not the repository still knows about it.
+    async fn list_files(&self) -> Result<Vec<String>, String>;
 }
 
-pub struct FolderIOManager;
-impl IOManager for FolderIOManager {
-    async fn get_file_content(&self, file: &FileDefinition) -> Result<Vec<u8>, String> {
-        let full_path = Util::full_path(file);
-        match File::open(&full_path).await {
-            Ok(mut file) => {
-                let mut content = Vec::new();
-                file.read_to_end(&mut content).await.expect("Unable to read file");
-                Ok(content)
-            },
-            Err(_) => {
-                Err("File not found.".to_string())
+/// Keeps each file's content as a plain file named after its id under `base_path`.
+pub struct FolderIOManager {
+    base_path: String
+}
+impl FolderIOManager {
+    const BUFFER_SIZE: usize = 64 * 1024;
+
+    pub fn new(base_path: &str) -> Self {
+        Self { base_path: base_path.to_string() }
+    }
+
+    /// Temp file next to `full_path`, so that renaming it over the target stays atomic.
+    fn get_temp_path(full_path: &str) -> String {
+        format!("{full_path}.{}.tmp", Util::new_id())
+    }
+
+    /// Makes the written temp file the content at `full_path`: fsync, rename over the target, fsync the directory.
+    /// Readers see either the old content or the new one, and the new one survives a crash once this returns.
+    pub async fn commit(file: File, temp_path: &str, full_path: &str) -> Result<(), String> {
+        file.sync_all().await.map_err(|e| e.to_string())?;
+        drop(file);
+        tokio::fs::rename(temp_path, full_path).await.map_err(|e| e.to_string())?;
+        let dir = Path::new(full_path).parent().expect("File without parent dir");
+        File::open(dir).await.map_err(|e| e.to_string())?
+                .sync_all().await.map_err(|e| e.to_string())
+    }
+
+    async fn write_stream(file: &mut File, content: &mut (dyn AsyncRead + Send + Unpin), max_size: u64) -> Result<StoredContent, String> {
+        let mut hasher = Xxh3::new();
+        let mut buffer = vec![0u8; Self::BUFFER_SIZE];
+        let mut size = 0u64;
+        loop {
+            let read = content.read(&mut buffer).await.map_err(|e| e.to_string())?;
+            if read == 0 {
+                break;
+            }
+            size += read as u64;
+            if size > max_size {
+                return Err("Content exceeds size limit.".to_string());
             }
+            hasher.update(&buffer[..read]);
+            file.write_all(&buffer[..read]).await.map_err(|e| e.to_string())?;
         }
+        file.flush().await.map_err(|e| e.to_string())?;
+
+        Ok(StoredContent { size, checksum: Util::format_checksum(hasher.digest()) })
+    }
+}
+impl IOManager for FolderIOManager {
+    async fn open_file_content(&self, file: &FileDefinition, offset: u64) -> Result<(ContentReader, u64), String> {
+        let full_path = Util::full_path(&self.base_path, file);
+        if !Util::validate_file(&full_path) {
+            return Err("File not found.".to_string());
+        }
+        let mut file = File::open(&full_path).await.map_err(|e| e.to_string())?;
+        let len = file.metadata().await.map_err(|e| e.to_string())?.len();
+        file.seek(SeekFrom::Start(offset.min(len))).await.map_err(|e| e.to_string())?;
+
+        Ok((Box::pin(file), len))
     }
 
-    async fn store_file_content(&self, file_data: &FileData) -> Result<(), String> {
-        if !Util::validate_path(&file_data.definition.path).await {
+    async fn store_file_stream(&self, file_def: &FileDefinition, content: &mut (dyn AsyncRead + Send + Unpin),
+                max_size: u64) -> Result<StoredContent, String> {
+        if !Util::validate_path(&self.base_path, &file_def.path).await {
             return Err("Invalid path.".to_string());
         }
 
-        let full_path_str = Util::full_path(&file_data.definition);
+        let full_path_str = Util::full_path(&self.base_path, file_def);
+        let temp_path = Self::get_temp_path(&full_path_str);
 
-        let mut file = File::create(&full_path_str).await.expect("Unable to create file");
-        match file.write_all(&file_data.content).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.to_string())
+        let mut file = File::create(&temp_path).await.map_err(|e| e.to_string())?;
+        let stored = match Self::write_stream(&mut file, content, max_size).await {
+            Ok(stored) => Self::commit(file, &temp_path, &full_path_str).await.map(|_| stored),
+            Err(e) => Err(e),
+        };
+        if stored.is_err() {
+                // The target was never touched, only the partial temp file needs to go.
+            let _ = tokio::fs::remove_file(&temp_path).await;
         }
+        stored
+    }
+
+    /// Renames the scratch file into place, as it is on the same file system.
+    async fn store_staged(&self, file_def: &FileDefinition, staged: &StagedContent) -> Result<StoredContent, String> {
+        if !Util::validate_path(&self.base_path, &file_def.path).await {
+            return Err("Invalid path.".to_string());
+        }
+        let file = File::open(&staged.path).await.map_err(|e| e.to_string())?;
+        Self::commit(file, &staged.path, &Util::full_path(&self.base_path, file_def)).await?;
+        Ok(staged.stored.clone())
     }
     
     async fn create_empty(&self, file_def: &FileDefinition) -> Result<bool, String> {
-        if !Util::validate_path(&file_def.path).await {
+        if !Util::validate_path(&self.base_path, &file_def.path).await {
             return Err("Invalid path.".to_string());
         }
 
-        let full_path_str = Util::full_path(file_def);
-        match File::create(&full_path_str).await {
+        let full_path_str = Util::full_path(&self.base_path, file_def);
+        let temp_path = Self::get_temp_path(&full_path_str);
+        let file = File::create(&temp_path).await.map_err(|e| e.to_string())?;
+        match Self::commit(file, &temp_path, &full_path_str).await {
             Ok(_) => Ok(true),
-            Err(e) => Err(e.to_string())
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                Err(e)
+            }
         }
     }
     
     async fn delete_file(&self, file_def: &FileDefinition) -> Result<bool, String> {
-        if !Util::validate_path(&file_def.path).await {
+        if !Util::validate_path(&self.base_path, &file_def.path).await {
             return Err("Invalid path.".to_string());
         }
 
-        let full_path_str = Util::full_path(file_def);
+        let full_path_str = Util::full_path(&self.base_path, file_def);
 
         match tokio::fs::remove_file(&full_path_str).await {
             Ok(_) => Ok(true),
             Err(e) => Err(e.to_string())
         }
     }
-}
\ No newline at end of file
+
+    async fn store_version(&self, file: &FileDefinition, revision: u64) -> Result<(), String> {
+        let version_path = Util::version_path(&self.base_path, file, revision);
+        let version_dir = Path::new(&version_path).parent().expect("Version without parent dir");
+        tokio::fs::create_dir_all(version_dir).await.map_err(|e| e.to_string())?;
+            // Content is only ever replaced by renaming over it, never written in place,
+            // so a hard link keeps this version as it is now. Copy where links aren't supported.
+        let _ = tokio::fs::remove_file(&version_path).await;
+        if tokio::fs::hard_link(Util::full_path(&self.base_path, file), &version_path).await.is_ok() {
+            return Ok(());
+        }
+        tokio::fs::copy(Util::full_path(&self.base_path, file), &version_path).await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+    }
+
+    async fn open_version_content(&self, file: &FileDefinition, revision: u64, offset: u64) -> Result<(ContentReader, u64), String> {
+        let version_path = Util::version_path(&self.base_path, file, revision);
+        if !Util::validate_file(&version_path) {
+            return Err("Version not found.".to_string());
+        }
+        let mut file = File::open(&version_path).await.map_err(|e| e.to_string())?;
+        let len = file.metadata().await.map_err(|e| e.to_string())?.len();
+        file.seek(SeekFrom::Start(offset.min(len))).await.map_err(|e| e.to_string())?;
+
+        Ok((Box::pin(file), len))
+    }
+
+    async fn delete_version(&self, file: &FileDefinition, revision: u64) -> Result<(), String> {
+        tokio::fs::remove_file(Util::version_path(&self.base_path, file, revision)).await
+                .map_err(|e| e.to_string())
+    }
+
+    async fn trash_file(&self, file: &FileDefinition) -> Result<(), String> {
+        let trash_path = Util::trash_path(&self.base_path, file);
+        let trash_dir = Path::new(&trash_path).parent().expect("Trash without parent dir");
+        tokio::fs::create_dir_all(trash_dir).await.map_err(|e| e.to_string())?;
+        tokio::fs::rename(Util::full_path(&self.base_path, file), &trash_path).await
+                .map_err(|e| e.to_string())
+    }
+
+    async fn restore_trashed(&self, file: &FileDefinition) -> Result<(), String> {
+        if !Util::validate_path(&self.base_path, &file.path).await {
+            return Err("Invalid path.".to_string());
+        }
+        tokio::fs::rename(Util::trash_path(&self.base_path, file), Util::full_path(&self.base_path, file)).await
+                .map_err(|e| e.to_string())
+    }
+
+    async fn purge_trashed(&self, file: &FileDefinition) -> Result<(), String> {
+        tokio::fs::remove_file(Util::trash_path(&self.base_path, file)).await
+                .map_err(|e| e.to_string())
+    }
+
+    async fn list_files(&self) -> Result<Vec<String>, String> {
+        let mut ids = Vec::new();
+        let mut entries = match tokio::fs::read_dir(&self.base_path).await {
+            Ok(entries) => entries,
+            Err(_) => return Ok(ids),
+        };
+        while let Some(entry) = entries.next_entry().await.map_err(|e| e.to_string())? {
+            let name = entry.file_name().to_string_lossy().to_string();
+                // Metadata and the versions, trash and temp dirs are all dot files; temp files end in `.tmp`.
+            if name.starts_with('.') || name.ends_with(".tmp") {
+                continue;
+            }
+            if entry.file_type().await.map_err(|e| e.to_string())?.is_file() {
+                ids.push(name);
+            }
+        }
+        Ok(ids)
+    }
+}
+
+
+/// Storage backend picked from the configuration.
+pub enum StorageIOManager {
+    Folder(FolderIOManager),
+    Chunked(ChunkedIOManager),
+    Memory(InMemoryIOManager),
+    S3(S3IOManager)
+}
+impl StorageIOManager {
+    pub fn from_config(config: &Config) -> Self {
+        match config.storage {
+            Storage::Folder => StorageIOManager::Folder(FolderIOManager::new(&config.base_path)),
+            Storage::Chunked => StorageIOManager::Chunked(ChunkedIOManager::new(&config.base_path)),
+            Storage::Memory => StorageIOManager::Memory(InMemoryIOManager::default()),
+            Storage::S3 => StorageIOManager::S3(S3IOManager::new(config.s3.clone().expect("No s3 settings"))),
+        }
+    }
+}
+impl IOManager for StorageIOManager {
+    async fn open_file_content(&self, file: &FileDefinition, offset: u64) -> Result<(ContentReader, u64), String> {
+        match self {
+            StorageIOManager::Folder(io) => io.open_file_content(file, offset).await,
+            StorageIOManager::Chunked(io) => io.open_file_content(file, offset).await,
+            StorageIOManager::Memory(io) => io.open_file_content(file, offset).await,
+            StorageIOManager::S3(io) => io.open_file_content(file, offset).await,
+        }
+    }
+
+    async fn store_file_stream(&self, file: &FileDefinition, content: &mut (dyn AsyncRead + Send + Unpin),
+                max_size: u64) -> Result<StoredContent, String> {
+        match self {
+            StorageIOManager::Folder(io) => io.store_file_stream(file, content, max_size).await,
+            StorageIOManager::Chunked(io) => io.store_file_stream(file, content, max_size).await,
+            StorageIOManager::Memory(io) => io.store_file_stream(file, content, max_size).await,
+            StorageIOManager::S3(io) => io.store_file_stream(file, content, max_size).await,
+        }
+    }
+
+    async fn store_staged(&self, file: &FileDefinition, staged: &StagedContent) -> Result<StoredContent, String> {
+        match self {
+            StorageIOManager::Folder(io) => io.store_staged(file, staged).await,
+            StorageIOManager::Chunked(io) => io.store_staged(file, staged).await,
+            StorageIOManager::Memory(io) => io.store_staged(file, staged).await,
+            StorageIOManager::S3(io) => io.store_staged(file, staged).await,
+        }
+    }
+
+    async fn create_empty(&self, file: &FileDefinition) -> Result<bool, String> {
+        match self {
+            StorageIOManager::Folder(io) => io.create_empty(file).await,
+            StorageIOManager::Chunked(io) => io.create_empty(file).await,
+            StorageIOManager::Memory(io) => io.create_empty(file).await,
+            StorageIOManager::S3(io) => io.create_empty(file).await,
+        }
+    }
+
+    async fn delete_file(&self, file_def: &FileDefinition) -> Result<bool, String> {
+        match self {
+            StorageIOManager::Folder(io) => io.delete_file(file_def).await,
+            StorageIOManager::Chunked(io) => io.delete_file(file_def).await,
+            StorageIOManager::Memory(io) => io.delete_file(file_def).await,
+            StorageIOManager::S3(io) => io.delete_file(file_def).await,
+        }
+    }
+
+    async fn store_version(&self, file: &FileDefinition, revision: u64) -> Result<(), String> {
+        match self {
+            StorageIOManager::Folder(io) => io.store_version(file, revision).await,
+            StorageIOManager::Chunked(io) => io.store_version(file, revision).await,
+            StorageIOManager::Memory(io) => io.store_version(file, revision).await,
+            StorageIOManager::S3(io) => io.store_version(file, revision).await,
+        }
+    }
+
+    async fn open_version_content(&self, file: &FileDefinition, revision: u64, offset: u64) -> Result<(ContentReader, u64), String> {
+        match self {
+            StorageIOManager::Folder(io) => io.open_version_content(file, revision, offset).await,
+            StorageIOManager::Chunked(io) => io.open_version_content(file, revision, offset).await,
+            StorageIOManager::Memory(io) => io.open_version_content(file, revision, offset).await,
+            StorageIOManager::S3(io) => io.open_version_content(file, revision, offset).await,
+        }
+    }
+
+    async fn delete_version(&self, file: &FileDefinition, revision: u64) -> Result<(), String> {
+        match self {
+            StorageIOManager::Folder(io) => io.delete_version(file, revision).await,
+            StorageIOManager::Chunked(io) => io.delete_version(file, revision).await,
+            StorageIOManager::Memory(io) => io.delete_version(file, revision).await,
+            StorageIOManager::S3(io) => io.delete_version(file, revision).await,
+        }
+    }
+
+    async fn trash_file(&self, file: &FileDefinition) -> Result<(), String> {
+        match self {
+            StorageIOManager::Folder(io) => io.trash_file(file).await,
+            StorageIOManager::Chunked(io) => io.trash_file(file).await,
+            StorageIOManager::Memory(io) => io.trash_file(file).await,
+            StorageIOManager::S3(io) => io.trash_file(file).await,
+        }
+    }
+
+    async fn restore_trashed(&self, file: &FileDefinition) -> Result<(), String> {
+        match self {
+            StorageIOManager::Folder(io) => io.restore_trashed(file).await,
+            StorageIOManager::Chunked(io) => io.restore_trashed(file).await,
+            StorageIOManager::Memory(io) => io.restore_trashed(file).await,
+            StorageIOManager::S3(io) => io.restore_trashed(file).await,
+        }
+    }
+
+    async fn purge_trashed(&self, file: &FileDefinition) -> Result<(), String> {
+        match self {
+            StorageIOManager::Folder(io) => io.purge_trashed(file).await,
+            StorageIOManager::Chunked(io) => io.purge_trashed(file).await,
+            StorageIOManager::Memory(io) => io.purge_trashed(file).await,
+            StorageIOManager::S3(io) => io.purge_trashed(file).await,
+        }
+    }
+
+    async fn list_files(&self) -> Result<Vec<String>, String> {
+        match self {
+            StorageIOManager::Folder(io) => io.list_files().await,
+            StorageIOManager::Chunked(io) => io.list_files().await,
+            StorageIOManager::Memory(io) => io.list_files().await,
+            StorageIOManager::S3(io) => io.list_files().await,
+        }
+    }
+}
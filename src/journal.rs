@@ -0,0 +1,272 @@
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::collections::HashMap;
+
+use serde::Serialize;
+use serde::Deserialize;
+use xxhash_rust::xxh3;
+
+use crate::extras::ExtrasDelta;
+use crate::extras::TouchedExtras;
+use crate::model::FileChange;
+use crate::model::ChangeType;
+use crate::model::FileDefinition;
+use crate::model::RevisionHistory;
+use crate::model::FileRepositoryState;
+
+
+#[derive(Serialize)]
+struct RecordRef<'a> {
+    revision: Option<u64>,
+    change: Option<&'a FileChange>,
+    extras: Option<ExtrasDelta>         // Only the rows the change touched.
+}
+#[derive(Deserialize)]
+struct Record {
+    revision: Option<u64>,
+    change: Option<FileChange>,
+    extras: Option<ExtrasDelta>
+}
+
+#[derive(Serialize, Deserialize)]
+struct Compacted {
+    state: FileRepositoryState,
+    contents: Vec<FileDefinition>
+}
+
+struct JournalFile {
+    file: File,
+    records: u64                // Appended since the last compaction.
+}
+
+/// Repository metadata as an append-only journal of checksummed records under `.journal`,
+/// compacted every so often into `snapshot.json`. Each record is fsynced before the change is acknowledged.
+/// A record is `[payload length: u32][xxh3 of payload: u64][JSON payload]`, little endian.
+pub struct JournalStore {
+    path: PathBuf,
+    compaction: u64,            // Records after which the journal is folded into the snapshot.
+    journal: Mutex<JournalFile>
+}
+impl JournalStore {
+    const HEADER_LEN: usize = 12;
+
+    pub fn open(path: &str, compaction: u64) -> Result<Self, String> {
+        let path = PathBuf::from(path);
+        std::fs::create_dir_all(&path).map_err(|e| e.to_string())?;
+        let file = OpenOptions::new().create(true).append(true).read(true)
+                .open(path.join("journal.log"))
+                .map_err(|e| e.to_string())?;
+        Ok(Self {
+            path,
+            compaction,
+            journal: Mutex::new(JournalFile { file, records: 0 })
+        })
+    }
+
+    pub fn is_empty(&self) -> Result<bool, String> {
+        let len = self.journal().file.metadata().map_err(|e| e.to_string())?.len();
+        Ok(len == 0 && !self.get_snapshot_path().is_file())
+    }
+
+    /// Reads the last snapshot and replays the journal over it.
+    /// A torn or corrupt record ends the journal: it and anything after it are truncated.
+    pub fn load(&self) -> Result<(FileRepositoryState, HashMap<String, FileDefinition>), String> {
+        let (mut state, mut contents) = match std::fs::read(self.get_snapshot_path()) {
+            Ok(data) => {
+                let compacted: Compacted = serde_json::from_slice(&data).map_err(|e| e.to_string())?;
+                let contents = compacted.contents.into_iter()
+                        .map(|fd| Ok((fd.id.clone().ok_or(format!("File {} in the snapshot has no id", fd.name))?, fd)))
+                        .collect::<Result<_, String>>()?;
+                (compacted.state, contents)
+            },
+            Err(_) => (Self::empty_state(), HashMap::new()),
+        };
+
+        let mut journal = self.journal();
+        let mut data = Vec::new();
+        let mut reader = File::open(self.get_journal_path()).map_err(|e| e.to_string())?;
+        reader.read_to_end(&mut data).map_err(|e| e.to_string())?;
+
+        let mut offset = 0;
+        let mut records = 0;
+        while offset < data.len() {
+            let Some(record) = Self::parse_record(&data[offset..]) else {
+                println!("Truncating torn journal record at offset {offset}.");
+                journal.file.set_len(offset as u64).map_err(|e| e.to_string())?;
+                journal.file.sync_all().map_err(|e| e.to_string())?;
+                break;
+            };
+            offset += Self::HEADER_LEN + Self::payload_len(&data[offset..]);
+            records += 1;
+            Self::apply(&mut state, &mut contents, record)?;
+        }
+        journal.records = records;
+
+        Ok((state, contents))
+    }
+
+    /// Appends the latest revision of `state` and the `touched` extras rows, compacting when the journal has grown enough.
+    /// Once the record is synced the change is durable, so a failed compaction is only logged.
+    pub fn record_change(&self, state: &FileRepositoryState, contents: &HashMap<String, FileDefinition>,
+                touched: &TouchedExtras) -> Result<(), String> {
+        let change = state.history.revisions.last().ok_or("No change to record".to_string())?;
+        self.append(Some(state.current_revision), Some(change), touched.delta(state))?;
+        self.compact_if_due(state, contents);
+        Ok(())
+    }
+
+    pub fn save_extras(&self, state: &FileRepositoryState, contents: &HashMap<String, FileDefinition>,
+                touched: &TouchedExtras) -> Result<(), String> {
+        self.append(None, None, touched.delta(state))?;
+        self.compact_if_due(state, contents);
+        Ok(())
+    }
+
+    /// Replaces everything with `state` and `contents`.
+    pub fn import(&self, state: &FileRepositoryState, contents: &HashMap<String, FileDefinition>) -> Result<(), String> {
+        self.compact(state, contents)
+    }
+
+    /// Writes and syncs one record. On failure the log is cut back to where it was,
+    /// so that a torn record doesn't end the journal ahead of later ones.
+    fn append(&self, revision: Option<u64>, change: Option<&FileChange>, extras: ExtrasDelta) -> Result<(), String> {
+        let mut journal = self.journal();
+        if change.is_none() && extras.is_empty() {
+            return Ok(());
+        }
+        let record = RecordRef {
+            revision,
+            change,
+            extras: (!extras.is_empty()).then_some(extras)
+        };
+        let payload = serde_json::to_vec(&record).map_err(|e| e.to_string())?;
+
+        let mut buffer = Vec::with_capacity(Self::HEADER_LEN + payload.len());
+        buffer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&xxh3::xxh3_64(&payload).to_le_bytes());
+        buffer.extend_from_slice(&payload);
+        let len = journal.file.metadata().map_err(|e| e.to_string())?.len();
+        let written = journal.file.write_all(&buffer)
+                .and_then(|_| journal.file.sync_data());
+        if let Err(e) = written {
+            if let Err(truncate_error) = journal.file.set_len(len).and_then(|_| journal.file.sync_all()) {
+                return Err(format!("{e}, and the journal could not be truncated back: {truncate_error}"));
+            }
+            return Err(e.to_string());
+        }
+
+        journal.records += 1;
+        Ok(())
+    }
+
+    fn compact_if_due(&self, state: &FileRepositoryState, contents: &HashMap<String, FileDefinition>) {
+        if self.journal().records < self.compaction {
+            return;
+        }
+        if let Err(e) = self.compact(state, contents) {
+            println!("Error compacting the journal: {e}");
+        }
+    }
+
+    /// Writes the whole state as the new snapshot, then empties the journal.
+    /// Records already in the snapshot are skipped on replay, should the process die in between.
+    fn compact(&self, state: &FileRepositoryState, contents: &HashMap<String, FileDefinition>) -> Result<(), String> {
+        let mut journal = self.journal();
+        let compacted = serde_json::to_vec(&Compacted {
+            state: Self::clone_state(state)?,
+            contents: contents.values().cloned().collect()
+        }).map_err(|e| e.to_string())?;
+
+        let snapshot_path = self.get_snapshot_path();
+        let temp_path = self.path.join("snapshot.json.tmp");
+        let mut temp = File::create(&temp_path).map_err(|e| e.to_string())?;
+        temp.write_all(&compacted).map_err(|e| e.to_string())?;
+        temp.sync_all().map_err(|e| e.to_string())?;
+        std::fs::rename(&temp_path, &snapshot_path).map_err(|e| e.to_string())?;
+        File::open(&self.path).and_then(|dir| dir.sync_all()).map_err(|e| e.to_string())?;
+
+        journal.file.set_len(0).map_err(|e| e.to_string())?;
+        journal.file.sync_all().map_err(|e| e.to_string())?;
+        journal.records = 0;
+        Ok(())
+    }
+
+    fn apply(state: &mut FileRepositoryState, contents: &mut HashMap<String, FileDefinition>, record: Record) -> Result<(), String> {
+        if let (Some(revision), Some(change)) = (record.revision, record.change) {
+            if revision > state.current_revision {
+                if revision != state.current_revision + 1 {
+                    return Err(format!("Revision {revision} out of sequence"));
+                }
+                let id = change.file.id.clone().ok_or(format!("Revision {revision} has no file id"))?;
+                match change.change {
+                    ChangeType::Delete => {
+                        contents.remove(&id);
+                    },
+                    _ => {
+                        contents.insert(id, change.file.clone());
+                    },
+                }
+                state.add_revision(change);
+            }
+        }
+        if let Some(extras) = record.extras {
+            extras.apply(state);
+        }
+        Ok(())
+    }
+
+    /// The record at the start of `data`, if it is complete and its checksum matches.
+    fn parse_record(data: &[u8]) -> Option<Record> {
+        if data.len() < Self::HEADER_LEN {
+            return None;
+        }
+        let len = Self::payload_len(data);
+        let checksum = u64::from_le_bytes(data[4..Self::HEADER_LEN].try_into().ok()?);
+        let payload = data.get(Self::HEADER_LEN..Self::HEADER_LEN + len)?;
+        if xxh3::xxh3_64(payload) != checksum {
+            return None;
+        }
+        serde_json::from_slice(payload).ok()
+    }
+    fn payload_len(data: &[u8]) -> usize {
+        u32::from_le_bytes(data[..4].try_into().expect("Short header")) as usize
+    }
+
+    fn clone_state(state: &FileRepositoryState) -> Result<FileRepositoryState, String> {
+        let data = serde_json::to_vec(state).map_err(|e| e.to_string())?;
+        serde_json::from_slice(&data).map_err(|e| e.to_string())
+    }
+    fn empty_state() -> FileRepositoryState {
+        FileRepositoryState {
+            current_revision: 0,
+            history: RevisionHistory { revisions: Vec::new() },
+            versions: HashMap::new(),
+            trash: HashMap::new(),
+            snapshots: Vec::new(),
+            snapshot_files: HashMap::new(),
+            quarantined: HashMap::new()
+        }
+    }
+
+    fn journal(&self) -> MutexGuard<'_, JournalFile> {
+        self.journal.lock().expect("Journal poisoned")
+    }
+
+    fn get_journal_path(&self) -> PathBuf {
+        self.path.join("journal.log")
+    }
+    fn get_snapshot_path(&self) -> PathBuf {
+        self.path.join("snapshot.json")
+    }
+    pub fn get_default_path(state_path: &str) -> String {
+        let binding = Path::new(state_path).join(".journal");
+        binding.to_str().unwrap().to_string()
+    }
+}
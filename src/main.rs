@@ -3,23 +3,213 @@ mod util;
 mod routes;
 mod config;
 mod repository;
+mod registry;
+mod auth;
+mod access;
+mod metadata;
+mod journal;
+mod extras;
 mod io_manager;
+mod chunk_store;
+mod memory_store;
+mod s3_store;
 pub mod model;
 mod tests;
 mod patcher;
+mod headers;
+mod responses;
+mod upload;
+mod delta;
+mod scrubber;
 
 #[macro_use] extern crate rocket;
 
+use std::sync::Arc;
+use std::collections::BTreeMap;
+
+use rocket::Build;
+use rocket::Rocket;
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::tokio::sync::Mutex;
+
+use config::Config;
+use auth::TokenStore;
+use access::AccessStore;
+use registry::RepositoryRegistry;
+use scrubber::Scrubber;
+
+use routes::get_repositories;
+use routes::create_repository;
+use routes::delete_repository;
+
 use routes::get_file;
 use routes::create_empty;
 use routes::update_file;
 use routes::delete_file;
 
+use routes::get_trash;
+use routes::restore_trashed;
+
 use routes::get_patch;
 
-#[launch]
-fn rocket() -> _ {
-    rocket::build()
-            .mount("/api/v1/", routes![get_file, create_empty, update_file, delete_file,
-                        get_patch])
+use routes::create_snapshot;
+use routes::get_snapshots;
+use routes::get_snapshot_files;
+use routes::restore_snapshot;
+use routes::delete_snapshot;
+
+use routes::get_versions;
+use routes::get_version;
+use routes::restore_version;
+
+use routes::get_signature;
+use routes::update_from_delta;
+use routes::get_delta;
+
+use routes::open_upload;
+use routes::get_upload;
+use routes::append_upload;
+use routes::commit_upload;
+use routes::abort_upload;
+
+use routes::verify;
+use routes::verify_all;
+use routes::get_tokens;
+use routes::mint_token;
+use routes::revoke_token;
+use routes::get_users;
+use routes::put_user;
+use routes::delete_user;
+use routes::get_grants;
+use routes::add_grant;
+use routes::revoke_grant;
+use routes::unauthorized;
+use routes::default_repository_aliases;
+
+/// Server around `registry`, e.g. an in-memory one when embedded in tests.
+pub fn build(registry: RepositoryRegistry) -> Rocket<Build> {
+    let tokens = TokenStore::open(registry.get_config()).expect("Unable to open token store");
+    let access = AccessStore::open(registry.get_config()).expect("Unable to open access store");
+    build_with(rocket::Config::figment(), registry, tokens, access)
+}
+
+/// Server around `registry` accepting `tokens`, limited by the grants in `access`,
+/// with Rocket's own settings taken from `figment`.
+pub fn build_with(figment: Figment, registry: RepositoryRegistry, tokens: TokenStore, access: AccessStore) -> Rocket<Build> {
+    let registry = Arc::new(Mutex::new(registry));
+    let scrubbed = registry.clone();
+    rocket::custom(figment)
+            .manage(registry)
+            .manage(Mutex::new(tokens))
+            .manage(Mutex::new(access))
+            .register("/", catchers![unauthorized])
+            .attach(default_repository_aliases())
+            .attach(AdHoc::on_liftoff("Scrubber", |_| Box::pin(async move {
+                rocket::tokio::spawn(async move { Scrubber::run(&scrubbed).await });
+            })))
+            .mount("/api/v1/", routes![get_repositories, create_repository, delete_repository,
+                        get_file, create_empty, update_file, delete_file,
+                        get_trash, restore_trashed,
+                        get_patch,
+                        create_snapshot, get_snapshots, get_snapshot_files, restore_snapshot, delete_snapshot,
+                        get_versions, get_version, restore_version,
+                        get_signature, update_from_delta, get_delta,
+                        open_upload, get_upload, append_upload, commit_upload, abort_upload])
+            .mount("/admin/", routes![verify, verify_all, get_tokens, mint_token, revoke_token,
+                        get_users, put_user, delete_user, get_grants, add_grant, revoke_grant])
+}
+
+/// Serves the repositories, or with `--verify [--repair]` checks them, prints a report per repository
+/// and exits with status 1 if anything was found. `--mint-token <name> [--admin] [--user <user>]` prints
+/// a new token and `--revoke-token <id>` revokes one, also while the server runs: it picks up the change on the next request.
+/// Any other `--<key> <value>` flag overrides that config key.
+#[rocket::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (config, figment) = match Config::load(&args) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            println!("[Error [config]: {e}");
+            std::process::exit(2);
+        }
+    };
+    let mut tokens = match TokenStore::open(&config) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            println!("[Error [main]: Unable to open token store: {e}");
+            std::process::exit(2);
+        }
+    };
+    let access = match AccessStore::open(&config) {
+        Ok(access) => access,
+        Err(e) => {
+            println!("[Error [main]: Unable to open access store: {e}");
+            std::process::exit(2);
+        }
+    };
+    if let Some(name) = flag_value(&args, "--mint-token") {
+        let admin = args.iter().any(|arg| arg == "--admin");
+        let user = flag_value(&args, "--user").map(|user| match access.get_user(user) {
+            Some(user) => user,
+            None => {
+                println!("[Error [mint_token]: User {user} not found");
+                std::process::exit(2);
+            }
+        });
+        match tokens.mint(name, admin, user) {
+            Ok(minted) => println!("{}", serde_json::to_string_pretty(&minted).expect("Token serialization error.")),
+            Err(e) => {
+                println!("[Error [mint_token]: {e}");
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+    if let Some(id) = flag_value(&args, "--revoke-token") {
+        match tokens.revoke(id) {
+            Ok(true) => println!("Revoked {id}"),
+            Ok(false) => {
+                println!("[Error [revoke_token]: Token {id} not found");
+                std::process::exit(1);
+            },
+            Err(e) => {
+                println!("[Error [revoke_token]: {e}");
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+    let registry = match RepositoryRegistry::load(config) {
+        Ok(registry) => registry,
+        Err(e) => {
+            println!("[Error [main]: {e}");
+            std::process::exit(2);
+        }
+    };
+    if args.iter().any(|arg| arg == "--verify") {
+        let repair = args.iter().any(|arg| arg == "--repair");
+        let mut reports = BTreeMap::new();
+        for name in registry.get_names() {
+            let named = registry.get(&name).expect("Listed repository not found");
+            match named.lock().await.verify(repair).await {
+                Ok(report) => reports.insert(name, report),
+                Err(e) => {
+                    println!("[Error [verify]: {name}: {e}");
+                    std::process::exit(2);
+                }
+            };
+        }
+        println!("{}", serde_json::to_string_pretty(&reports).expect("Report serialization error."));
+        std::process::exit(if reports.values().all(|report| report.is_clean()) { 0 } else { 1 });
+    }
+    if let Err(e) = build_with(figment, registry, tokens, access).launch().await {
+        println!("[Error [main]: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let index = args.iter().position(|arg| arg == flag)?;
+    args.get(index + 1).map(|value| value.as_str())
 }
@@ -0,0 +1,129 @@
+
+use std::io::Cursor;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::collections::HashMap;
+
+use rocket::tokio::io::AsyncRead;
+use rocket::tokio::io::AsyncReadExt;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::util::Util;
+use crate::model::FileDefinition;
+use crate::io_manager::IOManager;
+use crate::io_manager::ContentReader;
+use crate::io_manager::StoredContent;
+
+
+/// Keeps all content in memory, for tests and embedding without touching the disk.
+/// Versions and trashed files are kept under `<id>@<rev>` and `<id>@trash`.
+#[derive(Default)]
+pub struct InMemoryIOManager {
+    contents: Mutex<HashMap<String, Arc<[u8]>>>
+}
+impl InMemoryIOManager {
+    fn get(&self, key: &str) -> Option<Arc<[u8]>> {
+        self.contents.lock().expect("Memory store poisoned").get(key).cloned()
+    }
+    fn insert(&self, key: String, content: Arc<[u8]>) {
+        self.contents.lock().expect("Memory store poisoned").insert(key, content);
+    }
+    fn remove(&self, key: &str) -> Option<Arc<[u8]>> {
+        self.contents.lock().expect("Memory store poisoned").remove(key)
+    }
+    fn rename(&self, from: &str, to: String) -> Result<(), String> {
+        let content = self.remove(from).ok_or("File not found.".to_string())?;
+        self.insert(to, content);
+        Ok(())
+    }
+
+    fn open(content: Arc<[u8]>, offset: u64) -> (ContentReader, u64) {
+        let len = content.len() as u64;
+        let mut reader = Cursor::new(content);
+        reader.set_position(offset.min(len));
+        (Box::pin(reader), len)
+    }
+
+    fn get_id(file_def: &FileDefinition) -> &str {
+        file_def.id.as_ref().expect("No id in File Definition")
+    }
+    fn get_version_key(file_def: &FileDefinition, revision: u64) -> String {
+        format!("{}@{revision}", Self::get_id(file_def))
+    }
+    fn get_trash_key(file_def: &FileDefinition) -> String {
+        format!("{}@trash", Self::get_id(file_def))
+    }
+}
+
+impl IOManager for InMemoryIOManager {
+    async fn open_file_content(&self, file: &FileDefinition, offset: u64) -> Result<(ContentReader, u64), String> {
+        let content = self.get(Self::get_id(file)).ok_or("File not found.".to_string())?;
+        Ok(Self::open(content, offset))
+    }
+
+    async fn store_file_stream(&self, file_def: &FileDefinition, content: &mut (dyn AsyncRead + Send + Unpin),
+                max_size: u64) -> Result<StoredContent, String> {
+        let mut data = Vec::new();
+        let read = content.take(max_size.saturating_add(1)).read_to_end(&mut data).await
+                .map_err(|e| e.to_string())?;
+        if read as u64 > max_size {
+            return Err("Content exceeds size limit.".to_string());
+        }
+        let mut hasher = Xxh3::new();
+        hasher.update(&data);
+
+        let size = data.len() as u64;
+        self.insert(Self::get_id(file_def).to_string(), data.into());
+        Ok(StoredContent { size, checksum: Util::format_checksum(hasher.digest()) })
+    }
+
+    async fn create_empty(&self, file_def: &FileDefinition) -> Result<bool, String> {
+        self.insert(Self::get_id(file_def).to_string(), Arc::from([]));
+        Ok(true)
+    }
+
+    async fn delete_file(&self, file_def: &FileDefinition) -> Result<bool, String> {
+        match self.remove(Self::get_id(file_def)) {
+            Some(_) => Ok(true),
+            None => Err("File not found.".to_string()),
+        }
+    }
+
+    async fn store_version(&self, file: &FileDefinition, revision: u64) -> Result<(), String> {
+        let content = self.get(Self::get_id(file)).ok_or("File not found.".to_string())?;
+        self.insert(Self::get_version_key(file, revision), content);
+        Ok(())
+    }
+
+    async fn open_version_content(&self, file: &FileDefinition, revision: u64, offset: u64) -> Result<(ContentReader, u64), String> {
+        let content = self.get(&Self::get_version_key(file, revision)).ok_or("Version not found.".to_string())?;
+        Ok(Self::open(content, offset))
+    }
+
+    async fn delete_version(&self, file: &FileDefinition, revision: u64) -> Result<(), String> {
+        self.remove(&Self::get_version_key(file, revision))
+                .map(|_| ())
+                .ok_or("Version not found.".to_string())
+    }
+
+    async fn trash_file(&self, file: &FileDefinition) -> Result<(), String> {
+        self.rename(Self::get_id(file), Self::get_trash_key(file))
+    }
+
+    async fn restore_trashed(&self, file: &FileDefinition) -> Result<(), String> {
+        self.rename(&Self::get_trash_key(file), Self::get_id(file).to_string())
+    }
+
+    async fn purge_trashed(&self, file: &FileDefinition) -> Result<(), String> {
+        self.remove(&Self::get_trash_key(file))
+                .map(|_| ())
+                .ok_or("File not found.".to_string())
+    }
+
+    async fn list_files(&self) -> Result<Vec<String>, String> {
+        Ok(self.contents.lock().expect("Memory store poisoned").keys()
+                .filter(|key| !key.contains('@'))
+                .cloned()
+                .collect())
+    }
+}
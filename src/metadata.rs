@@ -0,0 +1,324 @@
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::collections::HashMap;
+
+use rusqlite::params;
+use rusqlite::Connection;
+use rusqlite::OptionalExtension;
+use rusqlite::Transaction;
+
+use crate::config::Config;
+use crate::config::Metadata;
+use crate::journal::JournalStore;
+use crate::extras::ExtrasDelta;
+use crate::extras::TouchedExtras;
+use crate::model::FileChange;
+use crate::model::ChangeType;
+use crate::model::FileDefinition;
+use crate::model::RevisionHistory;
+use crate::model::FileRepositoryState;
+
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS files (
+        id TEXT PRIMARY KEY,
+        path TEXT NOT NULL,
+        name TEXT NOT NULL,
+        definition TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS files_path ON files (path, name);
+    CREATE TABLE IF NOT EXISTS revisions (
+        revision INTEGER PRIMARY KEY,
+        file_id TEXT,
+        change TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS revisions_file_id ON revisions (file_id, revision);
+    CREATE TABLE IF NOT EXISTS versions (
+        file_id TEXT PRIMARY KEY,
+        definitions TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS trash (
+        file_id TEXT PRIMARY KEY,
+        trashed TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS snapshots (
+        name TEXT PRIMARY KEY,
+        snapshot TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS snapshot_files (
+        name TEXT PRIMARY KEY,
+        definitions TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS quarantined (
+        file_id TEXT PRIMARY KEY,
+        revision TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS extras (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+";
+
+/// Where the repository persists its state, selected by the `metadata` setting.
+pub enum MetadataStore {
+    Sqlite(SqliteStore),
+    Journal(JournalStore)
+}
+impl MetadataStore {
+    /// Opens the configured store under the state path.
+    pub fn open(config: &Config) -> Result<Self, String> {
+        let state_path = config.get_state_path();
+        std::fs::create_dir_all(state_path).map_err(|e| e.to_string())?;
+        match config.metadata {
+            Metadata::Sqlite => SqliteStore::open(&SqliteStore::get_default_path(state_path)).map(MetadataStore::Sqlite),
+            Metadata::Journal => JournalStore::open(&JournalStore::get_default_path(state_path), config.journal_compaction)
+                    .map(MetadataStore::Journal),
+        }
+    }
+
+    pub fn is_empty(&self) -> Result<bool, String> {
+        match self {
+            MetadataStore::Sqlite(store) => store.is_empty(),
+            MetadataStore::Journal(store) => store.is_empty(),
+        }
+    }
+
+    pub fn load(&self) -> Result<(FileRepositoryState, HashMap<String, FileDefinition>), String> {
+        match self {
+            MetadataStore::Sqlite(store) => store.load(),
+            MetadataStore::Journal(store) => store.load(),
+        }
+    }
+
+    /// Persists the latest revision of `state` along with the `touched` extras rows.
+    pub fn record_change(&self, state: &FileRepositoryState, contents: &HashMap<String, FileDefinition>,
+                touched: &TouchedExtras) -> Result<(), String> {
+        match self {
+            MetadataStore::Sqlite(store) => store.record_change(state, touched),
+            MetadataStore::Journal(store) => store.record_change(state, contents, touched),
+        }
+    }
+
+    pub fn save_extras(&self, state: &FileRepositoryState, contents: &HashMap<String, FileDefinition>,
+                touched: &TouchedExtras) -> Result<(), String> {
+        match self {
+            MetadataStore::Sqlite(store) => store.save_extras(state, touched),
+            MetadataStore::Journal(store) => store.save_extras(state, contents, touched),
+        }
+    }
+
+    pub fn import(&self, state: &FileRepositoryState, contents: &HashMap<String, FileDefinition>) -> Result<(), String> {
+        match self {
+            MetadataStore::Sqlite(store) => store.import(state, contents),
+            MetadataStore::Journal(store) => store.import(state, contents),
+        }
+    }
+}
+
+
+/// Repository metadata kept in SQLite: live definitions in `files`, one row per revision in `revisions`,
+/// and one row per file or snapshot in `versions`, `trash`, `snapshots` and `snapshot_files`.
+/// Each change is written in its own transaction, along with only the rows it touched.
+/// `extras` is where versions, trash and snapshots used to be kept whole; it is migrated on open.
+pub struct SqliteStore {
+    connection: Mutex<Connection>       // Only for `Sync`, the repository lock already serializes access.
+}
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let connection = Connection::open(path).map_err(|e| e.to_string())?;
+        connection.pragma_update(None, "journal_mode", "WAL").map_err(|e| e.to_string())?;
+        connection.pragma_update(None, "synchronous", "FULL").map_err(|e| e.to_string())?;
+        connection.execute_batch(SCHEMA).map_err(|e| e.to_string())?;
+        Self::migrate_extras(&connection)?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+    pub fn is_empty(&self) -> Result<bool, String> {
+        let count: u64 = self.connection().query_row("SELECT (SELECT COUNT(*) FROM files) + (SELECT COUNT(*) FROM revisions)", [],
+                |row| row.get(0)).map_err(|e| e.to_string())?;
+        Ok(count == 0)
+    }
+
+    pub fn load(&self) -> Result<(FileRepositoryState, HashMap<String, FileDefinition>), String> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT revision, change FROM revisions ORDER BY revision")
+                .map_err(|e| e.to_string())?;
+        let rows = statement.query_map([], |row| Ok((row.get::<_, u64>(0)?, row.get::<_, String>(1)?)))
+                .map_err(|e| e.to_string())?;
+        let mut revisions = Vec::new();
+        for row in rows {
+            let (revision, change) = row.map_err(|e| e.to_string())?;
+            if revision != revisions.len() as u64 + 1 {
+                return Err(format!("Revision {revision} out of sequence"));
+            }
+            revisions.push(serde_json::from_str::<FileChange>(&change).map_err(|e| e.to_string())?);
+        }
+
+        let mut statement = connection.prepare("SELECT id, definition FROM files")
+                .map_err(|e| e.to_string())?;
+        let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+                .map_err(|e| e.to_string())?;
+        let mut contents = HashMap::new();
+        for row in rows {
+            let (id, definition) = row.map_err(|e| e.to_string())?;
+            contents.insert(id, serde_json::from_str(&definition).map_err(|e| e.to_string())?);
+        }
+
+        let state = FileRepositoryState {
+            current_revision: revisions.len() as u64,
+            history: RevisionHistory { revisions },
+            versions: Self::read_rows(&connection, "SELECT file_id, definitions FROM versions")?.into_iter().collect(),
+            trash: Self::read_rows(&connection, "SELECT file_id, trashed FROM trash")?.into_iter().collect(),
+            snapshots: Self::read_rows(&connection, "SELECT name, snapshot FROM snapshots ORDER BY rowid")?.into_iter()
+                    .map(|(_, snapshot)| snapshot)
+                    .collect(),
+            snapshot_files: Self::read_rows(&connection, "SELECT name, definitions FROM snapshot_files")?.into_iter().collect(),
+            quarantined: Self::read_rows(&connection, "SELECT file_id, revision FROM quarantined")?.into_iter().collect()
+        };
+        Ok((state, contents))
+    }
+
+    /// Persists the latest revision of `state` along with its effect on `files` and the `touched` extras rows.
+    pub fn record_change(&self, state: &FileRepositoryState, touched: &TouchedExtras) -> Result<(), String> {
+        let change = state.history.revisions.last().ok_or("No change to record".to_string())?;
+        let connection = self.connection();
+        let transaction = connection.unchecked_transaction().map_err(|e| e.to_string())?;
+        Self::insert_change(&transaction, state.current_revision, change)?;
+        Self::write_extras(&transaction, &touched.delta(state))?;
+        transaction.commit().map_err(|e| e.to_string())
+    }
+
+    /// Persists the `touched` rows of versions, trash and snapshots, for changes that don't add a revision.
+    pub fn save_extras(&self, state: &FileRepositoryState, touched: &TouchedExtras) -> Result<(), String> {
+        let changed = touched.delta(state);
+        if changed.is_empty() {
+            return Ok(());
+        }
+        let connection = self.connection();
+        let transaction = connection.unchecked_transaction().map_err(|e| e.to_string())?;
+        Self::write_extras(&transaction, &changed)?;
+        transaction.commit().map_err(|e| e.to_string())
+    }
+
+    /// Replaces everything with `state` and `contents`, as a single transaction.
+    pub fn import(&self, state: &FileRepositoryState, contents: &HashMap<String, FileDefinition>) -> Result<(), String> {
+        let connection = self.connection();
+        let changed = TouchedExtras::all(state).delta(state);
+        let transaction = connection.unchecked_transaction().map_err(|e| e.to_string())?;
+        transaction.execute_batch("DELETE FROM files; DELETE FROM revisions; DELETE FROM versions; DELETE FROM trash;
+                    DELETE FROM snapshots; DELETE FROM snapshot_files; DELETE FROM quarantined; DELETE FROM extras;")
+                .map_err(|e| e.to_string())?;
+        for (index, change) in state.history.revisions.iter().enumerate() {
+            transaction.execute("INSERT INTO revisions (revision, file_id, change) VALUES (?1, ?2, ?3)",
+                    params![index as u64 + 1, change.file.id, Self::to_json(change)?])
+                    .map_err(|e| e.to_string())?;
+        }
+        for file in contents.values() {
+            Self::upsert_file(&transaction, file)?;
+        }
+        Self::write_extras(&transaction, &changed)?;
+        transaction.commit().map_err(|e| e.to_string())
+    }
+
+    fn insert_change(transaction: &Transaction, revision: u64, change: &FileChange) -> Result<(), String> {
+        transaction.execute("INSERT INTO revisions (revision, file_id, change) VALUES (?1, ?2, ?3)",
+                params![revision, change.file.id, Self::to_json(change)?])
+                .map_err(|e| e.to_string())?;
+        match change.change {
+            ChangeType::Delete => {
+                transaction.execute("DELETE FROM files WHERE id = ?1", params![change.file.id])
+                        .map_err(|e| e.to_string())?;
+            },
+            _ => Self::upsert_file(transaction, &change.file)?,
+        }
+        Ok(())
+    }
+
+    fn upsert_file(transaction: &Transaction, file: &FileDefinition) -> Result<(), String> {
+        transaction.execute("INSERT INTO files (id, path, name, definition) VALUES (?1, ?2, ?3, ?4)
+                    ON CONFLICT (id) DO UPDATE SET path = ?2, name = ?3, definition = ?4",
+                params![file.id, file.path, file.name, Self::to_json(file)?])
+                .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Writes the changed rows of versions, trash, snapshots and quarantined files, and deletes the removed ones.
+    fn write_extras(transaction: &Transaction, changed: &ExtrasDelta) -> Result<(), String> {
+        Self::write_rows(transaction, "versions", "file_id", "definitions", &changed.versions)?;
+        Self::write_rows(transaction, "trash", "file_id", "trashed", &changed.trash)?;
+        Self::write_rows(transaction, "snapshots", "name", "snapshot", &changed.snapshots)?;
+        Self::write_rows(transaction, "snapshot_files", "name", "definitions", &changed.snapshot_files)?;
+        Self::write_rows(transaction, "quarantined", "file_id", "revision", &changed.quarantined)
+    }
+
+    fn write_rows<T: serde::Serialize>(transaction: &Transaction, table: &str, key: &str, value: &str,
+                rows: &HashMap<String, Option<T>>) -> Result<(), String> {
+        for (id, row) in rows {
+            match row {
+                Some(row) => transaction.execute(&format!("INSERT INTO {table} ({key}, {value}) VALUES (?1, ?2)
+                            ON CONFLICT ({key}) DO UPDATE SET {value} = ?2"),
+                        params![id, Self::to_json(row)?]),
+                None => transaction.execute(&format!("DELETE FROM {table} WHERE {key} = ?1"), params![id]),
+            }.map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn read_rows<T: serde::de::DeserializeOwned>(connection: &Connection, query: &str) -> Result<Vec<(String, T)>, String> {
+        let mut statement = connection.prepare(query).map_err(|e| e.to_string())?;
+        let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+                .map_err(|e| e.to_string())?;
+        let mut res = Vec::new();
+        for row in rows {
+            let (key, value) = row.map_err(|e| e.to_string())?;
+            res.push((key, serde_json::from_str(&value).map_err(|e| e.to_string())?));
+        }
+        Ok(res)
+    }
+
+    /// Moves versions, trash and snapshots kept whole in `extras` into their own tables.
+    fn migrate_extras(connection: &Connection) -> Result<(), String> {
+        let legacy: u64 = connection.query_row("SELECT COUNT(*) FROM extras", [], |row| row.get(0))
+                .map_err(|e| e.to_string())?;
+        if legacy == 0 {
+            return Ok(());
+        }
+        let state = FileRepositoryState {
+            current_revision: 0,
+            history: RevisionHistory { revisions: Vec::new() },
+            versions: Self::read_extra(connection, "versions")?.unwrap_or_default(),
+            trash: Self::read_extra(connection, "trash")?.unwrap_or_default(),
+            snapshots: Self::read_extra(connection, "snapshots")?.unwrap_or_default(),
+            snapshot_files: Self::read_extra(connection, "snapshot_files")?.unwrap_or_default(),
+            quarantined: HashMap::new()
+        };
+        let changed = TouchedExtras::all(&state).delta(&state);
+        let transaction = connection.unchecked_transaction().map_err(|e| e.to_string())?;
+        Self::write_extras(&transaction, &changed)?;
+        transaction.execute("DELETE FROM extras", []).map_err(|e| e.to_string())?;
+        transaction.commit().map_err(|e| e.to_string())
+    }
+
+    fn read_extra<T: serde::de::DeserializeOwned>(connection: &Connection, key: &str) -> Result<Option<T>, String> {
+        let value: Option<String> = connection.query_row("SELECT value FROM extras WHERE key = ?1", params![key],
+                |row| row.get(0)).optional().map_err(|e| e.to_string())?;
+        match value {
+            Some(value) => serde_json::from_str(&value).map(Some).map_err(|e| e.to_string()),
+            None => Ok(None),
+        }
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().expect("Metadata store poisoned")
+    }
+
+    fn to_json<T: serde::Serialize>(value: &T) -> Result<String, String> {
+        serde_json::to_string(value).map_err(|e| e.to_string())
+    }
+
+    pub fn get_default_path(state_path: &str) -> String {
+        let binding = Path::new(state_path).join(".sync.db");
+        binding.to_str().unwrap().to_string()
+    }
+}
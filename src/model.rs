@@ -1,5 +1,6 @@
 
 use std::time::SystemTime;
+use std::collections::HashMap;
 
 use serde::Serialize;
 use serde::Deserialize;
@@ -13,7 +14,8 @@ pub struct FileDefinition {
     pub id: Option<String>,
     pub size: Option<u64>,
     pub checksum: Option<String>,
-    pub last_update: Option<SystemTime>
+    pub last_update: Option<SystemTime>,
+    pub revision: Option<u64>
 }
 impl FileDefinition {
     pub fn new(id: String, name: String, path: String) -> Self {
@@ -23,7 +25,8 @@ impl FileDefinition {
             id: Some(id),
             size: Some(0),
             checksum: None,
-            last_update: None
+            last_update: None,
+            revision: None
         }
     }
     pub fn with_checksum(id: String, name: String, path: String, checksum: String) -> Self {
@@ -33,7 +36,8 @@ impl FileDefinition {
             id: Some(id),
             size: Some(0),
             checksum: Some(checksum),
-            last_update: None
+            last_update: None,
+            revision: None
         }
     }
     pub fn validate(&self) -> bool {
@@ -41,17 +45,26 @@ impl FileDefinition {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(crate = "rocket::serde")]
 pub struct FileChange {
     pub file: FileDefinition,
-    pub change: ChangeType
+    pub change: ChangeType,
+    pub conflict_copy: Option<FileDefinition>
 }
 impl FileChange {
     pub fn new(file: FileDefinition, change: ChangeType) -> Self {
         Self {
             file,
-            change
+            change,
+            conflict_copy: None
+        }
+    }
+    pub fn conflict(file: FileDefinition, conflict_copy: FileDefinition) -> Self {
+        Self {
+            file,
+            change: ChangeType::Conflict,
+            conflict_copy: Some(conflict_copy)
         }
     }
 }
@@ -71,6 +84,141 @@ impl ChangePatch {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct UploadSession {
+    pub id: Option<String>,
+    pub file_id: String,
+    pub size: u64,
+    pub checksum: String,
+    pub offset: Option<u64>,
+    #[serde(default)]
+    pub base_revision: Option<u64>      // Of the file when the session opened, set by the server.
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: String
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct FileSignature {
+    pub block_size: u64,
+    pub size: u64,
+    pub checksum: Option<String>,
+    pub blocks: Vec<BlockSignature>
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub enum DeltaInstruction {
+    Copy { block: u64, count: u64 },
+    Literal { data: String }        // Base64 encoded.
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct FileDelta {
+    pub block_size: u64,
+    pub base_checksum: Option<String>,
+    pub target_checksum: Option<String>,
+    pub instructions: Vec<DeltaInstruction>
+}
+
+/// A deleted file kept with its last definition until restored or purged.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TrashedFile {
+    pub file: FileDefinition,
+    pub deleted_at: SystemTime
+}
+
+/// Named point in the repository history whose files can be restored.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Snapshot {
+    pub name: String,
+    pub revision: u64,
+    pub created_at: SystemTime
+}
+
+/// Stored content whose size or checksum differ from its definition.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ContentMismatch {
+    pub file: FileDefinition,
+    pub size: u64,
+    pub checksum: String
+}
+
+/// Outcome of checking the stored content against the repository metadata.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct VerifyReport {
+    pub checked: u64,
+    pub missing: Vec<FileDefinition>,       // Definitions without content.
+    pub mismatched: Vec<ContentMismatch>,
+    pub orphans: Vec<String>,               // Ids of content without a definition.
+    pub repaired: bool
+}
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty() && self.orphans.is_empty()
+    }
+}
+
+/// Bearer token as listed by the admin endpoints. The token itself is only shown once, when minted.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub admin: bool,        // Whether it may manage tokens, users and repositories.
+    #[serde(default)]
+    pub user: Option<String>,   // Whose grants limit it, unrestricted if none.
+    pub created: SystemTime
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct MintedToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub details: ApiToken
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,      // Also allows reading.
+    Admin       // Also allows writing, and managing snapshots.
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct User {
+    pub name: String,
+    #[serde(default)]
+    pub groups: Vec<String>
+}
+
+/// Permission on the files of `repository` (`*` for all) under `path`, given to a user or to every member of a group.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Grant {
+    pub id: Option<String>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub repository: String,
+    #[serde(default)]
+    pub path: String,           // Prefix of `FileDefinition.path`, empty for the whole repository.
+    pub permission: Permission
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct RevisionHistory {
     pub revisions: Vec<FileChange>
@@ -79,23 +227,40 @@ pub struct RevisionHistory {
 #[derive(Serialize, Deserialize)]
 pub struct FileRepositoryState {
     pub current_revision: u64,
-    pub history: RevisionHistory
+    pub history: RevisionHistory,
+    #[serde(default)]
+    pub versions: HashMap<String, Vec<FileDefinition>>,     // Retained prior versions, oldest first.
+    #[serde(default)]
+    pub trash: HashMap<String, TrashedFile>,
+    #[serde(default)]
+    pub snapshots: Vec<Snapshot>,
+    #[serde(default)]
+    pub snapshot_files: HashMap<String, Vec<FileDefinition>>,     // Contents of each snapshot, by name.
+    #[serde(default)]
+    pub quarantined: HashMap<String, u64>       // Revision of each file whose content failed a scrub, not served until replaced.
 }
 impl FileRepositoryState {
     pub fn add_revision(&mut self, change: FileChange) {
         self.current_revision += 1;
         self.history.revisions.push(change);
     }
+    /// Takes back the latest revision, for a change that couldn't be persisted.
+    pub fn remove_last_revision(&mut self) -> Option<FileChange> {
+        let change = self.history.revisions.pop()?;
+        self.current_revision -= 1;
+        Some(change)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(crate = "rocket::serde")]
 pub enum ChangeType {
     Create,
     Update,
     Delete,
     DoDownload,
-    DoUpload
+    DoUpload,
+    Conflict
 }
 
 #[derive(Serialize, Deserialize)]
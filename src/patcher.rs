@@ -6,35 +6,50 @@ use crate::model::ChangeType;
 use crate::model::ChangePatch;
 use crate::model::FileDefinition;
 use crate::repository::FileRepository;
+use crate::io_manager::IOManager;
 
 
 pub struct Patcher;
 impl Patcher {
-    pub fn get_patch(rev: u64, file_list: &Vec<FileDefinition>, repository: &FileRepository) -> Option<ChangePatch> {
+    /// Changes bringing the client from `rev` up to date, limited to the files `readable` accepts
+    /// at their server path. Files it rejects are left out on both sides, as if neither had them. Conflicts on files
+    /// `writable` rejects get no conflict copy, leaving the client to keep its own version.
+    pub async fn get_patch<IO: IOManager>(rev: u64, file_list: &[FileDefinition], client: &str, repository: &mut FileRepository<IO>,
+                readable: impl Fn(&FileDefinition) -> bool, writable: impl Fn(&FileDefinition) -> bool) -> Option<ChangePatch> {
         if rev == 0 {
-            Self::build_initial_patch(repository)
+            Self::build_initial_patch(repository, &readable)
         }
         else {
-            Self::build_patch_for(rev, file_list, repository)
+                // Judged by where the server has the file, not where the client says it is.
+            let file_list: Vec<FileDefinition> = file_list.iter()
+                    .filter(|fd| match fd.id.as_deref().and_then(|id| repository.get_last_definition(id)) {
+                        Some(server_fd) => readable(server_fd),
+                        None => readable(fd),
+                    })
+                    .cloned()
+                    .collect();
+            Self::build_patch_for(rev, &file_list, client, repository, &readable, &writable).await
         }
     }
 
-    fn build_initial_patch(repository: &FileRepository) -> Option<ChangePatch> {
+    fn build_initial_patch<IO: IOManager>(repository: &FileRepository<IO>, readable: &impl Fn(&FileDefinition) -> bool) -> Option<ChangePatch> {
         let revision = repository.get_revision();
         let entries = repository.get_all_entries();
         let changes = entries.iter()
+                .filter(|d| readable(d))
                 .map(|d| FileChange::new((*d).clone(), ChangeType::DoDownload))
                 .collect();
 
         Some(ChangePatch::new(revision, changes))
     }
 
-    fn build_patch_for(rev: u64, client_list: &Vec<FileDefinition>, repository: &FileRepository) -> Option<ChangePatch> {
-        let latest_rev = repository.get_revision();
-        let server_ahead = latest_rev > rev;
+    async fn build_patch_for<IO: IOManager>(rev: u64, client_list: &Vec<FileDefinition>, client: &str, repository: &mut FileRepository<IO>,
+                readable: &impl Fn(&FileDefinition) -> bool, writable: &impl Fn(&FileDefinition) -> bool) -> Option<ChangePatch> {
         let mut res = Vec::new();
+        let mut conflicts = Vec::new();
         let server_list = repository.get_all_entries();
         let mut srv_fds_map: HashMap<String, &FileDefinition> = server_list.iter()
+                .filter(|df| readable(df))
                 .map(|df| (df.id.as_ref().unwrap().clone(), *df))
                 .collect();
 
@@ -42,10 +57,8 @@ impl Patcher {
             let client_fd_id = client_fd.id.as_ref().unwrap();
             if !srv_fds_map.contains_key(client_fd_id) {
                         // Client has file that server doesn't.
-                    // TODO check in history between revs to consider remote deletion, instead of just server_ahead.
-                    // This is very finicky and not a good solution in general.
-                let was_deleted = server_ahead;
-                if was_deleted {
+                    // Only a Delete recorded since the client's revision means it was removed remotely.
+                if repository.was_deleted_since(client_fd_id, rev) {
                     res.push(FileChange::new(client_fd.clone(), ChangeType::Delete));
                 }
                 else {
@@ -58,14 +71,17 @@ impl Patcher {
             let server_fd = *srv_fds_map.get(client_fd_id).unwrap();
             let file_is_same = Self::fuzzy_compare(client_fd, server_fd);
             if !file_is_same {
-                    // TODO do better checking, probably with revisions
-                if server_fd.last_update >= client_fd.last_update {
-                        // Server file is more recent.
-                    res.push(FileChange::new(server_fd.clone(), ChangeType::DoDownload));
-                }
-                else {
-                    res.push(FileChange::new(client_fd.clone(), ChangeType::DoUpload));
-                }
+                let base_rev = client_fd.revision.unwrap_or(rev);
+                let (server_changed, client_changed) = match repository.get_definition_at(client_fd_id, base_rev) {
+                    Some(base_fd) => (server_fd.revision.unwrap_or(0) > base_rev, !Self::fuzzy_compare(client_fd, base_fd)),
+                    None => (true, true),   // No base to tell which side changed, so both versions are kept.
+                };
+                match (server_changed, client_changed) {
+                    (true, true) if writable(server_fd) => conflicts.push((server_fd.clone(), base_rev)),
+                    (true, true) => res.push(FileChange::new(server_fd.clone(), ChangeType::Conflict)),
+                    (false, true) => res.push(FileChange::new(client_fd.clone(), ChangeType::DoUpload)),
+                    _ => res.push(FileChange::new(server_fd.clone(), ChangeType::DoDownload)),
+                };
             }
             else {
                 // noop
@@ -75,12 +91,37 @@ impl Patcher {
 
             // Thanks to removal on match; files that exist in the server and not in the client
         for server_only_fs in srv_fds_map.values() {
-            println!("Server only file: {:#?}", &server_only_fs);
                 // Deleted files should be handled during local-patch, so all thhese should be new files.
             res.push(FileChange::new((*server_only_fs).clone(), ChangeType::DoDownload));
         }
 
-        Some(ChangePatch::new(latest_rev, res))
+            // Both sides diverged: keep the server version and have the client upload its own as a sibling copy.
+            // Should one copy fail, those made for this patch are removed again so a retry starts clean.
+        let mut created = Vec::new();
+        for (server_fd, base_rev) in conflicts {
+            match repository.create_conflict_copy(&server_fd, client, base_rev).await {
+                Ok((copy_fd, is_new)) => {
+                    if is_new {
+                        created.push(copy_fd.id.clone().expect("No id"));
+                    }
+                    res.push(FileChange::conflict(server_fd, copy_fd));
+                },
+                Err(_) => {
+                    for id in created {
+                        let _ = repository.delete(&id).await;
+                    }
+                    return None;
+                }
+            }
+        }
+
+        Some(ChangePatch::new(repository.get_revision(), res))
+    }
+
+    /// Whether `client` can go into the name of a conflict copy: 1 to 64 letters, digits, spaces, '-' or '_'.
+    pub fn is_valid_client(client: &str) -> bool {
+        !client.is_empty() && client.len() <= 64
+                && client.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-' || c == '_')
     }
 
     fn fuzzy_compare(a: &FileDefinition, b: &FileDefinition) -> bool {
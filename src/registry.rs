@@ -0,0 +1,191 @@
+
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use rocket::http::Status;
+use rocket::request::Outcome;
+use rocket::request::Request;
+use rocket::request::FromRequest;
+use rocket::tokio::fs;
+use rocket::tokio::sync::Mutex;
+
+use crate::config::Config;
+use crate::config::Storage;
+use crate::routes::Uploads;
+use crate::routes::Repository;
+use crate::upload::UploadManager;
+use crate::repository::FileRepository;
+
+
+/// All repositories hosted by the server, managed by Rocket and shared with the scrubber.
+pub type Registry = Arc<Mutex<RepositoryRegistry>>;
+
+/// Repository served under `name`, along with its own upload sessions.
+#[derive(Clone)]
+pub struct NamedRepository {
+    pub name: String,
+    pub repository: Repository,
+    pub uploads: Arc<Uploads>
+}
+impl Deref for NamedRepository {
+    type Target = Repository;
+
+    fn deref(&self) -> &Repository {
+        &self.repository
+    }
+}
+
+/// The repository named by the `<repo>` segment of `/repos/<repo>/...` routes, 404 if there is none.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for NamedRepository {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(Ok(name)) = request.param::<&str>(1) else {
+            return Outcome::Error((Status::NotFound, "No repository in path".to_string()));
+        };
+        let Some(registry) = request.rocket().state::<Registry>() else {
+            return Outcome::Error((Status::InternalServerError, "No repository registry".to_string()));
+        };
+        match registry.lock().await.get(name) {
+            Some(repository) => Outcome::Success(repository),
+            None => Outcome::Error((Status::NotFound, format!("Repository {name} not found"))),
+        }
+    }
+}
+
+
+/// Named repositories, each with its own storage root, state and revision counter.
+/// `default` always exists and lives directly under the configured paths; the others
+/// live under `.repos/<name>` there, see `Config::for_repository`.
+pub struct RepositoryRegistry {
+    config: Config,
+    repositories: HashMap<String, NamedRepository>,
+    deleting: HashSet<String>               // Names taken out of service while their content is purged.
+}
+impl RepositoryRegistry {
+    pub const DEFAULT: &'static str = "default";
+
+    /// Opens the default repository and every named one found under the state path.
+    pub fn load(config: Config) -> Result<Self, String> {
+        let mut registry = Self::with_default(FileRepository::load(config.clone())?);
+        for name in Self::find_stored(&config)? {
+            let repository = FileRepository::load(config.for_repository(&name)).map_err(|e| format!("{name}: {e}"))?;
+            registry.insert(&name, repository);
+        }
+        Ok(registry)
+    }
+    /// Registry serving `repository` as its default one, with new repositories set up like it.
+    pub fn with_default(repository: FileRepository) -> Self {
+        let mut registry = Self { config: repository.get_config().clone(), repositories: HashMap::new(), deleting: HashSet::new() };
+        registry.insert(Self::DEFAULT, repository);
+        registry
+    }
+    /// Registry keeping everything in memory only.
+    pub fn in_memory() -> Self {
+        Self::with_default(FileRepository::in_memory())
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn get(&self, name: &str) -> Option<NamedRepository> {
+        self.repositories.get(name).cloned()
+    }
+
+    /// Names of all repositories, sorted.
+    pub fn get_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.repositories.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn create(&mut self, name: &str) -> Result<NamedRepository, String> {
+        if !Self::is_valid_name(name) {
+            return Err("Repository names are 1 to 64 letters, digits, '-' or '_'".to_string());
+        }
+        if self.repositories.contains_key(name) {
+            return Err("Repository already exists".to_string());
+        }
+        if self.deleting.contains(name) {
+            return Err("Repository is being deleted".to_string());
+        }
+        let repository = FileRepository::load(self.config.for_repository(name))?;
+        Ok(self.insert(name, repository))
+    }
+
+    /// Removes the repository along with all of its content and state.
+    /// Returns false if there is no such repository. The registry is only locked to take the repository out
+    /// and to settle the outcome, so other repositories are served while the content is purged.
+    pub async fn delete(registry: &Registry, name: &str) -> Result<bool, String> {
+        if name == Self::DEFAULT {
+            return Err("The default repository can't be deleted".to_string());
+        }
+        let Some(named) = registry.lock().await.take(name) else {
+            return Ok(false);
+        };
+        let mut rep_lock = named.lock().await;
+        if let Err(e) = rep_lock.purge_all().await {
+            drop(rep_lock);
+            let mut registry = registry.lock().await;
+            registry.deleting.remove(name);
+            registry.repositories.insert(name.to_string(), named);
+            return Err(e);
+        }
+        let config = rep_lock.get_config().clone();
+        drop(rep_lock);
+        let mut removed = Ok(true);
+        for dir in [Some(&config.base_path), config.state_path.as_ref()].into_iter().flatten() {
+            if Path::new(dir).exists() {
+                if let Err(e) = fs::remove_dir_all(dir).await {
+                    removed = Err(e.to_string());
+                    break;
+                }
+            }
+        }
+        registry.lock().await.deleting.remove(name);
+        removed
+    }
+    /// Takes the repository out of service, keeping its name reserved until the deletion is settled.
+    fn take(&mut self, name: &str) -> Option<NamedRepository> {
+        let named = self.repositories.remove(name)?;
+        self.deleting.insert(name.to_string());
+        Some(named)
+    }
+
+    fn insert(&mut self, name: &str, repository: FileRepository) -> NamedRepository {
+        let named = NamedRepository {
+            name: name.to_string(),
+            uploads: Arc::new(UploadManager::new(&repository.get_config().base_path)),
+            repository: Arc::new(Mutex::new(repository)),
+        };
+        self.repositories.insert(name.to_string(), named.clone());
+        named
+    }
+
+    fn is_valid_name(name: &str) -> bool {
+        !name.is_empty() && name.len() <= 64
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+
+    /// Named repositories with state under `.repos`, none for the memory backend.
+    fn find_stored(config: &Config) -> Result<Vec<String>, String> {
+        let repos_path = Path::new(config.get_state_path()).join(".repos");
+        if matches!(config.storage, Storage::Memory) || !repos_path.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(repos_path).map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.path().is_dir() && Self::is_valid_name(&name) {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+}
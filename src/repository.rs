@@ -1,133 +1,842 @@
 
+use std::io::SeekFrom;
 use std::path::Path;
+use std::cmp::Reverse;
+use std::time::Duration;
+use std::time::SystemTime;
 use std::collections::HashMap;
+use std::collections::HashSet;
+
+use rocket::tokio::fs;
+use rocket::tokio::fs::File;
+use rocket::tokio::io::AsyncRead;
+use rocket::tokio::io::AsyncReadExt;
+use rocket::tokio::io::AsyncSeekExt;
+use rocket::tokio::io::AsyncWriteExt;
+use xxhash_rust::xxh3::Xxh3;
 
 use crate::util::Util;
+use crate::delta::Delta;
 use crate::config::Config;
-use crate::model::FileData;
+use crate::config::ReadVerification;
+use crate::config::Storage;
+use crate::model::FileDelta;
 use crate::model::FileChange;
 use crate::model::ChangeType;
+use crate::model::Snapshot;
+use crate::model::ChangePatch;
+use crate::model::TrashedFile;
+use crate::model::VerifyReport;
+use crate::model::ContentMismatch;
 use crate::model::FileDefinition;
 use crate::model::RevisionHistory;
 use crate::model::FileRepositoryState;
+use crate::model::DeltaInstruction;
 use crate::io_manager::IOManager;
-use crate::io_manager::FolderIOManager;
+use crate::io_manager::ContentReader;
+use crate::io_manager::StorageIOManager;
+use crate::io_manager::StagedContent;
+use crate::io_manager::StoredContent;
+use crate::metadata::MetadataStore;
+use crate::extras::TouchedExtras;
 
 
-pub struct FileRepository {
+pub struct FileRepository<IO: IOManager = StorageIOManager> {
+    config: Config,
     state: FileRepositoryState,
-    io_manager: FolderIOManager,    // TODO: make it generic
-    contents: HashMap<String, FileDefinition>
+    io_manager: IO,
+    contents: HashMap<String, FileDefinition>,
+    store: Option<MetadataStore>,       // Where state is saved on every change, if anywhere.
+    touched: TouchedExtras              // Extras rows changed since the state was last saved.
 }
 impl FileRepository {
-    pub fn load_default() -> FileRepository {
-        let (state, contents) = match Self::load_state() {
-            Ok(state) => state,
-            Err(_) => {
-                println!("No repository state to load. Creating new empty one...");
-                (
-                    FileRepositoryState { current_revision: 0, history: RevisionHistory {
-                        revisions: Vec::new() }
-                    },
-                    HashMap::new()
-                )
-            },
-        };
-
-        Self {
+    /// Empty repository on the configured backend, with state kept in memory only.
+    pub fn new(config: Config) -> FileRepository {
+        let io_manager = StorageIOManager::from_config(&config);
+        Self::with_io_manager(config, io_manager, None)
+    }
+    /// Repository backed by the metadata store under the state path, migrating the legacy JSON state into it.
+    pub fn load(config: Config) -> Result<FileRepository, String> {
+        if matches!(config.storage, Storage::Memory) {
+                // State describing content that won't survive a restart isn't worth saving.
+            return Ok(Self::new(config));
+        }
+        let store = MetadataStore::open(&config).map_err(|e| format!("Unable to open metadata store: {e}"))?;
+        Self::migrate_legacy_state(&config, &store).map_err(|e| format!("Unable to migrate repository state: {e}"))?;
+        let (state, contents) = store.load().map_err(|e| format!("Unable to load repository state: {e}"))?;
+        Ok(Self {
+            io_manager: StorageIOManager::from_config(&config),
+            config,
             state,
-            io_manager: FolderIOManager { },
             contents,
+            store: Some(store),
+            touched: TouchedExtras::default(),
+        })
+    }
+    /// Empty repository that keeps both content and state in memory only.
+    pub fn in_memory() -> FileRepository {
+        let config = Config { storage: Storage::Memory, ..Config::default() };
+        Self::new(config)
+    }
+}
+impl<IO: IOManager> FileRepository<IO> {
+    pub fn with_io_manager(config: Config, io_manager: IO, store: Option<MetadataStore>) -> Self {
+        Self {
+            config,
+            state: FileRepositoryState { current_revision: 0, history: RevisionHistory {
+                revisions: Vec::new() },
+                versions: HashMap::new(),
+                trash: HashMap::new(),
+                snapshots: Vec::new(),
+                snapshot_files: HashMap::new(),
+                quarantined: HashMap::new()
+            },
+            io_manager,
+            contents: HashMap::new(),
+            store,
+            touched: TouchedExtras::default(),
         }
     }
-    fn add_change(&mut self, change: FileChange) {
+    fn restore_quarantine(&mut self, id: &str, quarantined: Option<u64>) {
+        if let Some(revision) = quarantined {
+            self.state.quarantined.insert(id.to_string(), revision);
+            self.touched.quarantine(id);
+        }
+    }
+    /// Records `change` as the next revision, taking it back again if it can't be persisted.
+    /// Callers undo whatever else they changed when this fails.
+    fn add_change(&mut self, change: FileChange) -> Result<(), String> {
         self.state.add_revision(change);
-        let _ = self.save_state();
+        if let Err(e) = self.save_change() {
+            self.state.remove_last_revision();
+            return Err(format!("Unable to save repository state: {e}"));
+        }
+        Ok(())
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.config
     }
 
     pub fn get_definition(&self, id: &str) -> Option<FileDefinition> {
         self.contents.get(id).cloned()
     }
 
-    pub async fn get_file_data(&self, id: &str) -> Result<FileData, String> {
+    /// Opens the content of the file for reading from `offset`, also returning its total length.
+    pub async fn open_file(&self, id: &str, offset: u64) -> Result<(ContentReader, u64), String> {
         let file_def = match self.get_definition(id) {
             Some(res) => res,
             None => return Err("File not found".to_string()),
         };
-        match self.io_manager.get_file_content(&file_def).await {
-            Ok(content) => {
-                Ok(FileData::new(file_def, content))
-            },
-            Err(e) => Err(e)
+        self.io_manager.open_file_content(&file_def, offset).await
+    }
+
+    /// Opens the content described by `file_def`, which may be the current file or a retained version.
+    pub async fn open_definition(&self, file_def: &FileDefinition, offset: u64) -> Result<(ContentReader, u64), String> {
+        let id = file_def.id.as_deref().ok_or("No id in file definition".to_string())?;
+        let current = self.get_definition(id).ok_or("File not found".to_string())?;
+        match file_def.revision {
+            Some(rev) if current.revision != Some(rev) => self.io_manager.open_version_content(&current, rev, offset).await,
+            _ => self.io_manager.open_file_content(&current, offset).await,
+        }
+    }
+
+    /// Whether the stored content of `file_def`, current or a version, still hashes to its recorded checksum.
+    pub async fn content_matches(&self, file_def: &FileDefinition) -> Result<bool, String> {
+        let Some(expected) = &file_def.checksum else {
+            return Ok(true);
+        };
+        let (mut reader, _) = self.open_definition(file_def, 0).await?;
+        let mut hasher = Xxh3::new();
+        Delta::copy_hashing(&mut reader, &mut rocket::tokio::io::sink(), &mut hasher).await?;
+        Ok(Util::format_checksum(hasher.digest()) == *expected)
+    }
+    /// Whether this read should be checked first, as set by `read_verification`.
+    pub fn should_verify_read(&self) -> bool {
+        match self.config.read_verification {
+            ReadVerification::Always => true,
+            ReadVerification::Sampled => rand::random::<f64>() < self.config.read_sample_rate,
+            ReadVerification::Never => false,
+        }
+    }
+
+    /// Stops serving the file until its content is replaced, also across restarts.
+    pub fn quarantine(&mut self, id: &str) -> Result<(), String> {
+        let Some(file_def) = self.get_definition(id) else {
+            return Ok(());
+        };
+        self.state.quarantined.insert(id.to_string(), file_def.revision.unwrap_or_default());
+        self.touched.quarantine(id);
+        if let Err(e) = self.save_state() {
+            self.state.quarantined.remove(id);
+            return Err(e);
+        }
+        Ok(())
+    }
+    pub fn is_quarantined(&self, id: &str) -> bool {
+        self.state.quarantined.contains_key(id)
+    }
+
+    /// Retained prior versions of the file followed by its current one, oldest first.
+    pub fn get_versions(&self, id: &str) -> Option<Vec<FileDefinition>> {
+        let current = self.get_definition(id)?;
+        let mut versions = self.state.versions.get(id).cloned().unwrap_or_default();
+        versions.push(current);
+        Some(versions)
+    }
+
+    pub fn get_version(&self, id: &str, rev: u64) -> Option<FileDefinition> {
+        self.get_versions(id)?.into_iter()
+                .find(|v| v.revision == Some(rev))
+    }
+
+    /// Keeps the current content of the file as a version before it gets replaced.
+    /// Nothing is pruned yet, the update may still fail; see `expire_versions`.
+    async fn preserve_version(&mut self, current: &FileDefinition) -> Result<(), String> {
+        let rev = current.revision.unwrap_or_default();
+        self.io_manager.store_version(current, rev).await?;
+
+        let id = current.id.clone().expect("No id");
+        self.touched.version(&id);
+        self.state.versions.entry(id).or_default().push(current.clone());
+        Ok(())
+    }
+    /// Takes the oldest versions past the configured retention off the record, to be recorded along with the update.
+    /// Their content is only dropped once that succeeds, otherwise `restore_expired` puts them back.
+    fn expire_versions(&mut self, id: &str) -> Vec<FileDefinition> {
+        let retention = self.config.version_retention;
+        self.touched.version(id);
+        match self.state.versions.get_mut(id) {
+            Some(versions) => versions.drain(..versions.len().saturating_sub(retention)).collect(),
+            None => Vec::new(),
+        }
+    }
+    fn restore_expired(&mut self, id: &str, expired: Vec<FileDefinition>) {
+        if !expired.is_empty() {
+            self.state.versions.entry(id.to_string()).or_default().splice(..0, expired);
+            self.touched.version(id);
+        }
+    }
+    async fn discard_versions(&mut self, file_def: &FileDefinition) {
+        let id = file_def.id.as_deref().expect("No id");
+        self.touched.version(id);
+        for version in self.state.versions.remove(id).unwrap_or_default() {
+            self.drop_version(file_def, version.revision.unwrap_or_default()).await;
+        }
+    }
+    /// Deletes the stored version unless it is still retained or pinned by a snapshot.
+    async fn drop_version(&self, file_def: &FileDefinition, rev: u64) {
+        let id = file_def.id.as_deref().expect("No id");
+        let retained = self.state.versions.get(id)
+                .is_some_and(|versions| versions.iter().any(|v| v.revision == Some(rev)));
+        let pinned = self.state.snapshot_files.values()
+                .any(|files| files.iter().any(|f| f.id.as_deref() == Some(id) && f.revision == Some(rev)));
+        if retained || pinned {
+            return;
+        }
+        if let Err(e) = self.io_manager.delete_version(file_def, rev).await {
+            println!("[Error [drop_version]: {e}");
         }
     }
 
     pub async fn create_empty(&mut self, file_def: &FileDefinition) -> Result<String, String> {
+        self.create_empty_as(file_def, None).await
+    }
+    /// Creates the file under `id`, which must be free, or under a new random id without one.
+    async fn create_empty_as(&mut self, file_def: &FileDefinition, id: Option<String>) -> Result<String, String> {
+        let is_taken = |id: &str| self.exists(id) || self.state.trash.contains_key(id);
         if self.exists_named(file_def) {
             Err("File already exists".to_string())
         }
+        else if id.as_deref().is_some_and(is_taken) {
+            Err("File id already taken".to_string())
+        }
         else {
             let mut file_definition = file_def.clone();
-            let new_id = Util::new_id();
+            let mut new_id = id.unwrap_or_else(Util::new_id);
+            while is_taken(&new_id) {
+                new_id = Util::new_id();
+            }
             file_definition.id = Some(new_id.clone());
             file_definition.size = Some(0);
-            match self.io_manager.create_empty(&file_definition).await {
-                Ok(_) => {
-                    self.contents.insert(new_id.clone(), file_definition.clone());
-                    let change = FileChange::new(file_definition, ChangeType::Create);
-                    self.add_change(change);
-                    Ok(new_id)
-                },
-                Err(e) => Err(e)
+            file_definition.checksum = Some(Util::checksum(&[]));
+            file_definition.revision = Some(self.next_revision());
+            file_definition.last_update = Some(SystemTime::now());
+            self.io_manager.create_empty(&file_definition).await?;
+            self.contents.insert(new_id.clone(), file_definition.clone());
+            let change = FileChange::new(file_definition.clone(), ChangeType::Create);
+            if let Err(e) = self.add_change(change) {
+                self.contents.remove(&new_id);
+                if let Err(e) = self.io_manager.delete_file(&file_definition).await {
+                    println!("[Error [create_empty]: {e}");
+                }
+                return Err(e);
+            }
+            Ok(new_id)
+        }
+    }
+
+    /// Creates an empty sibling of `original` named after the conflicting client,
+    /// for that client to upload its divergent version into. The copy's id derives from the original,
+    /// the client and the revision they diverged from, so a retried patch gets the same copy back.
+    /// Ids of copies since deleted are skipped in a fixed order, never replaced by a random one.
+    /// Returns whether the copy was created by this call.
+    pub async fn create_conflict_copy(&mut self, original: &FileDefinition, client: &str, base_rev: u64) -> Result<(FileDefinition, bool), String> {
+        let original_id = original.id.as_deref().ok_or("No id in file definition".to_string())?;
+        let mut attempt = 0;
+        let copy_id = loop {
+            let copy_id = Util::checksum(format!("{original_id}\0{client}\0{base_rev}\0{attempt}").as_bytes());
+            if let Some(existing) = self.get_definition(&copy_id) {
+                return Ok((existing, false));
+            }
+            if !self.state.trash.contains_key(&copy_id) {
+                break copy_id;
             }
+            attempt += 1;
+        };
+
+        let (stem, ext) = match original.name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{ext}")),
+            _ => (original.name.as_str(), String::new()),
+        };
+        let date = Util::format_date(SystemTime::now());
+        let base_name = format!("{stem} (conflict from {client} {date})");
+
+        let mut copy_def = FileDefinition::new(String::new(), format!("{base_name}{ext}"), original.path.clone());
+        let mut attempt = 1;
+        while self.exists_named(&copy_def) {
+            attempt += 1;
+            copy_def.name = format!("{base_name} {attempt}{ext}");
         }
+
+        let new_id = self.create_empty_as(&copy_def, Some(copy_id)).await?;
+        let copy_def = self.get_definition(&new_id).ok_or("Conflict copy not found".to_string())?;
+        Ok((copy_def, true))
     }
 
-    pub async fn update(&mut self, file_data: &FileData) -> Result<bool, String> {
-        let file_def = &file_data.definition;
+    pub async fn update_stream(&mut self, file_def: &FileDefinition, content: &mut (dyn AsyncRead + Send + Unpin),
+                max_size: u64) -> Result<bool, String> {
+        let current = self.prepare_update(file_def).await?;
+        let stored = self.io_manager.store_file_stream(file_def, content, max_size).await;
+        self.finish_update(file_def, current, stored).await
+    }
+    /// As `update_stream`, with the content already staged, so that only storing it happens under the lock.
+    pub async fn update_staged(&mut self, file_def: &FileDefinition, staged: &StagedContent) -> Result<bool, String> {
+        let current = self.prepare_update(file_def).await?;
+        let stored = self.io_manager.store_staged(file_def, staged).await;
+        self.finish_update(file_def, current, stored).await
+    }
+    /// Keeps the current content as a version ahead of an update, returning its definition.
+    async fn prepare_update(&mut self, file_def: &FileDefinition) -> Result<Option<FileDefinition>, String> {
         if file_def.id.is_none() {
             return Err("No id in file definition".to_string())
         }
-
-        match self.io_manager.store_file_content(file_data).await {
-            Ok(_) => {
+        let current = self.get_definition(file_def.id.as_deref().expect("No id"));
+        if let Some(current) = &current {
+            self.preserve_version(current).await?;
+        }
+        Ok(current)
+    }
+    /// Records the update once the content is `stored`, or undoes `prepare_update` if it couldn't be.
+    async fn finish_update(&mut self, file_def: &FileDefinition, current: Option<FileDefinition>,
+                stored: Result<StoredContent, String>) -> Result<bool, String> {
+        match stored {
+            Ok(stored) => {
                 let mut updated_def = file_def.clone();
-                updated_def.size = Some(file_data.content.len() as u64);
-                updated_def.checksum = Some(Util::checksum(&file_data.content));
+                updated_def.size = Some(stored.size);
+                updated_def.checksum = Some(stored.checksum);
+                updated_def.revision = Some(self.next_revision());
+                updated_def.last_update = Some(SystemTime::now());
                 let change = FileChange::new(updated_def.clone(), ChangeType::Update);
-                self.contents.insert(file_def.id.clone().expect("No id"), updated_def.clone());
-                self.add_change(change);
+                let id = file_def.id.clone().expect("No id");
+                self.contents.insert(id.clone(), updated_def.clone());
+                let expired = self.expire_versions(&id);
+                let quarantined = self.state.quarantined.remove(&id);
+                self.touched.quarantine(&id);
+                if let Err(e) = self.add_change(change) {
+                    self.restore_quarantine(&id, quarantined);
+                    self.restore_expired(&id, expired);
+                    self.revert_update(file_def, current.as_ref()).await;
+                    return Err(e);
+                }
+                for version in expired {
+                    self.drop_version(file_def, version.revision.unwrap_or_default()).await;
+                }
                 Ok(true)
             },
             Err(e) => {
-                Err(e.to_string())
+                    // The content was left as it was, so the version just kept isn't one.
+                if let Some(current) = &current {
+                    self.forget_preserved_version(current).await;
+                }
+                Err(e)
+            }
+        }
+    }
+    /// Puts back the content and definition replaced by an update whose revision couldn't be recorded.
+    async fn revert_update(&mut self, file_def: &FileDefinition, current: Option<&FileDefinition>) {
+        let id = file_def.id.clone().expect("No id");
+        let Some(current) = current else {
+            self.contents.remove(&id);
+            if let Err(e) = self.io_manager.delete_file(file_def).await {
+                println!("[Error [revert_update]: {e}");
+            }
+            return;
+        };
+        self.contents.insert(id, current.clone());
+        let rev = current.revision.unwrap_or_default();
+        let restored = match self.io_manager.open_version_content(current, rev, 0).await {
+            Ok((mut content, _)) => self.io_manager.store_file_stream(current, &mut content, self.config.file_limit()).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        match restored {
+            Ok(_) => self.forget_preserved_version(current).await,
+            Err(e) => println!("[Error [revert_update]: {e}"),
+        }
+    }
+    async fn forget_preserved_version(&mut self, current: &FileDefinition) {
+        let id = current.id.as_deref().expect("No id");
+        if let Some(versions) = self.state.versions.get_mut(id) {
+            versions.pop();
+            self.touched.version(id);
+        }
+        self.drop_version(current, current.revision.unwrap_or_default()).await;
+    }
+
+    /// Replaces the content of the file with that of its version at `rev`, recording an update.
+    pub async fn restore_version(&mut self, id: &str, rev: u64) -> Result<FileDefinition, String> {
+        let version = self.get_version(id, rev).ok_or("Version not found".to_string())?;
+        let current = self.get_definition(id).ok_or("File not found".to_string())?;
+        if current.revision == Some(rev) {
+            return Ok(current);
+        }
+
+            // Copy aside first: preserving the current content may prune the version being restored.
+        let temp_path = Util::temp_path(&self.config.base_path).await?;
+        let res = self.restore_version_from(&current, &version, &temp_path).await;
+        let _ = fs::remove_file(&temp_path).await;
+        res
+    }
+    async fn restore_version_from(&mut self, current: &FileDefinition, version: &FileDefinition, temp_path: &str) -> Result<FileDefinition, String> {
+        let (mut reader, _) = self.open_definition(version, 0).await?;
+        let mut temp = File::create(temp_path).await.map_err(|e| e.to_string())?;
+        Delta::copy_hashing(&mut reader, &mut temp, &mut Xxh3::new()).await?;
+        temp.flush().await.map_err(|e| e.to_string())?;
+
+        let mut content = File::open(temp_path).await.map_err(|e| e.to_string())?;
+        self.update_stream(current, &mut content, self.config.file_limit()).await?;
+        self.get_definition(current.id.as_deref().expect("No id")).ok_or("File not found".to_string())
+    }
+
+    /// Rebuilds the file from its current content and `delta`, recording the result as an update.
+    pub async fn apply_delta(&mut self, id: &str, delta: &FileDelta) -> Result<bool, String> {
+        let file_def = match self.get_definition(id) {
+            Some(res) => res,
+            None => return Err("File not found".to_string()),
+        };
+        if delta.base_checksum.is_some() && delta.base_checksum != file_def.checksum {
+            return Err("Delta base doesn't match current content".to_string());
+        }
+        if !Delta::is_valid_block_size(delta.block_size) {
+            return Err("Invalid block size".to_string());
+        }
+
+        let base_path = Util::temp_path(&self.config.base_path).await?;
+        let temp_path = Util::temp_path(&self.config.base_path).await?;
+        let res = self.apply_delta_from(&file_def, delta, &base_path, &temp_path).await;
+        let _ = fs::remove_file(&base_path).await;
+        let _ = fs::remove_file(&temp_path).await;
+        res
+    }
+    /// Builds the new content in `temp_path` from a local copy of the current one in `base_path`,
+    /// which copies seek into. The result can't grow past the file size limit, however often blocks are repeated.
+    async fn apply_delta_from(&mut self, file_def: &FileDefinition, delta: &FileDelta, base_path: &str, temp_path: &str) -> Result<bool, String> {
+        let (mut reader, _) = self.io_manager.open_file_content(file_def, 0).await?;
+        let mut base = File::create(base_path).await.map_err(|e| e.to_string())?;
+        let len = Delta::copy_hashing(&mut reader, &mut base, &mut Xxh3::new()).await?;
+        base.flush().await.map_err(|e| e.to_string())?;
+        let mut base = File::open(base_path).await.map_err(|e| e.to_string())?;
+
+        let limit = self.config.file_limit();
+        let mut written: u64 = 0;
+        let mut temp = File::create(temp_path).await.map_err(|e| e.to_string())?;
+        let mut hasher = Xxh3::new();
+        for instruction in &delta.instructions {
+            match instruction {
+                DeltaInstruction::Copy { block, count } => {
+                    let copy_len = count.checked_mul(delta.block_size);
+                    let offset = block.checked_mul(delta.block_size);
+                    let (Some(copy_len), Some(offset)) = (copy_len, offset) else {
+                        return Err("Delta copies past end of file".to_string());
+                    };
+                    if offset.checked_add(copy_len).is_none_or(|end| end > len.next_multiple_of(delta.block_size)) {
+                        return Err("Delta copies past end of file".to_string());
+                    }
+                    let copy_len = copy_len.min(len.saturating_sub(offset));
+                    written = Self::check_limit(written, copy_len, limit)?;
+                    base.seek(SeekFrom::Start(offset)).await.map_err(|e| e.to_string())?;
+                    Delta::copy_hashing(&mut (&mut base).take(copy_len), &mut temp, &mut hasher).await?;
+                },
+                DeltaInstruction::Literal { data } => {
+                    let literal = Delta::decode_literal(data)?;
+                    written = Self::check_limit(written, literal.len() as u64, limit)?;
+                    Delta::copy_hashing(&mut literal.as_slice(), &mut temp, &mut hasher).await?;
+                },
+            }
+        }
+        temp.flush().await.map_err(|e| e.to_string())?;
+
+        let checksum = Util::format_checksum(hasher.digest());
+        if delta.target_checksum.as_ref().is_some_and(|target| *target != checksum) {
+            return Err(format!("Checksum mismatch: expected {}, got {}",
+                    delta.target_checksum.as_deref().unwrap_or_default(), checksum));
+        }
+
+        let mut content = File::open(temp_path).await.map_err(|e| e.to_string())?;
+        self.update_stream(file_def, &mut content, limit).await
+    }
+    fn check_limit(written: u64, len: u64, limit: u64) -> Result<u64, String> {
+        written.checked_add(len)
+                .filter(|total| *total <= limit)
+                .ok_or("Delta result exceeds the file size limit".to_string())
+    }
+
+    /// Removes the file from the repository, keeping its content in the trash.
+    /// Returns `None` if there is no such file.
+    pub async fn delete(&mut self, id: &str) -> Result<Option<FileDefinition>, String> {
+        self.purge_expired_trash().await;
+        let Some(file) = self.get_definition(id) else {
+            return Ok(None);
+        };
+        self.io_manager.trash_file(&file).await?;
+
+        self.contents.remove(id);
+        let trashed = TrashedFile { file: file.clone(), deleted_at: SystemTime::now() };
+        self.state.trash.insert(id.to_string(), trashed);
+        self.touched.trash(id);
+        let mut deleted_def = file.clone();
+        deleted_def.revision = Some(self.next_revision());
+        let change = FileChange::new(deleted_def, ChangeType::Delete);
+        let quarantined = self.state.quarantined.remove(id);
+        self.touched.quarantine(id);
+        if let Err(e) = self.add_change(change) {
+            self.restore_quarantine(id, quarantined);
+            self.state.trash.remove(id);
+            self.contents.insert(id.to_string(), file.clone());
+            if let Err(e) = self.io_manager.restore_trashed(&file).await {
+                println!("[Error [delete]: {e}");
+            }
+            return Err(e);
+        }
+        Ok(Some(file))
+    }
+
+    /// Deletes all stored content, live, trashed and retained versions, ahead of the repository itself going away.
+    pub async fn purge_all(&mut self) -> Result<(), String> {
+        self.touched.mark_all(&self.state);
+        self.state.snapshots.clear();
+        self.state.snapshot_files.clear();
+        let live: Vec<FileDefinition> = self.contents.drain().map(|(_, file)| file).collect();
+        for file in live {
+            self.discard_versions(&file).await;
+            self.io_manager.delete_file(&file).await?;
+        }
+        let trashed: Vec<TrashedFile> = self.state.trash.drain().map(|(_, trashed)| trashed).collect();
+        for trashed in trashed {
+            self.discard_versions(&trashed.file).await;
+            self.io_manager.purge_trashed(&trashed.file).await?;
+        }
+        Ok(())
+    }
+
+    /// Deleted files still in the trash, most recently deleted first.
+    pub fn get_trash(&self) -> Vec<TrashedFile> {
+        let mut trash: Vec<TrashedFile> = self.state.trash.values().cloned().collect();
+        trash.sort_by_key(|t| Reverse(t.deleted_at));
+        trash
+    }
+
+    pub fn get_trashed(&self, id: &str) -> Option<&TrashedFile> {
+        self.state.trash.get(id)
+    }
+
+    /// Brings a trashed file back under its old id, recording it as created again.
+    pub async fn restore_trashed(&mut self, id: &str) -> Result<FileDefinition, String> {
+        let trashed = match self.state.trash.get(id) {
+            Some(trashed) => trashed.clone(),
+            None => return Err("File not in trash".to_string()),
+        };
+        if self.exists_named(&trashed.file) {
+            return Err("File already exists".to_string());
+        }
+        self.io_manager.restore_trashed(&trashed.file).await?;
+        self.state.trash.remove(id);
+        self.touched.trash(id);
+
+        let mut file_definition = trashed.file.clone();
+        file_definition.revision = Some(self.next_revision());
+        file_definition.last_update = Some(SystemTime::now());
+        self.contents.insert(id.to_string(), file_definition.clone());
+        let change = FileChange::new(file_definition.clone(), ChangeType::Create);
+        if let Err(e) = self.add_change(change) {
+            self.contents.remove(id);
+            if let Err(e) = self.io_manager.trash_file(&trashed.file).await {
+                println!("[Error [restore_trashed]: {e}");
+            }
+            self.state.trash.insert(id.to_string(), trashed);
+            self.touched.trash(id);
+            return Err(e);
+        }
+        Ok(file_definition)
+    }
+
+    /// Drops trashed files older than the configured retention, along with their versions.
+    pub async fn purge_expired_trash(&mut self) {
+        let retention = Duration::from_secs(self.config.trash_retention);
+        let now = SystemTime::now();
+        let expired: Vec<TrashedFile> = self.state.trash.values()
+                .filter(|t| now.duration_since(t.deleted_at).unwrap_or_default() >= retention)
+                .cloned()
+                .collect();
+        if expired.is_empty() {
+            return;
+        }
+            // Forget the files before purging them, so the state never lists content that is gone.
+        for trashed in &expired {
+            let id = trashed.file.id.as_deref().expect("No id");
+            self.state.trash.remove(id);
+            self.touched.trash(id);
+        }
+        if let Err(e) = self.save_state() {
+            println!("[Error [purge_expired_trash]: {e}");
+            for trashed in expired {
+                let id = trashed.file.id.clone().expect("No id");
+                self.touched.trash(&id);
+                self.state.trash.insert(id, trashed);
             }
+            return;
+        }
+        for trashed in expired {
+            if let Err(e) = self.io_manager.purge_trashed(&trashed.file).await {
+                println!("[Error [purge_expired_trash]: {e}");
+            }
+            self.discard_versions(&trashed.file).await;
+        }
+        if let Err(e) = self.save_state() {
+            println!("[Error [purge_expired_trash]: {e}");
         }
     }
 
-    pub async fn delete(&mut self, id: &str) -> Option<FileDefinition> {
-        let res = self.contents.remove(id);
-        if let Some(file) = &res {
-            match self.io_manager.delete_file(file).await {
-                Ok(_) => {
-                    let change = FileChange::new(file.clone(), ChangeType::Delete);
-                    self.add_change(change);
-                    res
+    pub fn get_snapshots(&self) -> &[Snapshot] {
+        &self.state.snapshots
+    }
+
+    pub fn get_snapshot_files(&self, name: &str) -> Option<&Vec<FileDefinition>> {
+        self.state.snapshot_files.get(name)
+    }
+
+    /// Records the current contents under `name`, keeping a version of every file so it can be restored later.
+    pub async fn create_snapshot(&mut self, name: &str) -> Result<Snapshot, String> {
+        if name.is_empty() || name.contains('/') {
+            return Err("Invalid snapshot name".to_string());
+        }
+        if self.state.snapshot_files.contains_key(name) {
+            return Err("Snapshot already exists".to_string());
+        }
+        let files: Vec<FileDefinition> = self.contents.values().cloned().collect();
+        for file in &files {
+            self.io_manager.store_version(file, file.revision.unwrap_or_default()).await?;
+        }
+
+        let snapshot = Snapshot {
+            name: name.to_string(),
+            revision: self.get_revision(),
+            created_at: SystemTime::now()
+        };
+        self.state.snapshots.push(snapshot.clone());
+        self.state.snapshot_files.insert(name.to_string(), files.clone());
+        self.touched.snapshot(name);
+        if let Err(e) = self.save_state() {
+            self.state.snapshots.pop();
+            self.state.snapshot_files.remove(name);
+            self.touched.snapshot(name);
+            for file in &files {
+                self.drop_version(file, file.revision.unwrap_or_default()).await;
+            }
+            return Err(format!("Unable to save repository state: {e}"));
+        }
+        Ok(snapshot)
+    }
+
+    pub async fn delete_snapshot(&mut self, name: &str) -> Result<(), String> {
+        let files = self.state.snapshot_files.remove(name).ok_or("Snapshot not found".to_string())?;
+        let snapshots = self.state.snapshots.clone();
+        self.state.snapshots.retain(|s| s.name != name);
+        self.touched.snapshot(name);
+        if let Err(e) = self.save_state() {
+            self.state.snapshots = snapshots;
+            self.state.snapshot_files.insert(name.to_string(), files);
+            self.touched.snapshot(name);
+            return Err(format!("Unable to save repository state: {e}"));
+        }
+        for file in &files {
+            self.drop_version(file, file.revision.unwrap_or_default()).await;
+        }
+        Ok(())
+    }
+
+    /// Brings the contents back to the snapshot, recording each difference as a new revision.
+    /// Returns the changes made, for clients to apply.
+    pub async fn restore_snapshot(&mut self, name: &str) -> Result<ChangePatch, String> {
+        let files = self.state.snapshot_files.get(name).cloned().ok_or("Snapshot not found".to_string())?;
+        let start_rev = self.get_revision();
+        let snapshot_ids: HashSet<&str> = files.iter().map(|f| f.id.as_deref().expect("No id")).collect();
+
+            // Deletes go first so re-created files can't clash with files added since.
+        let added: Vec<String> = self.contents.keys()
+                .filter(|id| !snapshot_ids.contains(id.as_str()))
+                .cloned()
+                .collect();
+        for id in added {
+            if self.delete(&id).await?.is_none() {
+                return Err(format!("Unable to delete file {id}"));
+            }
+        }
+
+        for file in &files {
+            let rev = file.revision.unwrap_or_default();
+            let id = file.id.as_deref().expect("No id");
+            match self.get_definition(id) {
+                Some(current) if current.revision == file.revision || current.checksum == file.checksum => {},
+                Some(current) => {
+                    let (mut content, _) = self.io_manager.open_version_content(file, rev, 0).await?;
+                    self.update_stream(&current, &mut content, self.config.file_limit()).await?;
                 },
+                None => self.recreate_from_version(file).await?,
+            }
+        }
+
+        let changes = self.get_changes_since(start_rev).to_vec();
+        Ok(ChangePatch::new(self.get_revision(), changes))
+    }
+    async fn recreate_from_version(&mut self, file: &FileDefinition) -> Result<(), String> {
+        let id = file.id.clone().expect("No id");
+        let (mut content, _) = self.io_manager.open_version_content(file, file.revision.unwrap_or_default(), 0).await?;
+        let stored = self.io_manager.store_file_stream(file, &mut content, self.config.file_limit()).await?;
+
+        let mut file_definition = file.clone();
+        file_definition.size = Some(stored.size);
+        file_definition.checksum = Some(stored.checksum);
+        file_definition.revision = Some(self.next_revision());
+        file_definition.last_update = Some(SystemTime::now());
+        self.contents.insert(id.clone(), file_definition.clone());
+        let trashed = self.state.trash.remove(&id);
+        self.touched.trash(&id);
+        let change = FileChange::new(file_definition, ChangeType::Create);
+        if let Err(e) = self.add_change(change) {
+            self.contents.remove(&id);
+            if let Some(trashed) = trashed {
+                self.touched.trash(&id);
+                self.state.trash.insert(id, trashed);
+            }
+            if let Err(e) = self.io_manager.delete_file(file).await {
+                println!("[Error [recreate_from_version]: {e}");
+            }
+            return Err(e);
+        }
+        if let Some(trashed) = trashed {
+            if let Err(e) = self.io_manager.purge_trashed(&trashed.file).await {
+                println!("[Error [recreate_from_version]: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the content of every file is stored with the size and checksum on record,
+    /// and lists stored content no file refers to. With `repair`, missing files are recorded as deleted
+    /// and mismatched ones as updated to what is actually stored.
+    pub async fn verify(&mut self, repair: bool) -> Result<VerifyReport, String> {
+        let mut report = VerifyReport::default();
+        let mut files: Vec<FileDefinition> = self.contents.values().cloned().collect();
+        files.sort_by(|a, b| a.id.cmp(&b.id));
+        for file in files {
+            report.checked += 1;
+            let Ok((mut reader, _)) = self.io_manager.open_file_content(&file, 0).await else {
+                report.missing.push(file);
+                continue;
+            };
+            let mut hasher = Xxh3::new();
+            let size = match Delta::copy_hashing(&mut reader, &mut rocket::tokio::io::sink(), &mut hasher).await {
+                Ok(size) => size,
                 Err(e) => {
-                    println!("Error: {}", e);
-                    None
+                    println!("[Error [verify]: {e}");
+                    report.missing.push(file);
+                    continue;
                 }
+            };
+            let checksum = Util::format_checksum(hasher.digest());
+            if file.size != Some(size) || file.checksum.as_ref() != Some(&checksum) {
+                report.mismatched.push(ContentMismatch { file, size, checksum });
             }
         }
-        else {
-            None
+
+        report.orphans = self.io_manager.list_files().await?.into_iter()
+                .filter(|id| !self.contents.contains_key(id))
+                .collect();
+        report.orphans.sort();
+
+        if repair && !(report.missing.is_empty() && report.mismatched.is_empty()) {
+            self.repair(&report).await?;
+            report.repaired = true;
         }
+        Ok(report)
+    }
+    /// Brings the metadata in line with the stored content found by `verify`.
+    /// Missing files go with their versions, as on a permanent delete.
+    async fn repair(&mut self, report: &VerifyReport) -> Result<(), String> {
+        for file in &report.missing {
+            let id = file.id.clone().expect("No id");
+            self.contents.remove(&id);
+            let mut deleted_def = file.clone();
+            deleted_def.revision = Some(self.next_revision());
+            let quarantined = self.state.quarantined.remove(&id);
+            self.touched.quarantine(&id);
+            if let Err(e) = self.add_change(FileChange::new(deleted_def, ChangeType::Delete)) {
+                self.restore_quarantine(&id, quarantined);
+                self.contents.insert(id, file.clone());
+                return Err(e);
+            }
+            self.discard_versions(file).await;
+        }
+        for mismatch in &report.mismatched {
+            let id = mismatch.file.id.clone().expect("No id");
+            let mut updated_def = mismatch.file.clone();
+            updated_def.size = Some(mismatch.size);
+            updated_def.checksum = Some(mismatch.checksum.clone());
+            updated_def.revision = Some(self.next_revision());
+            updated_def.last_update = Some(SystemTime::now());
+            self.contents.insert(id.clone(), updated_def.clone());
+            let quarantined = self.state.quarantined.remove(&id);
+            self.touched.quarantine(&id);
+            if let Err(e) = self.add_change(FileChange::new(updated_def, ChangeType::Update)) {
+                self.restore_quarantine(&id, quarantined);
+                self.contents.insert(id, mismatch.file.clone());
+                return Err(e);
+            }
+        }
+        Ok(())
     }
 
     pub fn get_revision(&self) -> u64 {
         self.state.current_revision
     }
+    fn next_revision(&self) -> u64 {
+        self.state.current_revision + 1
+    }
+
+    pub fn exists(&self, id: &str) -> bool {
+        self.contents.contains_key(id)
+    }
 
     pub fn exists_named(&self, file_def: &FileDefinition) -> bool {
         self.contents.values().any(|f| f.name == file_def.name && f.path == file_def.path)
@@ -137,48 +846,79 @@ impl FileRepository {
         self.contents.values().collect()
     }
 
+    /// Changes recorded after `rev`, up to and including the current revision.
+    pub fn get_changes_since(&self, rev: u64) -> &[FileChange] {
+        let revisions = &self.state.history.revisions;
+        let start = (rev as usize).min(revisions.len());
+        &revisions[start..]
+    }
 
-    fn save_state(&self) -> Result<(), std::io::Error> {
-        let state_str = serde_json::to_string(&self.state)
-                    .expect("Repo State serialization error.");
-        std::fs::write(Self::get_save_state_path(), state_str.as_bytes())?;
+    /// Definition of the file as recorded at revision `rev`, if it existed then.
+    pub fn get_definition_at(&self, id: &str, rev: u64) -> Option<&FileDefinition> {
+        let revisions = &self.state.history.revisions;
+        let end = (rev as usize).min(revisions.len());
+        revisions[..end].iter().rev()
+                .find(|c| c.file.id.as_deref() == Some(id))
+                .filter(|c| !matches!(c.change, ChangeType::Delete))
+                .map(|c| &c.file)
+    }
 
-        let content_vec: Vec<&FileDefinition> = self.contents.values().collect();
-        let contents_str = serde_json::to_string(&content_vec)
-                    .expect("Repo Contents serialization error.");
-        std::fs::write(Self::get_save_contents_path(), contents_str.as_bytes())
+    /// Current definition of the file, or the last one recorded if it has since been deleted.
+    pub fn get_last_definition(&self, id: &str) -> Option<&FileDefinition> {
+        self.contents.get(id).or_else(|| self.state.history.revisions.iter().rev()
+                .find(|c| c.file.id.as_deref() == Some(id))
+                .map(|c| &c.file))
     }
-    fn load_state() -> Result<(FileRepositoryState, HashMap<String, FileDefinition>), std::io::Error> {
-        let state_path = Self::get_save_state_path();
-        let stored_state: FileRepositoryState =  match std::fs::read(state_path) {
-            Ok(data) => {
-                serde_json::from_slice(&data).unwrap()
-            },
-            Err(e) => return Err(e)
-        };
 
-        let content_path = Self::get_save_contents_path();
-        let stored_content_vec: Vec<FileDefinition> =  match std::fs::read(content_path) {
-            Ok(data) => {
-                serde_json::from_slice(&data).unwrap()
-            },
-            Err(e) => return Err(e)
-        };
-        let stored_content = stored_content_vec.iter()
-                    .map(|fd| (fd.id.as_ref().unwrap().clone(), fd.clone()))
+    pub fn was_deleted_since(&self, id: &str, rev: u64) -> bool {
+        self.get_changes_since(rev).iter()
+                .any(|c| matches!(c.change, ChangeType::Delete)
+                        && c.file.id.as_deref() == Some(id))
+    }
+
+
+    fn save_change(&mut self) -> Result<(), String> {
+        if let Some(store) = &self.store {
+            store.record_change(&self.state, &self.contents, &self.touched)?;
+        }
+        self.touched = TouchedExtras::default();
+        Ok(())
+    }
+    fn save_state(&mut self) -> Result<(), String> {
+        if let Some(store) = &self.store {
+            store.save_extras(&self.state, &self.contents, &self.touched)?;
+        }
+        self.touched = TouchedExtras::default();
+        Ok(())
+    }
+
+    /// Imports `.sync-state` and `.sync-contents` into an empty store, then sets them aside.
+    fn migrate_legacy_state(config: &Config, store: &MetadataStore) -> Result<(), String> {
+        let state_path = Self::get_legacy_state_path(config);
+        let contents_path = Self::get_legacy_contents_path(config);
+        if !Path::new(&state_path).is_file() || !store.is_empty()? {
+            return Ok(());
+        }
+        println!("Migrating repository state to the metadata store...");
+        let data = std::fs::read(&state_path).map_err(|e| e.to_string())?;
+        let state: FileRepositoryState = serde_json::from_slice(&data).map_err(|e| e.to_string())?;
+        let data = std::fs::read(&contents_path).map_err(|e| e.to_string())?;
+        let contents: Vec<FileDefinition> = serde_json::from_slice(&data).map_err(|e| e.to_string())?;
+        let contents = contents.into_iter()
+                    .map(|fd| (fd.id.clone().expect("No id"), fd))
                     .collect();
 
-        Ok((stored_state, stored_content))
+        store.import(&state, &contents)?;
+        std::fs::rename(&state_path, format!("{state_path}.migrated")).map_err(|e| e.to_string())?;
+        std::fs::rename(&contents_path, format!("{contents_path}.migrated")).map_err(|e| e.to_string())
     }
 
-    fn get_save_state_path() -> String {
-        let base_path = &Config::get_base_path();
-        let binding = Path::new(base_path).join(".sync-state");
+    fn get_legacy_state_path(config: &Config) -> String {
+        let binding = Path::new(&config.base_path).join(".sync-state");
         binding.to_str().unwrap().to_string()
     }
-    fn get_save_contents_path() -> String {
-        let base_path = &Config::get_base_path();
-        let binding = Path::new(base_path).join(".sync-contents");
+    fn get_legacy_contents_path(config: &Config) -> String {
+        let binding = Path::new(&config.base_path).join(".sync-contents");
         binding.to_str().unwrap().to_string()
     }
 }
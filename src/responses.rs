@@ -0,0 +1,246 @@
+
+use std::io;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use std::task::ready;
+
+use rocket::request::Request;
+use rocket::response;
+use rocket::response::Response;
+use rocket::response::Responder;
+use rocket::http::Status;
+use rocket::http::ContentType;
+use rocket::tokio::io::AsyncRead;
+use rocket::tokio::io::AsyncReadExt;
+use rocket::tokio::io::ReadBuf;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::util::Util;
+use crate::delta::Delta;
+use crate::model::FileDefinition;
+use crate::headers::Range;
+use crate::headers::etag_for;
+use crate::headers::RangeSelection;
+use crate::io_manager::IOManager;
+use crate::io_manager::ContentReader;
+use crate::repository::FileRepository;
+
+
+enum FileBody {
+    NotModified,
+    Full(ContentReader, u64),
+    Partial(ContentReader, (u64, u64), u64),
+    Multipart(ContentReader, u64, String),
+    Unsatisfiable(u64)
+}
+
+/// Why content couldn't be served.
+#[derive(Responder)]
+pub enum ReadError {
+    #[response(status = 400)]
+    BadRequest(String),
+    #[response(status = 403)]
+    Forbidden(String),
+    #[response(status = 404)]
+    NotFound(String),
+    /// The stored content no longer matches its checksum, so it isn't served at all.
+    /// Content found not to match while streaming is cut short instead.
+    #[response(status = 500)]
+    Integrity(String),
+}
+
+/// File content streamed from storage along with its validators,
+/// or a bare 304 when the client's copy is current.
+pub struct FileResponse {
+    definition: FileDefinition,
+    body: FileBody,
+    check: Option<ContentReader>        // Whole content still to verify, for ranges; see `verified`.
+}
+impl FileResponse {
+    /// Opens the content with the repository locked. When the read is to be verified, a full body
+    /// is hashed as it streams, while ranges leave a whole-content check for `verified`, once the lock is released.
+    pub async fn open<IO: IOManager>(repository: &FileRepository<IO>, definition: FileDefinition, range: &Range) -> Result<Self, ReadError> {
+        let id = definition.id.as_deref().unwrap_or_default();
+        let current = repository.get_definition(id).is_some_and(|current| current.revision == definition.revision);
+        if current && repository.is_quarantined(id) {
+            return Err(ReadError::Integrity(format!("File {id} is quarantined after failing its integrity check")));
+        }
+        let verify = definition.checksum.is_some() && repository.should_verify_read();
+        let mut response = Self::open_checked(repository, definition, range).await.map_err(ReadError::NotFound)?;
+        if verify {
+            response.body = match response.body {
+                FileBody::Full(reader, len) => FileBody::Full(Box::pin(VerifyingReader::new(reader, &response.definition)), len),
+                FileBody::Unsatisfiable(len) => FileBody::Unsatisfiable(len),
+                body => {
+                    let (reader, _) = repository.open_definition(&response.definition, 0).await.map_err(ReadError::NotFound)?;
+                    response.check = Some(reader);
+                    body
+                },
+            };
+        }
+        Ok(response)
+    }
+    /// Fails if the whole-content check left by `open` finds the content doesn't match its checksum.
+    pub async fn verified(mut self) -> Result<Self, ReadError> {
+        let Some(mut reader) = self.check.take() else {
+            return Ok(self);
+        };
+        let mut hasher = Xxh3::new();
+        Delta::copy_hashing(&mut reader, &mut rocket::tokio::io::sink(), &mut hasher).await.map_err(ReadError::NotFound)?;
+        if self.definition.checksum.as_deref() != Some(Util::format_checksum(hasher.digest()).as_str()) {
+            let id = self.definition.id.as_deref().unwrap_or_default();
+            println!("[Error [FileResponse::verified]: File {id} at revision {} failed its integrity check",
+                    self.definition.revision.unwrap_or_default());
+            return Err(ReadError::Integrity(format!("File {id} failed its integrity check")));
+        }
+        Ok(self)
+    }
+    async fn open_checked<IO: IOManager>(repository: &FileRepository<IO>, definition: FileDefinition, range: &Range) -> Result<Self, String> {
+        let (reader, len) = repository.open_definition(&definition, 0).await?;
+
+        let body = match range.resolve(&definition, len) {
+            RangeSelection::Full => FileBody::Full(reader, len),
+            RangeSelection::Unsatisfiable => FileBody::Unsatisfiable(len),
+            RangeSelection::Partial(ranges) if ranges.len() == 1 => {
+                let (start, end) = ranges[0];
+                let (reader, _) = repository.open_definition(&definition, start).await?;
+                FileBody::Partial(Box::pin(reader.take(end - start + 1)), (start, end), len)
+            },
+            RangeSelection::Partial(ranges) => {
+                let boundary = Util::new_id();
+                let mut body: ContentReader = Box::pin(Cursor::new(Vec::new()));
+                let mut body_len = 0;
+                for (start, end) in ranges {
+                    let part_header = format!("\r\n--{boundary}\r\nContent-Type: application/octet-stream\r\n\
+                            Content-Range: bytes {start}-{end}/{len}\r\n\r\n").into_bytes();
+                    let (reader, _) = repository.open_definition(&definition, start).await?;
+                    body_len += part_header.len() as u64 + end - start + 1;
+                    body = Box::pin(body.chain(Cursor::new(part_header)).chain(reader.take(end - start + 1)));
+                }
+                let closing = format!("\r\n--{boundary}--\r\n").into_bytes();
+                body_len += closing.len() as u64;
+                FileBody::Multipart(Box::pin(body.chain(Cursor::new(closing))), body_len, boundary)
+            },
+        };
+
+        Ok(Self { definition, body, check: None })
+    }
+    pub fn not_modified(definition: FileDefinition) -> Self {
+        Self {
+            definition,
+            body: FileBody::NotModified,
+            check: None
+        }
+    }
+}
+
+/// Passes the content through while hashing it, holding back the latest chunk until the next one
+/// arrives or the whole content is found to match its checksum. On a mismatch the body ends short of
+/// its `Content-Length`, so the client can't take it for complete, and the read fails.
+struct VerifyingReader {
+    inner: ContentReader,
+    hasher: Xxh3,
+    buffer: Vec<u8>,
+    ready: Vec<u8>,             // Passed on from `position`.
+    position: usize,
+    held: Vec<u8>,
+    done: bool,
+    expected: String,
+    id: String,
+    revision: u64
+}
+impl VerifyingReader {
+    const BUFFER_SIZE: usize = 64 * 1024;
+
+    fn new(inner: ContentReader, definition: &FileDefinition) -> Self {
+        Self {
+            inner,
+            hasher: Xxh3::new(),
+            buffer: vec![0u8; Self::BUFFER_SIZE],
+            ready: Vec::new(),
+            position: 0,
+            held: Vec::new(),
+            done: false,
+            expected: definition.checksum.clone().unwrap_or_default(),
+            id: definition.id.clone().unwrap_or_default(),
+            revision: definition.revision.unwrap_or_default()
+        }
+    }
+}
+impl AsyncRead for VerifyingReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.position < this.ready.len() {
+                let len = buf.remaining().min(this.ready.len() - this.position);
+                buf.put_slice(&this.ready[this.position..this.position + len]);
+                this.position += len;
+                return Poll::Ready(Ok(()));
+            }
+            if this.done {
+                return Poll::Ready(Ok(()));
+            }
+            let mut read_buf = ReadBuf::new(&mut this.buffer);
+            ready!(this.inner.as_mut().poll_read(cx, &mut read_buf))?;
+            let read = read_buf.filled().len();
+            if read == 0 {
+                if Util::format_checksum(this.hasher.digest()) != this.expected {
+                    println!("[Error [VerifyingReader]: File {} at revision {} failed its integrity check", this.id, this.revision);
+                    this.done = true;
+                    this.held.clear();
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData,
+                            format!("File {} failed its integrity check", this.id))));
+                }
+                this.ready = std::mem::take(&mut this.held);
+                this.done = true;
+            }
+            else {
+                this.hasher.update(&this.buffer[..read]);
+                this.ready = std::mem::replace(&mut this.held, this.buffer[..read].to_vec());
+            }
+            this.position = 0;
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for FileResponse {
+    fn respond_to(self, _request: &'r Request<'_>) -> response::Result<'static> {
+        let mut builder = Response::build();
+        builder.header(etag_for(&self.definition));
+        builder.raw_header("Accept-Ranges", "bytes");
+        if let Some(last_update) = self.definition.last_update {
+            builder.raw_header("Last-Modified", httpdate::fmt_http_date(last_update));
+        }
+
+        match self.body {
+            FileBody::NotModified => {
+                builder.status(Status::NotModified);
+            },
+            FileBody::Full(reader, len) => {
+                builder.header(ContentType::Binary)
+                        .raw_header("Content-Length", len.to_string())
+                        .streamed_body(reader);
+            },
+            FileBody::Partial(reader, (start, end), len) => {
+                builder.status(Status::PartialContent)
+                        .header(ContentType::Binary)
+                        .raw_header("Content-Range", format!("bytes {start}-{end}/{len}"))
+                        .raw_header("Content-Length", (end - start + 1).to_string())
+                        .streamed_body(reader);
+            },
+            FileBody::Multipart(reader, body_len, boundary) => {
+                builder.status(Status::PartialContent)
+                        .raw_header("Content-Type", format!("multipart/byteranges; boundary={boundary}"))
+                        .raw_header("Content-Length", body_len.to_string())
+                        .streamed_body(reader);
+            },
+            FileBody::Unsatisfiable(len) => {
+                builder.status(Status::RangeNotSatisfiable)
+                        .raw_header("Content-Range", format!("bytes */{len}"));
+            },
+        };
+        builder.ok()
+    }
+}
@@ -1,90 +1,757 @@
 
 
-use std::sync::LazyLock;
+use std::sync::Arc;
+use std::collections::BTreeMap;
+
 use rocket::serde::json::Json;
+use rocket::data::Data;
+use rocket::data::Limits;
+use rocket::data::ToByteUnit;
+use rocket::State;
+use rocket::http::Header;
+use rocket::http::uri::Origin;
+use rocket::fairing::AdHoc;
 use rocket::tokio::sync::Mutex;
 use rocket::response::status::Accepted;
 use rocket::response::status::BadRequest;
 use rocket::response::status::Created;
-use rocket::response::status::NotFound;
 
-use crate::model::FileData;
+use crate::model::FileDelta;
 use crate::model::ChangePatch;
+use crate::model::FileSignature;
+use crate::model::Snapshot;
+use crate::model::TrashedFile;
+use crate::model::UploadSession;
+use crate::model::VerifyReport;
+use crate::model::ApiToken;
+use crate::model::MintedToken;
+use crate::model::User;
+use crate::model::Grant;
+use crate::model::Permission;
 use crate::model::FileDefinition;
+use crate::delta::Delta;
 use crate::patcher::Patcher;
+use crate::headers::IfMatch;
+use crate::headers::Range;
+use crate::headers::IfNoneMatch;
+use crate::headers::IfModifiedSince;
+use crate::headers::etag_for;
+use crate::headers::is_not_modified;
+use crate::responses::ReadError;
+use crate::responses::FileResponse;
+use crate::auth::Admin;
+use crate::auth::Tokens;
+use crate::access::Access;
+use crate::access::Caller;
+use crate::registry::Registry;
+use crate::registry::RepositoryRegistry;
+use crate::registry::NamedRepository;
 use crate::repository::FileRepository;
+use crate::upload::UploadManager;
+use crate::io_manager::StagedContent;
 
 
-static REPOSITORY: LazyLock<Mutex<FileRepository>> = LazyLock::new(|| Mutex::new(FileRepository::load_default()));
+/// One hosted repository, shared by the routes addressing it and the scrubber.
+pub type Repository = Arc<Mutex<FileRepository>>;
+/// Upload sessions, each locked on its own while chunks are appended or committed.
+pub type Uploads = UploadManager;
 
+/// Serves the routes from before there were named repositories, e.g. `/api/v1/file/<id>`,
+/// as their `/api/v1/repos/default/...` counterparts.
+pub fn default_repository_aliases() -> AdHoc {
+    AdHoc::on_request("Default repository aliases", |request, _| Box::pin(async move {
+        let Some(path) = default_repository_path(request.uri().path().as_str()) else {
+            return;
+        };
+        let uri = match request.uri().query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path,
+        };
+        match Origin::parse_owned(uri) {
+            Ok(origin) => request.set_uri(origin),
+            Err(e) => println!("[Error [default_repository_aliases]: {e}"),
+        }
+    }))
+}
+fn default_repository_path(path: &str) -> Option<String> {
+    let rest = path.strip_prefix("/api/v1/")?;
+    let first = rest.split('/').next()?;
+    ["file", "trash", "snapshots", "patch", "upload"].contains(&first)
+            .then(|| format!("/api/v1/repos/{}/{rest}", RepositoryRegistry::DEFAULT))
+}
+
+
+#[get("/repos")]
+pub async fn get_repositories(caller: Caller, registry: &State<Registry>) -> Json<Vec<String>> {
+    let names = registry.lock().await.get_names();
+    Json(names.into_iter().filter(|name| caller.can_see(name)).collect())
+}
+
+#[post("/repos/<name>")]
+pub async fn create_repository(_admin: Admin, name: &str, registry: &State<Registry>) -> Result<Created<String>, BadRequest<String>> {
+    match registry.lock().await.create(name) {
+        Ok(_) => Ok(Created::new(format!("/api/v1/repos/{name}"))),
+        Err(e) => {
+            println!("[Error [create_repository]: {e}");
+            Err(BadRequest(e))
+        }
+    }
+}
 
-#[post("/file", data = "<fd>")]
-pub async fn create_empty(fd: Json<FileDefinition>) -> Result<Created<String>, BadRequest<String>> {
-    match REPOSITORY.lock().await.create_empty(&fd).await {
+#[delete("/repos/<name>")]
+pub async fn delete_repository(_admin: Admin, name: &str, registry: &State<Registry>) -> Result<Accepted<String>, DeleteError> {
+    if name == RepositoryRegistry::DEFAULT {
+        return Err(DeleteError::BadRequest("The default repository can't be deleted".to_string()));
+    }
+    match RepositoryRegistry::delete(registry, name).await {
+        Ok(true) => Ok(Accepted("Deleted".to_string())),
+        Ok(false) => Err(DeleteError::NotFound("Repository not found".to_string())),
+        Err(e) => {
+            println!("[Error [delete_repository]: {e}");
+            Err(DeleteError::Failed(e))
+        }
+    }
+}
+
+
+#[post("/repos/<_repo>/file", data = "<fd>")]
+pub async fn create_empty(_repo: &str, caller: Caller, fd: Json<FileDefinition>, repository: NamedRepository) -> Result<Created<String>, UpdateError> {
+    if !caller.can(&repository.name, &fd.path, Permission::Write) {
+        return Err(UpdateError::Forbidden("No write access to this path".to_string()));
+    }
+    match repository.lock().await.create_empty(&fd).await {
         Ok(res) => Ok(Created::new(res)),
         Err(e) => {
             println!("[Error [create_empty]: {e}");
-            Err(BadRequest(e))
+            Err(UpdateError::BadRequest(e))
         }
     }
 }
 
-#[put("/file/<file_id>", data = "<content>")]
-pub async fn update_file(file_id: &str, content: Vec<u8>) -> Result<Accepted<String>, BadRequest<String>> {
-    let mut rep_lock = REPOSITORY.lock().await;
+#[derive(Responder)]
+#[response(status = 202)]
+pub struct Updated {
+    body: String,
+    etag: Header<'static>,
+}
+
+#[derive(Responder)]
+pub enum UpdateError {
+    #[response(status = 400)]
+    BadRequest(String),
+    #[response(status = 403)]
+    Forbidden(String),
+    #[response(status = 412)]
+    PreconditionFailed(String),
+}
+
+/// The body is received into a scratch file before the repository is locked, so a slow upload
+/// doesn't hold up other requests. Access and `If-Match` are checked again once it is.
+#[put("/repos/<_repo>/file/<file_id>", data = "<content>")]
+pub async fn update_file(_repo: &str, caller: Caller, file_id: &str, if_match: IfMatch, repository: NamedRepository, limits: &Limits,
+            content: Data<'_>) -> Result<Updated, UpdateError> {
+    let base_path = {
+        let rep_lock = repository.lock().await;
+        check_update(&caller, &if_match, &repository, rep_lock.get_definition(file_id))?;
+        rep_lock.get_config().base_path.clone()
+    };
+    let limit = limits.get("file").unwrap_or(Limits::FILE);
+    let mut stream = content.open(limit + 1.bytes());
+    let staged = match StagedContent::write(&base_path, &mut stream, limit.as_u64()).await {
+        Ok(staged) => staged,
+        Err(e) => {
+            println!("[Error [update_file]: {e}");
+            return Err(UpdateError::BadRequest(e));
+        }
+    };
+
+    let mut rep_lock = repository.lock().await;
+    let updated = match check_update(&caller, &if_match, &repository, rep_lock.get_definition(file_id)) {
+        Ok(file_def) => rep_lock.update_staged(&file_def, &staged).await.map_err(|e| {
+            println!("[Error [update_file]: {e}");
+            UpdateError::BadRequest(e)
+        }),
+        Err(e) => Err(e),
+    };
+    staged.remove().await;
+    let res = updated?;
+    let updated_def = rep_lock.get_definition(file_id).expect("Updated file not found");
+    Ok(Updated { body: res.to_string(), etag: etag_for(&updated_def) })
+}
+/// The file to update, if it exists, the caller may write it and it matches `If-Match`.
+fn check_update(caller: &Caller, if_match: &IfMatch, repository: &NamedRepository, file_def: Option<FileDefinition>) -> Result<FileDefinition, UpdateError> {
+    let Some(file_def) = file_def else {
+        return Err(UpdateError::BadRequest("File id doesn't exist".to_string()));
+    };
+    if !caller.can(&repository.name, &file_def.path, Permission::Write) {
+        return Err(UpdateError::Forbidden("No write access to this file".to_string()));
+    }
+    if !if_match.matches(&file_def) {
+        return Err(UpdateError::PreconditionFailed("File has changed since expected version".to_string()));
+    }
+    Ok(file_def)
+}
+
+#[get("/repos/<_repo>/file/<file_id>")]
+pub async fn get_file(_repo: &str, caller: Caller, file_id:  &str, if_none_match: IfNoneMatch, if_modified_since: IfModifiedSince,
+            range: Range, repository: NamedRepository) -> Result<FileResponse, ReadError> {
+    let rep_lock = repository.lock().await;
+    let file_def = match rep_lock.get_definition(file_id) {
+        Some(file_def) => file_def,
+        None => return Err(ReadError::NotFound("File not found".to_string())),
+    };
+    if !caller.can_read(&repository.name, &file_def) {
+        return Err(ReadError::Forbidden("No read access to this file".to_string()));
+    }
+    if is_not_modified(&file_def, &if_none_match, &if_modified_since) {
+        return Ok(FileResponse::not_modified(file_def));
+    }
+    let response = FileResponse::open(&rep_lock, file_def, &range).await?;
+    drop(rep_lock);
+    response.verified().await
+}
+
+#[get("/repos/<_repo>/file/<file_id>/versions")]
+pub async fn get_versions(_repo: &str, caller: Caller, file_id: &str, repository: NamedRepository) -> Result<Json<Vec<FileDefinition>>, ReadError> {
+    match repository.lock().await.get_versions(file_id) {
+        Some(versions) if !versions.iter().all(|v| caller.can_read(&repository.name, v)) =>
+            Err(ReadError::Forbidden("No read access to this file".to_string())),
+        Some(versions) => Ok(Json(versions)),
+        None => Err(ReadError::NotFound("File not found".to_string())),
+    }
+}
+
+#[get("/repos/<_repo>/file/<file_id>/versions/<rev>")]
+pub async fn get_version(_repo: &str, caller: Caller, file_id: &str, rev: u64, range: Range, repository: NamedRepository) -> Result<FileResponse, ReadError> {
+    let rep_lock = repository.lock().await;
+    let version_def = match rep_lock.get_version(file_id, rev) {
+        Some(version_def) => version_def,
+        None => return Err(ReadError::NotFound("Version not found".to_string())),
+    };
+    if !caller.can_read(&repository.name, &version_def) {
+        return Err(ReadError::Forbidden("No read access to this file".to_string()));
+    }
+    let response = FileResponse::open(&rep_lock, version_def, &range).await?;
+    drop(rep_lock);
+    response.verified().await
+}
+
+#[post("/repos/<_repo>/file/<file_id>/restore/<rev>")]
+pub async fn restore_version(_repo: &str, caller: Caller, file_id: &str, rev: u64, if_match: IfMatch, repository: NamedRepository) -> Result<Updated, UpdateError> {
+    let mut rep_lock = repository.lock().await;
     match rep_lock.get_definition(file_id) {
-        Some(file_def) => {
-            let file_data = FileData::new(file_def, content);
-            match rep_lock.update(&file_data).await {
-                Ok(res) => Ok(Accepted(res.to_string())),
-                Err(e) => {
-                    println!("[Error [create_empty]: {e}");
-                    Err(BadRequest(e))
-                },
-            }
+        Some(file_def) if !caller.can(&repository.name, &file_def.path, Permission::Write) => {
+            return Err(UpdateError::Forbidden("No write access to this file".to_string()));
+        },
+        Some(file_def) if !if_match.matches(&file_def) => {
+            return Err(UpdateError::PreconditionFailed("File has changed since expected version".to_string()));
         },
-        None => Err(BadRequest("File id doesn't exist".to_string())),
+        Some(_) => {},
+        None => return Err(UpdateError::BadRequest("File id doesn't exist".to_string())),
+    };
+    match rep_lock.restore_version(file_id, rev).await {
+        Ok(restored_def) => Ok(Updated { body: true.to_string(), etag: etag_for(&restored_def) }),
+        Err(e) => {
+            println!("[Error [restore_version]: {e}");
+            Err(UpdateError::BadRequest(e))
+        },
+    }
+}
+
+#[get("/repos/<_repo>/file/<file_id>/signature?<block_size>")]
+pub async fn get_signature(_repo: &str, caller: Caller, file_id: &str, block_size: Option<u64>, repository: NamedRepository) -> Result<Json<FileSignature>, ReadError> {
+    let rep_lock = repository.lock().await;
+    if rep_lock.get_definition(file_id).is_some_and(|file_def| !caller.can_read(&repository.name, &file_def)) {
+        return Err(ReadError::Forbidden("No read access to this file".to_string()));
+    }
+    let (mut content, _) = match rep_lock.open_file(file_id, 0).await {
+        Ok(res) => res,
+        Err(e) => return Err(ReadError::NotFound(e)),
+    };
+    match Delta::signature(&mut content, Delta::clamp_block_size(block_size)).await {
+        Ok(signature) => Ok(Json(signature)),
+        Err(e) => Err(ReadError::NotFound(e)),
     }
 }
 
-#[get("/file/<file_id>")]
-pub async fn get_file(file_id:  &str) -> Result<Vec<u8>, NotFound<String>> {
-    match REPOSITORY.lock().await.get_file_data(file_id).await {
+#[put("/repos/<_repo>/file/<file_id>/delta", data = "<delta>")]
+pub async fn update_from_delta(_repo: &str, caller: Caller, file_id: &str, delta: Json<FileDelta>, repository: NamedRepository) -> Result<Updated, UpdateError> {
+    let mut rep_lock = repository.lock().await;
+    let file_def = match rep_lock.get_definition(file_id) {
+        Some(file_def) => file_def,
+        None => return Err(UpdateError::BadRequest("File id doesn't exist".to_string())),
+    };
+    if !caller.can(&repository.name, &file_def.path, Permission::Write) {
+        return Err(UpdateError::Forbidden("No write access to this file".to_string()));
+    }
+    if delta.base_checksum.is_some() && delta.base_checksum != file_def.checksum {
+        return Err(UpdateError::PreconditionFailed("File has changed since delta base".to_string()));
+    }
+    match rep_lock.apply_delta(file_id, &delta).await {
         Ok(res) => {
-            Ok(res.content)
+            let updated_def = rep_lock.get_definition(file_id).expect("Updated file not found");
+            Ok(Updated { body: res.to_string(), etag: etag_for(&updated_def) })
+        },
+        Err(e) => {
+            println!("[Error [update_from_delta]: {e}");
+            Err(UpdateError::BadRequest(e))
         },
-        Err(e) => Err(NotFound(e)),
     }
 }
 
-#[delete("/file/<file_id>")]
-pub async fn delete_file(file_id: &str) -> Result<Accepted<String>, NotFound<String>> {
-    match REPOSITORY.lock().await.delete(file_id).await {
-        Some(_res) => Ok(Accepted("Deleted".to_string())),
-        None => Err(NotFound("File not found".to_string())),
+#[post("/repos/<_repo>/file/<file_id>/delta", data = "<signature>")]
+pub async fn get_delta(_repo: &str, caller: Caller, file_id: &str, signature: Json<FileSignature>, repository: NamedRepository) -> Result<Json<FileDelta>, ReadError> {
+    let rep_lock = repository.lock().await;
+    if rep_lock.get_definition(file_id).is_some_and(|file_def| !caller.can_read(&repository.name, &file_def)) {
+        return Err(ReadError::Forbidden("No read access to this file".to_string()));
+    }
+    let (mut content, _) = match rep_lock.open_file(file_id, 0).await {
+        Ok(res) => res,
+        Err(e) => return Err(ReadError::NotFound(e)),
+    };
+    match Delta::diff(&signature, &mut content).await {
+        Ok(delta) => Ok(Json(delta)),
+        Err(e) => Err(ReadError::BadRequest(e)),
+    }
+}
+
+#[derive(Responder)]
+pub enum DeleteError {
+    #[response(status = 400)]
+    BadRequest(String),
+    #[response(status = 403)]
+    Forbidden(String),
+    #[response(status = 404)]
+    NotFound(String),
+    #[response(status = 500)]
+    Failed(String),
+}
+
+#[delete("/repos/<_repo>/file/<file_id>")]
+pub async fn delete_file(_repo: &str, caller: Caller, file_id: &str, repository: NamedRepository) -> Result<Accepted<String>, DeleteError> {
+    let mut rep_lock = repository.lock().await;
+    if rep_lock.get_definition(file_id).is_some_and(|file_def| !caller.can(&repository.name, &file_def.path, Permission::Write)) {
+        return Err(DeleteError::Forbidden("No write access to this file".to_string()));
+    }
+    match rep_lock.delete(file_id).await {
+        Ok(Some(_res)) => Ok(Accepted("Deleted".to_string())),
+        Ok(None) => Err(DeleteError::NotFound("File not found".to_string())),
+        Err(e) => {
+            println!("[Error [delete_file]: {e}");
+            Err(DeleteError::Failed(e))
+        }
+    }
+}
+
+#[get("/repos/<_repo>/trash")]
+pub async fn get_trash(_repo: &str, caller: Caller, repository: NamedRepository) -> Json<Vec<TrashedFile>> {
+    Json(repository.lock().await.get_trash().into_iter()
+            .filter(|trashed| caller.can_read(&repository.name, &trashed.file))
+            .collect())
+}
+
+#[post("/repos/<_repo>/trash/<file_id>/restore")]
+pub async fn restore_trashed(_repo: &str, caller: Caller, file_id: &str, repository: NamedRepository) -> Result<Json<FileDefinition>, UpdateError> {
+    let mut rep_lock = repository.lock().await;
+    if rep_lock.get_trashed(file_id).is_some_and(|trashed| !caller.can(&repository.name, &trashed.file.path, Permission::Write)) {
+        return Err(UpdateError::Forbidden("No write access to this file".to_string()));
+    }
+    match rep_lock.restore_trashed(file_id).await {
+        Ok(file_def) => Ok(Json(file_def)),
+        Err(e) => {
+            println!("[Error [restore_trashed]: {e}");
+            Err(UpdateError::BadRequest(e))
+        }
+    }
+}
+
+
+#[post("/repos/<_repo>/snapshots?<name>")]
+pub async fn create_snapshot(_repo: &str, caller: Caller, name: &str, repository: NamedRepository) -> Result<Created<Json<Snapshot>>, UpdateError> {
+    if !caller.can(&repository.name, "", Permission::Admin) {
+        return Err(UpdateError::Forbidden("Snapshots need admin access to the repository".to_string()));
+    }
+    match repository.lock().await.create_snapshot(name).await {
+        Ok(snapshot) => {
+            let location = format!("/api/v1/repos/{}/snapshots/{}/files", repository.name, snapshot.name);
+            Ok(Created::new(location).body(Json(snapshot)))
+        },
+        Err(e) => {
+            println!("[Error [create_snapshot]: {e}");
+            Err(UpdateError::BadRequest(e))
+        }
+    }
+}
+
+#[get("/repos/<_repo>/snapshots")]
+pub async fn get_snapshots(_repo: &str, caller: Caller, repository: NamedRepository) -> Result<Json<Vec<Snapshot>>, ReadError> {
+    if !caller.can_see(&repository.name) {
+        return Err(ReadError::Forbidden("No access to this repository".to_string()));
+    }
+    Ok(Json(repository.lock().await.get_snapshots().to_vec()))
+}
+
+#[get("/repos/<_repo>/snapshots/<name>/files")]
+pub async fn get_snapshot_files(_repo: &str, caller: Caller, name: &str, repository: NamedRepository) -> Result<Json<Vec<FileDefinition>>, ReadError> {
+    if !caller.can_see(&repository.name) {
+        return Err(ReadError::Forbidden("No access to this repository".to_string()));
+    }
+    match repository.lock().await.get_snapshot_files(name) {
+        Some(files) => Ok(Json(files.iter()
+                .filter(|file_def| caller.can_read(&repository.name, file_def))
+                .cloned()
+                .collect())),
+        None => Err(ReadError::NotFound("Snapshot not found".to_string())),
+    }
+}
+
+#[post("/repos/<_repo>/snapshots/<name>/restore")]
+pub async fn restore_snapshot(_repo: &str, caller: Caller, name: &str, repository: NamedRepository) -> Result<Json<ChangePatch>, UpdateError> {
+    if !caller.can(&repository.name, "", Permission::Admin) {
+        return Err(UpdateError::Forbidden("Snapshots need admin access to the repository".to_string()));
+    }
+    match repository.lock().await.restore_snapshot(name).await {
+        Ok(patch) => Ok(Json(patch)),
+        Err(e) => {
+            println!("[Error [restore_snapshot]: {e}");
+            Err(UpdateError::BadRequest(e))
+        }
+    }
+}
+
+#[delete("/repos/<_repo>/snapshots/<name>")]
+pub async fn delete_snapshot(_repo: &str, caller: Caller, name: &str, repository: NamedRepository) -> Result<Accepted<String>, DeleteError> {
+    if !caller.can(&repository.name, "", Permission::Admin) {
+        return Err(DeleteError::Forbidden("Snapshots need admin access to the repository".to_string()));
+    }
+    match repository.lock().await.delete_snapshot(name).await {
+        Ok(_) => Ok(Accepted("Deleted".to_string())),
+        Err(e) => Err(DeleteError::NotFound(e)),
     }
 }
 
 
-#[post("/patch/<rev>", data = "<file_list>")]
-pub async fn get_patch(rev: u64, file_list: Json<Vec<FileDefinition>>) -> Result<Json<ChangePatch>, BadRequest<String>> {
+#[post("/repos/<_repo>/patch/<rev>?<client>", data = "<file_list>")]
+pub async fn get_patch(_repo: &str, caller: Caller, rev: u64, client: Option<&str>, file_list: Json<Vec<FileDefinition>>,
+            repository: NamedRepository) -> Result<Json<ChangePatch>, BadRequest<String>> {
+    let client = client.unwrap_or("unknown client");
+    if !Patcher::is_valid_client(client) {
+        return Err(BadRequest("Client names are 1 to 64 letters, digits, spaces, '-' or '_'".to_string()));
+    }
+    let readable = |file_def: &FileDefinition| caller.can_read(&repository.name, file_def);
+    let writable = |file_def: &FileDefinition| caller.can(&repository.name, &file_def.path, Permission::Write);
     if rev == 0 {
-        if file_list.len() > 0 {
+        if !file_list.is_empty() {
             Err(BadRequest("File list should be empty for initial patch!".to_string()))
         }
         else {
-            let repo = &REPOSITORY.lock().await;
-            match Patcher::get_patch(0, &file_list, repo) {
+            let repo = &mut repository.lock().await;
+            match Patcher::get_patch(0, &file_list, client, repo, readable, writable).await {
                 Some(patch) => Ok(Json::from(patch)),
                 None => Err(BadRequest("Initial patch creation failed!".to_string())),
             }
         }
     }
     else {
-        let repo = &REPOSITORY.lock().await;
-            match Patcher::get_patch(rev, &file_list, repo) {
+        let repo = &mut repository.lock().await;
+            match Patcher::get_patch(rev, &file_list, client, repo, readable, writable).await {
                 Some(patch) => Ok(Json::from(patch)),
                 None => Err(BadRequest("Update patch creation failed!".to_string())),
             }
     }
 }
+
+
+#[derive(Responder)]
+pub enum UploadError {
+    #[response(status = 400)]
+    BadRequest(String),
+    #[response(status = 403)]
+    Forbidden(String),
+    #[response(status = 404)]
+    NotFound(String),
+    #[response(status = 409)]
+    Conflict(Json<UploadSession>),
+    #[response(status = 412)]
+    PreconditionFailed(String),
+    #[response(status = 413)]
+    TooLarge(String),
+}
+
+#[post("/repos/<_repo>/upload", data = "<request>")]
+pub async fn open_upload(_repo: &str, caller: Caller, request: Json<UploadSession>, repository: NamedRepository, limits: &Limits) -> Result<Created<Json<UploadSession>>, UploadError> {
+    if request.size > limits.get("file").unwrap_or(Limits::FILE).as_u64() {
+        return Err(UploadError::TooLarge("Upload exceeds the file size limit".to_string()));
+    }
+    let mut request = request.into_inner();
+    match repository.lock().await.get_definition(&request.file_id) {
+        Some(file_def) if !caller.can(&repository.name, &file_def.path, Permission::Write) =>
+            return Err(UploadError::Forbidden("No write access to this file".to_string())),
+        Some(file_def) => request.base_revision = file_def.revision,
+        None => return Err(UploadError::NotFound("File id doesn't exist".to_string())),
+    }
+    match repository.uploads.open(&request).await {
+        Ok(session) => {
+            let location = format!("/api/v1/repos/{}/upload/{}", repository.name, session.id.as_deref().unwrap_or_default());
+            Ok(Created::new(location).body(Json(session)))
+        },
+        Err(e) => {
+            println!("[Error [open_upload]: {e}");
+            Err(UploadError::BadRequest(e))
+        }
+    }
+}
+
+/// Checks the caller may write the file the session uploads into, as for opening it.
+async fn check_upload_access(caller: &Caller, session: &UploadSession, repository: &NamedRepository) -> Result<(), UploadError> {
+    match repository.lock().await.get_definition(&session.file_id) {
+        Some(file_def) if !caller.can(&repository.name, &file_def.path, Permission::Write) =>
+            Err(UploadError::Forbidden("No write access to this file".to_string())),
+        Some(_) => Ok(()),
+        None => Err(UploadError::NotFound("File id doesn't exist".to_string())),
+    }
+}
+
+#[get("/repos/<_repo>/upload/<session_id>")]
+pub async fn get_upload(_repo: &str, caller: Caller, session_id: &str, repository: NamedRepository) -> Result<Json<UploadSession>, UploadError> {
+    let session = match repository.uploads.get(session_id).await {
+        Ok(session) => session,
+        Err(e) => return Err(UploadError::NotFound(e)),
+    };
+    check_upload_access(&caller, &session, &repository).await?;
+    Ok(Json(session))
+}
+
+#[put("/repos/<_repo>/upload/<session_id>/<offset>", data = "<chunk>")]
+pub async fn append_upload(_repo: &str, caller: Caller, session_id: &str, offset: u64, repository: NamedRepository, limits: &Limits,
+            chunk: Data<'_>) -> Result<Json<UploadSession>, UploadError> {
+    let uploads = &repository.uploads;
+    let _session_lock = uploads.lock_session(session_id).await;
+    let session = match uploads.get(session_id).await {
+        Ok(session) => session,
+        Err(e) => return Err(UploadError::NotFound(e)),
+    };
+    check_upload_access(&caller, &session, &repository).await?;
+    if session.offset != Some(offset) {
+        return Err(UploadError::Conflict(Json(session)));     // Client resumes from the returned offset.
+    }
+
+    let limit = limits.get("file").unwrap_or(Limits::FILE);
+    let mut stream = chunk.open(limit);
+    match uploads.append(&session, &mut stream).await {
+        Ok(session) => Ok(Json(session)),
+        Err(e) => {
+            println!("[Error [append_upload]: {e}");
+            Err(UploadError::BadRequest(e))
+        }
+    }
+}
+
+#[post("/repos/<_repo>/upload/<session_id>/commit")]
+pub async fn commit_upload(_repo: &str, caller: Caller, session_id: &str, repository: NamedRepository) -> Result<Updated, UploadError> {
+    let uploads = &repository.uploads;
+    let _session_lock = uploads.lock_session(session_id).await;
+    let session = match uploads.get(session_id).await {
+        Ok(session) => session,
+        Err(e) => return Err(UploadError::NotFound(e)),
+    };
+    if let Err(e) = uploads.verify(&session).await {
+        return Err(UploadError::BadRequest(e));
+    }
+
+    let mut rep_lock = repository.lock().await;
+    let file_def = match rep_lock.get_definition(&session.file_id) {
+        Some(file_def) => file_def,
+        None => return Err(UploadError::NotFound("File id doesn't exist".to_string())),
+    };
+    if !caller.can(&repository.name, &file_def.path, Permission::Write) {
+        return Err(UploadError::Forbidden("No write access to this file".to_string()));
+    }
+        // Sessions opened before base revisions were recorded have none to check.
+    if session.base_revision.is_some() && session.base_revision != file_def.revision {
+        return Err(UploadError::PreconditionFailed("File has changed since the upload started".to_string()));
+    }
+    let mut content = match uploads.open_content(&session).await {
+        Ok(content) => content,
+        Err(e) => return Err(UploadError::BadRequest(e)),
+    };
+    match rep_lock.update_stream(&file_def, &mut content, session.size).await {
+        Ok(res) => {
+            if let Err(e) = uploads.remove(session_id).await {
+                println!("[Error [commit_upload]: {e}");
+            }
+            let updated_def = rep_lock.get_definition(&session.file_id).expect("Updated file not found");
+            Ok(Updated { body: res.to_string(), etag: etag_for(&updated_def) })
+        },
+        Err(e) => {
+            println!("[Error [commit_upload]: {e}");
+            Err(UploadError::BadRequest(e))
+        }
+    }
+}
+
+#[delete("/repos/<_repo>/upload/<session_id>")]
+pub async fn abort_upload(_repo: &str, caller: Caller, session_id: &str, repository: NamedRepository) -> Result<Accepted<String>, UploadError> {
+    let uploads = &repository.uploads;
+    let _session_lock = uploads.lock_session(session_id).await;
+    let session = match uploads.get(session_id).await {
+        Ok(session) => session,
+        Err(e) => return Err(UploadError::NotFound(e)),
+    };
+    check_upload_access(&caller, &session, &repository).await?;
+    match uploads.remove(session_id).await {
+        Ok(_) => Ok(Accepted("Aborted".to_string())),
+        Err(e) => Err(UploadError::NotFound(e)),
+    }
+}
+
+/// Checks every repository as `verify` does, reporting by repository name.
+#[post("/verify?<repair>")]
+pub async fn verify_all(_admin: Admin, repair: Option<bool>, registry: &State<Registry>) -> Result<Json<BTreeMap<String, VerifyReport>>, BadRequest<String>> {
+    let repositories: Vec<NamedRepository> = {
+        let registry = registry.lock().await;
+        registry.get_names().iter().filter_map(|name| registry.get(name)).collect()
+    };
+    let mut reports = BTreeMap::new();
+    for named in repositories {
+        match named.lock().await.verify(repair.unwrap_or(false)).await {
+            Ok(report) => reports.insert(named.name.clone(), report),
+            Err(e) => {
+                println!("[Error [verify_all]: {}: {e}", named.name);
+                return Err(BadRequest(format!("{}: {e}", named.name)));
+            }
+        };
+    }
+    Ok(Json(reports))
+}
+
+/// Checks stored content against the metadata, and with `repair` fixes the metadata to match.
+#[post("/repos/<_repo>/verify?<repair>")]
+pub async fn verify(_repo: &str, _admin: Admin, repair: Option<bool>, repository: NamedRepository) -> Result<Json<VerifyReport>, BadRequest<String>> {
+    match repository.lock().await.verify(repair.unwrap_or(false)).await {
+        Ok(report) => Ok(Json(report)),
+        Err(e) => {
+            println!("[Error [verify]: {e}");
+            Err(BadRequest(e))
+        }
+    }
+}
+
+
+#[get("/tokens")]
+pub async fn get_tokens(_admin: Admin, tokens: &State<Tokens>) -> Json<Vec<ApiToken>> {
+    Json(tokens.lock().await.get_tokens())
+}
+
+/// Mints a token, returning its secret this once. With `user` it only gets that user's grants.
+#[post("/tokens?<name>&<admin>&<user>")]
+pub async fn mint_token(_admin: Admin, name: &str, admin: Option<bool>, user: Option<&str>, tokens: &State<Tokens>,
+            access: &State<Access>) -> Result<Created<Json<MintedToken>>, BadRequest<String>> {
+    let access = access.lock().await;
+    let user = match user.map(|user| access.get_user(user).ok_or(format!("User {user} not found"))).transpose() {
+        Ok(user) => user,
+        Err(e) => return Err(BadRequest(e)),
+    };
+    match tokens.lock().await.mint(name, admin.unwrap_or(false), user) {
+        Ok(minted) => {
+            let location = format!("/admin/tokens/{}", minted.details.id);
+            Ok(Created::new(location).body(Json(minted)))
+        },
+        Err(e) => {
+            println!("[Error [mint_token]: {e}");
+            Err(BadRequest(e))
+        }
+    }
+}
+
+#[delete("/tokens/<id>")]
+pub async fn revoke_token(_admin: Admin, id: &str, tokens: &State<Tokens>) -> Result<Accepted<String>, DeleteError> {
+    match tokens.lock().await.revoke(id) {
+        Ok(true) => Ok(Accepted("Revoked".to_string())),
+        Ok(false) => Err(DeleteError::NotFound("Token not found".to_string())),
+        Err(e) => {
+            println!("[Error [revoke_token]: {e}");
+            Err(DeleteError::Failed(e))
+        }
+    }
+}
+
+
+#[get("/users")]
+pub async fn get_users(_admin: Admin, access: &State<Access>) -> Json<Vec<User>> {
+    Json(access.lock().await.get_users().to_vec())
+}
+
+/// Creates the user, or replaces its groups.
+#[put("/users/<name>", data = "<user>")]
+pub async fn put_user(_admin: Admin, name: &str, user: Json<User>, access: &State<Access>) -> Result<Accepted<Json<User>>, BadRequest<String>> {
+    let mut user = user.into_inner();
+    user.name = name.to_string();
+    match access.lock().await.put_user(user.clone()) {
+        Ok(_) => Ok(Accepted(Json(user))),
+        Err(e) => {
+            println!("[Error [put_user]: {e}");
+            Err(BadRequest(e))
+        }
+    }
+}
+
+#[delete("/users/<name>")]
+pub async fn delete_user(_admin: Admin, name: &str, access: &State<Access>) -> Result<Accepted<String>, DeleteError> {
+    match access.lock().await.delete_user(name) {
+        Ok(true) => Ok(Accepted("Deleted".to_string())),
+        Ok(false) => Err(DeleteError::NotFound("User not found".to_string())),
+        Err(e) => {
+            println!("[Error [delete_user]: {e}");
+            Err(DeleteError::Failed(e))
+        }
+    }
+}
+
+#[get("/grants")]
+pub async fn get_grants(_admin: Admin, access: &State<Access>) -> Json<Vec<Grant>> {
+    Json(access.lock().await.get_grants().to_vec())
+}
+
+#[post("/grants", data = "<grant>")]
+pub async fn add_grant(_admin: Admin, grant: Json<Grant>, access: &State<Access>) -> Result<Created<Json<Grant>>, BadRequest<String>> {
+    match access.lock().await.add_grant(&grant) {
+        Ok(grant) => {
+            let location = format!("/admin/grants/{}", grant.id.as_deref().unwrap_or_default());
+            Ok(Created::new(location).body(Json(grant)))
+        },
+        Err(e) => {
+            println!("[Error [add_grant]: {e}");
+            Err(BadRequest(e))
+        }
+    }
+}
+
+#[delete("/grants/<id>")]
+pub async fn revoke_grant(_admin: Admin, id: &str, access: &State<Access>) -> Result<Accepted<String>, DeleteError> {
+    match access.lock().await.revoke_grant(id) {
+        Ok(true) => Ok(Accepted("Revoked".to_string())),
+        Ok(false) => Err(DeleteError::NotFound("Grant not found".to_string())),
+        Err(e) => {
+            println!("[Error [revoke_grant]: {e}");
+            Err(DeleteError::Failed(e))
+        }
+    }
+}
+
+
+/// Challenge sent along with a 401, for requests without a current bearer token.
+#[derive(Responder)]
+#[response(status = 401)]
+pub struct Unauthorized {
+    body: String,
+    challenge: Header<'static>,
+}
+
+#[catch(401)]
+pub fn unauthorized() -> Unauthorized {
+    Unauthorized {
+        body: "Missing or invalid bearer token".to_string(),
+        challenge: Header::new("WWW-Authenticate", "Bearer"),
+    }
+}
@@ -0,0 +1,409 @@
+
+use std::io;
+use std::io::Cursor;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use hmac::Hmac;
+use hmac::Mac;
+use sha2::Digest;
+use sha2::Sha256;
+use reqwest::Client;
+use reqwest::Method;
+use reqwest::Response;
+use reqwest::StatusCode;
+use reqwest::RequestBuilder;
+use rocket::futures::TryStreamExt;
+use rocket::tokio::io::AsyncRead;
+use rocket::tokio::io::AsyncReadExt;
+use tokio_util::io::StreamReader;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::util::Util;
+use crate::config::S3Settings;
+use crate::model::FileDefinition;
+use crate::io_manager::IOManager;
+use crate::io_manager::ContentReader;
+use crate::io_manager::StoredContent;
+
+
+/// Stores content as objects in an S3-compatible bucket, under `<prefix>files/<id>`,
+/// `<prefix>versions/<id>/<rev>` and `<prefix>trash/<id>`.
+/// Content larger than one part is sent with a multipart upload.
+pub struct S3IOManager {
+    client: Client,
+    settings: S3Settings
+}
+impl S3IOManager {
+    pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+    const MAX_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+    const UNSIGNED_PAYLOAD: &'static str = "UNSIGNED-PAYLOAD";
+
+    pub fn new(settings: S3Settings) -> Self {
+        Self {
+            client: Client::new(),
+            settings
+        }
+    }
+
+    /// Request for `key`, signed with AWS Signature Version 4. `amz_headers` are sent and signed along.
+    fn request(&self, method: Method, key: &str, query: &[(&str, String)], amz_headers: &[(&str, String)]) -> RequestBuilder {
+        let amz_date = Self::format_amz_date(SystemTime::now());
+        let date = &amz_date[..8];
+        let endpoint = self.settings.endpoint.trim_end_matches('/');
+        let path = format!("/{}/{}", self.settings.bucket, Self::uri_encode(key, false));
+
+        let mut query: Vec<(String, String)> = query.iter()
+                .map(|(k, v)| (Self::uri_encode(k, true), Self::uri_encode(v, true)))
+                .collect();
+        query.sort();
+        let query = query.iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>().join("&");
+
+        let host = match reqwest::Url::parse(endpoint) {
+            Ok(url) => match url.port() {
+                Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+                None => url.host_str().unwrap_or_default().to_string(),
+            },
+            Err(_) => endpoint.to_string(),
+        };
+        let mut headers: Vec<(String, String)> = vec![
+            ("host".to_string(), host),
+            ("x-amz-content-sha256".to_string(), Self::UNSIGNED_PAYLOAD.to_string()),
+            ("x-amz-date".to_string(), amz_date.clone()),
+        ];
+        headers.extend(amz_headers.iter().map(|(k, v)| (k.to_lowercase(), v.trim().to_string())));
+        headers.sort();
+        let canonical_headers: String = headers.iter().map(|(k, v)| format!("{k}:{v}\n")).collect();
+        let signed_headers = headers.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>().join(";");
+
+        let canonical_request = format!("{method}\n{path}\n{query}\n{canonical_headers}\n{signed_headers}\n{}",
+                Self::UNSIGNED_PAYLOAD);
+        let scope = format!("{date}/{}/s3/aws4_request", self.settings.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+                hex::encode(Sha256::digest(canonical_request.as_bytes())));
+
+        let mut key = Self::hmac(format!("AWS4{}", self.settings.secret_key).as_bytes(), date.as_bytes());
+        for part in [self.settings.region.as_str(), "s3", "aws4_request"] {
+            key = Self::hmac(&key, part.as_bytes());
+        }
+        let signature = hex::encode(Self::hmac(&key, string_to_sign.as_bytes()));
+        let authorization = format!("AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                self.settings.access_key);
+
+        let url = if query.is_empty() {
+            format!("{endpoint}{path}")
+        }
+        else {
+            format!("{endpoint}{path}?{query}")
+        };
+        let mut builder = self.client.request(method, url)
+                .header("Authorization", authorization);
+        for (name, value) in headers.into_iter().filter(|(k, _)| k != "host") {
+            builder = builder.header(name, value);
+        }
+        builder
+    }
+
+    async fn send(builder: RequestBuilder) -> Result<Response, String> {
+        let response = builder.send().await.map_err(|e| e.to_string())?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err("File not found.".to_string());
+        }
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("Storage request failed ({status}): {body}"));
+        }
+        Ok(response)
+    }
+
+    async fn object_size(&self, key: &str) -> Result<u64, String> {
+        let response = Self::send(self.request(Method::HEAD, key, &[], &[])).await?;
+        response.headers().get("Content-Length")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+                .ok_or("Missing object size".to_string())
+    }
+
+    async fn open_object(&self, key: &str, offset: u64) -> Result<(ContentReader, u64), String> {
+        let len = self.object_size(key).await?;
+        if offset >= len {
+            return Ok((Box::pin(Cursor::new(Vec::new())), len));
+        }
+        let request = self.request(Method::GET, key, &[], &[])
+                .header("Range", format!("bytes={offset}-"));
+        let response = Self::send(request).await?;
+        let stream = response.bytes_stream().map_err(io::Error::other);
+        Ok((Box::pin(StreamReader::new(stream)), len))
+    }
+
+    async fn put_object(&self, key: &str, data: Vec<u8>) -> Result<(), String> {
+        Self::send(self.request(Method::PUT, key, &[], &[]).body(data)).await.map(|_| ())
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), String> {
+        Self::send(self.request(Method::DELETE, key, &[], &[])).await.map(|_| ())
+    }
+
+    /// Server-side copy, in parts when the object is too large for a single copy.
+    async fn copy_object(&self, from: &str, to: &str) -> Result<(), String> {
+        let source = format!("/{}/{}", self.settings.bucket, Self::uri_encode(from, false));
+        let size = self.object_size(from).await?;
+        if size <= Self::MAX_COPY_SIZE {
+            let response = Self::send(self.request(Method::PUT, to, &[], &[("x-amz-copy-source", source)])).await?;
+                // A copy can fail after the 200 has been sent, leaving the error in the body.
+            let body = response.text().await.map_err(|e| e.to_string())?;
+            if body.contains("<Error>") {
+                return Err(format!("Storage copy failed: {body}"));
+            }
+            return Ok(());
+        }
+
+        let upload_id = self.create_multipart(to).await?;
+        let part_size = self.part_size().max(size.div_ceil(10_000));
+        let mut etags = Vec::new();
+        let mut start = 0;
+        while start < size {
+            let end = (start + part_size).min(size) - 1;
+            let query = [("partNumber", (etags.len() + 1).to_string()), ("uploadId", upload_id.clone())];
+            let headers = [("x-amz-copy-source", source.clone()), ("x-amz-copy-source-range", format!("bytes={start}-{end}"))];
+            match Self::send(self.request(Method::PUT, to, &query, &headers)).await {
+                Ok(response) => {
+                    let body = response.text().await.map_err(|e| e.to_string())?;
+                    etags.push(Self::xml_value(&body, "ETag").unwrap_or_default());
+                },
+                Err(e) => {
+                    self.abort_multipart(to, &upload_id).await;
+                    return Err(e);
+                },
+            }
+            start = end + 1;
+        }
+        self.complete_multipart(to, &upload_id, &etags).await
+    }
+
+    async fn move_object(&self, from: &str, to: &str) -> Result<(), String> {
+        self.copy_object(from, to).await?;
+        self.delete_object(from).await
+    }
+
+    async fn create_multipart(&self, key: &str) -> Result<String, String> {
+        let response = Self::send(self.request(Method::POST, key, &[("uploads", String::new())], &[])).await?;
+        let body = response.text().await.map_err(|e| e.to_string())?;
+        Self::xml_value(&body, "UploadId").ok_or("Missing upload id".to_string())
+    }
+
+    async fn upload_part(&self, key: &str, upload_id: &str, number: usize, data: Vec<u8>) -> Result<String, String> {
+        let query = [("partNumber", number.to_string()), ("uploadId", upload_id.to_string())];
+        let response = Self::send(self.request(Method::PUT, key, &query, &[]).body(data)).await?;
+        response.headers().get("ETag")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+                .ok_or("Missing part ETag".to_string())
+    }
+
+    async fn complete_multipart(&self, key: &str, upload_id: &str, etags: &[String]) -> Result<(), String> {
+        let parts: String = etags.iter().enumerate()
+                .map(|(i, etag)| format!("<Part><PartNumber>{}</PartNumber><ETag>{etag}</ETag></Part>", i + 1))
+                .collect();
+        let body = format!("<CompleteMultipartUpload>{parts}</CompleteMultipartUpload>");
+        let query = [("uploadId", upload_id.to_string())];
+        let response = match Self::send(self.request(Method::POST, key, &query, &[]).body(body)).await {
+            Ok(response) => response,
+            Err(e) => {
+                self.abort_multipart(key, upload_id).await;
+                return Err(e);
+            },
+        };
+        let body = response.text().await.map_err(|e| e.to_string())?;
+        if body.contains("<Error>") {
+            self.abort_multipart(key, upload_id).await;
+            return Err(format!("Storage upload failed: {body}"));
+        }
+        Ok(())
+    }
+
+    async fn abort_multipart(&self, key: &str, upload_id: &str) {
+        let query = [("uploadId", upload_id.to_string())];
+        if let Err(e) = Self::send(self.request(Method::DELETE, key, &query, &[])).await {
+            println!("[Error [abort_multipart]: {e}");
+        }
+    }
+
+    async fn read_part(content: &mut (dyn AsyncRead + Send + Unpin), part_size: u64) -> Result<Vec<u8>, String> {
+        let mut part = Vec::new();
+        content.take(part_size).read_to_end(&mut part).await.map_err(|e| e.to_string())?;
+        Ok(part)
+    }
+
+    fn part_size(&self) -> u64 {
+        self.settings.part_size.max(Self::MIN_PART_SIZE)
+    }
+
+    fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// `YYYYMMDD'T'HHMMSS'Z'`, as expected in `x-amz-date`.
+    fn format_amz_date(time: SystemTime) -> String {
+        let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % 86_400;
+        format!("{}T{:02}{:02}{:02}Z", Util::format_date(time).replace('-', ""), secs / 3600, secs / 60 % 60, secs % 60)
+    }
+
+    fn uri_encode(value: &str, encode_slash: bool) -> String {
+        value.bytes().map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            b'/' if !encode_slash => "/".to_string(),
+            _ => format!("%{b:02X}"),
+        }).collect()
+    }
+
+    fn xml_value(xml: &str, tag: &str) -> Option<String> {
+        let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+        let end = start + xml[start..].find(&format!("</{tag}>"))?;
+        Some(xml[start..end].replace("&quot;", "\""))
+    }
+    fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+        let close = format!("</{tag}>");
+        xml.split(&format!("<{tag}>")).skip(1)
+                .filter_map(|rest| rest.split_once(&close))
+                .map(|(value, _)| value.replace("&quot;", "\"").replace("&amp;", "&"))
+                .collect()
+    }
+
+    fn get_id(file_def: &FileDefinition) -> &str {
+        file_def.id.as_ref().expect("No id in File Definition")
+    }
+    fn get_file_key(&self, file_def: &FileDefinition) -> String {
+        format!("{}files/{}", self.settings.prefix, Self::get_id(file_def))
+    }
+    fn get_version_key(&self, file_def: &FileDefinition, revision: u64) -> String {
+        format!("{}versions/{}/{revision}", self.settings.prefix, Self::get_id(file_def))
+    }
+    fn get_trash_key(&self, file_def: &FileDefinition) -> String {
+        format!("{}trash/{}", self.settings.prefix, Self::get_id(file_def))
+    }
+}
+
+impl IOManager for S3IOManager {
+    async fn open_file_content(&self, file: &FileDefinition, offset: u64) -> Result<(ContentReader, u64), String> {
+        self.open_object(&self.get_file_key(file), offset).await
+    }
+
+    async fn store_file_stream(&self, file_def: &FileDefinition, content: &mut (dyn AsyncRead + Send + Unpin),
+                max_size: u64) -> Result<StoredContent, String> {
+        let key = self.get_file_key(file_def);
+        let part_size = self.part_size();
+        let mut hasher = Xxh3::new();
+
+            // One part and nothing after it: a plain PUT is enough.
+        let first = Self::read_part(content, part_size).await?;
+        let mut size = first.len() as u64;
+        let next = if size < part_size {
+            Vec::new()
+        }
+        else {
+            Self::read_part(content, part_size).await?
+        };
+        if size + next.len() as u64 > max_size {
+            return Err("Content exceeds size limit.".to_string());
+        }
+        hasher.update(&first);
+        if next.is_empty() {
+            self.put_object(&key, first).await?;
+            return Ok(StoredContent { size, checksum: Util::format_checksum(hasher.digest()) });
+        }
+
+        let upload_id = self.create_multipart(&key).await?;
+        let mut etags = Vec::new();
+        let mut part = first;
+        let mut next = next;
+        loop {
+            match self.upload_part(&key, &upload_id, etags.len() + 1, part).await {
+                Ok(etag) => etags.push(etag),
+                Err(e) => {
+                    self.abort_multipart(&key, &upload_id).await;
+                    return Err(e);
+                },
+            }
+            if next.is_empty() {
+                break;
+            }
+            size += next.len() as u64;
+            if size > max_size {
+                self.abort_multipart(&key, &upload_id).await;
+                return Err("Content exceeds size limit.".to_string());
+            }
+            hasher.update(&next);
+            part = next;
+            next = match Self::read_part(content, part_size).await {
+                Ok(next) => next,
+                Err(e) => {
+                    self.abort_multipart(&key, &upload_id).await;
+                    return Err(e);
+                },
+            };
+        }
+        self.complete_multipart(&key, &upload_id, &etags).await?;
+
+        Ok(StoredContent { size, checksum: Util::format_checksum(hasher.digest()) })
+    }
+
+    async fn create_empty(&self, file_def: &FileDefinition) -> Result<bool, String> {
+        self.put_object(&self.get_file_key(file_def), Vec::new()).await?;
+        Ok(true)
+    }
+
+    async fn delete_file(&self, file_def: &FileDefinition) -> Result<bool, String> {
+        self.delete_object(&self.get_file_key(file_def)).await?;
+        Ok(true)
+    }
+
+    async fn store_version(&self, file: &FileDefinition, revision: u64) -> Result<(), String> {
+        self.copy_object(&self.get_file_key(file), &self.get_version_key(file, revision)).await
+    }
+
+    async fn open_version_content(&self, file: &FileDefinition, revision: u64, offset: u64) -> Result<(ContentReader, u64), String> {
+        self.open_object(&self.get_version_key(file, revision), offset).await
+                .map_err(|_| "Version not found.".to_string())
+    }
+
+    async fn delete_version(&self, file: &FileDefinition, revision: u64) -> Result<(), String> {
+        self.delete_object(&self.get_version_key(file, revision)).await
+    }
+
+    async fn trash_file(&self, file: &FileDefinition) -> Result<(), String> {
+        self.move_object(&self.get_file_key(file), &self.get_trash_key(file)).await
+    }
+
+    async fn restore_trashed(&self, file: &FileDefinition) -> Result<(), String> {
+        self.move_object(&self.get_trash_key(file), &self.get_file_key(file)).await
+    }
+
+    async fn purge_trashed(&self, file: &FileDefinition) -> Result<(), String> {
+        self.delete_object(&self.get_trash_key(file)).await
+    }
+
+    async fn list_files(&self) -> Result<Vec<String>, String> {
+        let prefix = format!("{}files/", self.settings.prefix);
+        let mut ids = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2".to_string()), ("prefix", prefix.clone())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.clone()));
+            }
+            let response = Self::send(self.request(Method::GET, "", &query, &[])).await?;
+            let body = response.text().await.map_err(|e| e.to_string())?;
+            ids.extend(Self::xml_values(&body, "Key").into_iter()
+                    .filter_map(|key| key.strip_prefix(&prefix).map(|id| id.to_string())));
+            token = Self::xml_value(&body, "NextContinuationToken");
+            if Self::xml_value(&body, "IsTruncated").as_deref() != Some("true") || token.is_none() {
+                break;
+            }
+        }
+        Ok(ids)
+    }
+}
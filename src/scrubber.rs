@@ -0,0 +1,70 @@
+
+use std::time::Duration;
+
+use rocket::tokio;
+use rocket::tokio::sync::Mutex;
+
+use crate::config::ScrubAction;
+use crate::registry::Registry;
+use crate::io_manager::IOManager;
+use crate::repository::FileRepository;
+
+
+/// Background task re-hashing the content of every repository each `scrub_interval` seconds, to catch bit rot
+/// in files nobody reads. Mismatches are logged, and with `scrub_action = "quarantine"`
+/// the file isn't served anymore until it is replaced. Trashed files past their retention are purged on the same pass.
+pub struct Scrubber;
+impl Scrubber {
+    pub async fn run(registry: &Registry) {
+        let (interval, quarantine) = {
+            let config = registry.lock().await.get_config().clone();
+            (config.scrub_interval, matches!(config.scrub_action, ScrubAction::Quarantine))
+        };
+        if interval == 0 {
+            return;
+        }
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            let names = registry.lock().await.get_names();
+            for name in names {
+                    // Deleted since the names were taken.
+                let Some(named) = registry.lock().await.get(&name) else {
+                    continue;
+                };
+                let failed = Self::scrub(&named.repository, quarantine).await;
+                println!("Scrub of {name} finished, {} file(s) failed their integrity check.", failed.len());
+                named.lock().await.purge_expired_trash().await;
+            }
+        }
+    }
+
+    /// Checks every file once, locking the repository for one file at a time.
+    /// Returns the ids of the files that failed.
+    pub async fn scrub<IO: IOManager>(repository: &Mutex<FileRepository<IO>>, quarantine: bool) -> Vec<String> {
+        let ids: Vec<String> = repository.lock().await.get_all_entries().iter()
+                .filter_map(|f| f.id.clone())
+                .collect();
+        let mut failed = Vec::new();
+        for id in ids {
+            let mut rep_lock = repository.lock().await;
+            let Some(file_def) = rep_lock.get_definition(&id) else {
+                continue;
+            };
+            match rep_lock.content_matches(&file_def).await {
+                Ok(true) => {},
+                Ok(false) => {
+                    println!("[Error [scrub]: File {id} at revision {} failed its integrity check",
+                            file_def.revision.unwrap_or_default());
+                    if quarantine {
+                        if let Err(e) = rep_lock.quarantine(&id) {
+                            println!("[Error [scrub]: Unable to quarantine file {id}: {e}");
+                        }
+                    }
+                    failed.push(id);
+                },
+                Err(e) => println!("[Error [scrub]: File {id}: {e}"),
+            }
+        }
+        failed
+    }
+}
@@ -1,24 +1,52 @@
 
+/// Directory under `tmp` that only one test uses, removed with everything in it once dropped.
+#[cfg(test)]
+struct TempDir {
+    path: String
+}
+#[cfg(test)]
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = format!("tmp/{name}-{}", crate::util::Util::new_id());
+        std::fs::create_dir_all(&path).expect("Unable to create temp dir");
+        Self { path }
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
 #[cfg(test)]
 mod util_tests {
+    use std::time::Duration;
+    use std::time::UNIX_EPOCH;
     use rocket::tokio::fs;
     use crate::model::FileDefinition;
     use crate::util::Util;
+    use super::TempDir;
 
     #[rocket::async_test]
     async fn test_validate_path() {
+        let dir = TempDir::new("test_validate_path");
         let path = "test_dir";
-        let result = Util::validate_path(path).await;
+        let result = Util::validate_path(dir.path(), path).await;
         assert!(result);
     }
 
     #[rocket::async_test]
     async fn test_validate_file() {
-        let path = "test_file.txt";
-        fs::File::create(path).await.expect("Unable to create test file");
-        let result = Util::validate_file(path);
+        let dir = TempDir::new("test_validate_file");
+        let path = format!("{}/test_file.txt", dir.path());
+        fs::File::create(&path).await.expect("Unable to create test file");
+        let result = Util::validate_file(&path);
         assert!(result);
-        fs::remove_file(path).await.expect("Unable to remove test file");
     }
 
     #[test]
@@ -29,8 +57,10 @@ mod util_tests {
             path: "test_dir".to_string(),
             checksum: None,
             size: Some(0),
+            last_update: None,
+            revision: None,
         };
-        let full_path = Util::full_path(&file_def);
+        let full_path = Util::full_path("tmp", &file_def);
         assert!(full_path.contains("test_id"));
     }
 
@@ -46,7 +76,14 @@ mod util_tests {
     fn test_checksum() {
         let content = b"test content".to_vec();
         let checksum = Util::checksum(&content);
-        assert_eq!(checksum, "9473fdd0d880a43c21b7778d34872157");
+        assert_eq!(checksum, "122566cfb6aea24f");
+    }
+
+    #[test]
+    fn test_format_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_709_251_200);    // 2024-03-01
+        assert_eq!(Util::format_date(time), "2024-03-01");
+        assert_eq!(Util::format_date(UNIX_EPOCH), "1970-01-01");
     }
 
     #[test]
@@ -58,53 +95,111 @@ mod util_tests {
 
 #[cfg(test)]
 mod io_manager_tests {
+    use rocket::tokio::io::AsyncReadExt;
+    use crate::util::Util;
     use crate::model::FileData;
     use crate::model::FileDefinition;
     use crate::io_manager::IOManager;
     use crate::io_manager::FolderIOManager;
+    use super::TempDir;
 
     #[rocket::async_test]
     async fn test_create_empty_file() {
+        let dir = TempDir::new("test_create_empty_file");
         let file_def = FileDefinition {
             id: Some("test_id".to_string()),
             name: "test_file.txt".to_string(),
             path: "test_dir".to_string(),
             checksum: None,
             size: Some(0),
+            last_update: None,
+            revision: None,
         };
-        let io_manager = FolderIOManager;
+        let io_manager = FolderIOManager::new(dir.path());
         let result = io_manager.create_empty(&file_def).await;
         assert!(result.is_ok());
     }
 
     #[rocket::async_test]
     async fn test_store_file_content() {
+        let dir = TempDir::new("test_store_file_content");
         let file_def = FileDefinition {
             id: Some("test_id".to_string()),
             name: "test_file.txt".to_string(),
             path: "test_dir".to_string(),
             checksum: None,
             size: Some(0),
+            last_update: None,
+            revision: None,
         };
         let file_data = FileData {
             definition: file_def.clone(),
             content: b"test content".to_vec(),
         };
-        let io_manager = FolderIOManager;
-        let result = io_manager.store_file_content(&file_data).await;
-        assert!(result.is_ok());
+        let io_manager = FolderIOManager::new(dir.path());
+        let result = io_manager.store_file_stream(&file_data.definition, &mut file_data.content.as_slice(), u64::MAX).await;
+        let stored = result.expect("Unable to store file");
+        assert_eq!(stored.size, file_data.content.len() as u64);
+        assert_eq!(stored.checksum, Util::checksum(&file_data.content));
+    }
+
+    #[rocket::async_test]
+    async fn test_store_file_stream_over_limit() {
+        let dir = TempDir::new("test_store_file_stream_over_limit");
+        let file_def = FileDefinition::new("test_limit_id".to_string(), "test_file.txt".to_string(), "test_dir".to_string());
+        let io_manager = FolderIOManager::new(dir.path());
+        let result = io_manager.store_file_stream(&file_def, &mut b"test content".as_slice(), 4).await;
+        assert!(result.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_failed_store_keeps_content() {
+        let dir = TempDir::new("test_failed_store_keeps_content");
+        let file_def = FileDefinition::new("test_atomic_id".to_string(), "test_file.txt".to_string(), "test_dir".to_string());
+        let io_manager = FolderIOManager::new(dir.path());
+        io_manager.store_file_stream(&file_def, &mut b"test".as_slice(), u64::MAX).await
+                .expect("Unable to store file");
+        let result = io_manager.store_file_stream(&file_def, &mut b"test content".as_slice(), 4).await;
+        assert!(result.is_err());
+
+        let (mut reader, _) = io_manager.open_file_content(&file_def, 0).await.expect("Unable to open file");
+        let mut content = String::new();
+        reader.read_to_string(&mut content).await.expect("Unable to read file");
+        assert_eq!(content, "test");
+        let leftovers = std::fs::read_dir(dir.path()).expect("Unable to list base dir")
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with("test_atomic_id."))
+                .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[rocket::async_test]
+    async fn test_open_file_content_at_offset() {
+        let dir = TempDir::new("test_open_file_content_at_offset");
+        let file_def = FileDefinition::new("test_open_id".to_string(), "test_file.txt".to_string(), "test_dir".to_string());
+        let io_manager = FolderIOManager::new(dir.path());
+        io_manager.store_file_stream(&file_def, &mut b"test content".as_slice(), u64::MAX).await
+                .expect("Unable to store file");
+        let (mut reader, len) = io_manager.open_file_content(&file_def, 5).await.expect("Unable to open file");
+        let mut content = String::new();
+        reader.read_to_string(&mut content).await.expect("Unable to read file");
+        assert_eq!(len, 12);
+        assert_eq!(content, "content");
     }
 
     #[rocket::async_test]
     async fn test_delete_file() {
+        let dir = TempDir::new("test_delete_file");
         let file_def = FileDefinition {
             id: Some("test_id".to_string()),
             name: "test_file.txt".to_string(),
             path: "test_dir".to_string(),
             checksum: None,
             size: Some(0),
+            last_update: None,
+            revision: None,
         };
-        let io_manager = FolderIOManager;
+        let io_manager = FolderIOManager::new(dir.path());
         io_manager.create_empty(&file_def).await.expect("Unable to create test file");
         let result = io_manager.delete_file(&file_def).await;
         assert!(result.is_ok());
@@ -116,17 +211,23 @@ mod repository_tests {
     use crate::util::Util;
     use crate::model::FileData;
     use crate::model::FileDefinition;
+    use crate::config::Config;
     use crate::repository::FileRepository;
+    use crate::io_manager::FolderIOManager;
+    use crate::io_manager::StagedContent;
+    use super::TempDir;
 
     #[rocket::async_test]
     async fn test_create_empty_file_in_repository() {
-        let mut repository = FileRepository::new();
+        let mut repository = FileRepository::in_memory();
         let file_def = FileDefinition {
             id: Some("test_id".to_string()),
             name: "test_file.txt".to_string(),
             path: "test_dir".to_string(),
             checksum: None,
             size: Some(0),
+            last_update: None,
+            revision: None,
         };
         let result = repository.create_empty(&file_def).await;
         assert!(result.is_ok());
@@ -135,41 +236,1819 @@ mod repository_tests {
 
     #[rocket::async_test]
     async fn test_update_file_in_repository() {
-        let mut repository = FileRepository::new();
+        let mut repository = FileRepository::in_memory();
         let file_def = FileDefinition {
             id: Some("test_id".to_string()),
             name: "test_file.txt".to_string(),
             path: "test_dir".to_string(),
             checksum: None,
             size: Some(0),
+            last_update: None,
+            revision: None,
         };
         repository.create_empty(&file_def).await.expect("Unable to create empty file");
         let file_data = FileData {
             definition: file_def.clone(),
             content: b"updated content".to_vec(),
         };
-        let result = repository.update(&file_data).await;
+        let result = repository.update_stream(&file_data.definition, &mut file_data.content.as_slice(), u64::MAX).await;
         assert!(result.is_ok());
         let updated_file = repository.get_definition(&file_def.id.unwrap()).expect("File not found");
         assert_eq!(updated_file.size.unwrap(), file_data.content.len() as u64);
         assert_eq!(updated_file.checksum, Some(Util::checksum(&file_data.content)));
     }
 
+    #[rocket::async_test]
+    async fn test_update_staged_file_in_repository() {
+        let dir = TempDir::new("test_update_staged_file_in_repository");
+        let config = Config { base_path: dir.path().to_string(), ..Config::default() };
+        let mut repository = FileRepository::with_io_manager(config, FolderIOManager::new(dir.path()), None);
+        let file_def = FileDefinition::new(String::new(), "test_file.txt".to_string(), "test_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        let file_def = repository.get_definition(&id).expect("File not found");
+
+        assert!(StagedContent::write(dir.path(), &mut b"too long".as_slice(), 4).await.is_err());
+        let staged = StagedContent::write(dir.path(), &mut b"staged content".as_slice(), 64).await.expect("Unable to stage content");
+        assert_eq!(staged.stored.checksum, Util::checksum(b"staged content"));
+        repository.update_staged(&file_def, &staged).await.expect("Unable to update file");
+        assert!(!std::path::Path::new(&staged.path).exists());
+        assert_eq!(std::fs::read(Util::full_path(dir.path(), &file_def)).expect("Unable to read file"), b"staged content");
+        assert_eq!(repository.get_definition(&id).expect("File not found").size, Some(14));
+        staged.remove().await;
+        let scratch = std::fs::read_dir(std::path::Path::new(dir.path()).join(".tmp")).expect("No scratch dir");
+        assert_eq!(scratch.count(), 0);
+    }
+
     #[rocket::async_test]
     async fn test_delete_file_in_repository() {
-        let mut repository = FileRepository::new();
+        let mut repository = FileRepository::in_memory();
         let file_def = FileDefinition {
             id: Some("test_id".to_string()),
             name: "test_file.txt".to_string(),
             path: "test_dir".to_string(),
             checksum: None,
             size: Some(0),
+            last_update: None,
+            revision: None,
         };
         let created_id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
         let result = repository.delete(&created_id).await;
-        assert!(result.is_some());
+        assert!(result.expect("Unable to delete file").is_some());
         assert!(!repository.exists(&created_id));
     }
+
+    #[rocket::async_test]
+    async fn test_failed_delete_keeps_definition() {
+        let dir = TempDir::new("test_failed_delete_keeps_definition");
+        let mut repository = FileRepository::with_io_manager(Config::default(), FolderIOManager::new(dir.path()), None);
+        let file_def = FileDefinition::new(String::new(), "test_failed_delete.txt".to_string(), "test_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        let created = repository.get_definition(&id).expect("File not found");
+        std::fs::remove_file(Util::full_path(dir.path(), &created)).expect("Unable to remove content");
+
+        let result = repository.delete(&id).await;
+        assert!(result.is_err());
+        assert!(repository.exists(&id));
+        assert_eq!(repository.get_revision(), 1);
+    }
+}
+
+
+#[cfg(test)]
+mod patcher_tests {
+    use crate::util::Util;
+    use crate::model::FileChange;
+    use crate::model::ChangePatch;
+    use crate::model::ChangeType;
+    use crate::model::FileDefinition;
+    use crate::patcher::Patcher;
+    use crate::repository::FileRepository;
+
+    fn test_def(name: &str) -> FileDefinition {
+        FileDefinition {
+            id: None,
+            name: name.to_string(),
+            path: "test_patcher_dir".to_string(),
+            checksum: None,
+            size: Some(0),
+            last_update: None,
+            revision: None,
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_client_only_file_is_created_when_not_deleted() {
+        let mut repository = FileRepository::in_memory();
+        repository.create_empty(&test_def("server_file.txt")).await.expect("Unable to create empty file");
+        let client_rev = repository.get_revision();
+        repository.create_empty(&test_def("other_file.txt")).await.expect("Unable to create empty file");
+
+        let mut client_fd = test_def("client_file.txt");
+        client_fd.id = Some("client_only_id".to_string());
+        let patch = Patcher::get_patch(client_rev, &[client_fd], "test_client", &mut repository, |_| true, |_| true).await.expect("No patch");
+        let change = patch.changes.iter()
+                .find(|c| c.file.id.as_deref() == Some("client_only_id"))
+                .expect("No change for client file");
+        assert!(matches!(change.change, ChangeType::Create));
+    }
+
+    #[rocket::async_test]
+    async fn test_client_only_file_is_deleted_when_deleted_on_server() {
+        let mut repository = FileRepository::in_memory();
+        let id = repository.create_empty(&test_def("deleted_file.txt")).await.expect("Unable to create empty file");
+        let client_fd = repository.get_definition(&id).expect("File not found");
+        let client_rev = repository.get_revision();
+        repository.delete(&id).await.expect("Unable to delete file");
+
+        let patch = Patcher::get_patch(client_rev, &[client_fd], "test_client", &mut repository, |_| true, |_| true).await.expect("No patch");
+        assert_eq!(patch.changes.len(), 1);
+        assert!(matches!(patch.changes[0].change, ChangeType::Delete));
+    }
+
+    async fn diverge(server_edit: bool, client_edit: bool) -> FileChange {
+        let mut repository = FileRepository::in_memory();
+        let id = repository.create_empty(&test_def("shared_file.txt")).await.expect("Unable to create empty file");
+        let base_fd = repository.get_definition(&id).expect("File not found");
+        let client_rev = repository.get_revision();
+        if server_edit {
+            repository.update_stream(&base_fd, &mut b"server content".as_slice(), u64::MAX).await
+                    .expect("Unable to update file");
+        }
+        let mut client_fd = base_fd.clone();
+        if client_edit {
+            client_fd.checksum = Some(Util::checksum(b"client content"));
+            client_fd.size = Some(14);
+        }
+
+        let patch = Patcher::get_patch(client_rev, &[client_fd], "test_client", &mut repository, |_| true, |_| true).await.expect("No patch");
+        assert_eq!(patch.changes.len(), 1);
+        patch.changes.into_iter().next().unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn test_server_only_edit_is_downloaded() {
+        assert!(matches!(diverge(true, false).await.change, ChangeType::DoDownload));
+    }
+
+    #[rocket::async_test]
+    async fn test_client_only_edit_is_uploaded() {
+        assert!(matches!(diverge(false, true).await.change, ChangeType::DoUpload));
+    }
+
+    #[rocket::async_test]
+    async fn test_both_sides_edit_is_conflict() {
+        let change = diverge(true, true).await;
+        assert!(matches!(change.change, ChangeType::Conflict));
+        let copy = change.conflict_copy.expect("No conflict copy");
+        assert_ne!(copy.id, change.file.id);
+        assert_eq!(copy.path, change.file.path);
+        assert!(copy.name.starts_with("shared_file (conflict from test_client "));
+        assert!(copy.name.ends_with(").txt"));
+    }
+
+    #[rocket::async_test]
+    async fn test_diverged_file_without_base_is_conflict() {
+        let mut repository = FileRepository::in_memory();
+        repository.create_empty(&test_def("older_file.txt")).await.expect("Unable to create empty file");
+        let id = repository.create_empty(&test_def("newer_file.txt")).await.expect("Unable to create empty file");
+        let mut client_fd = repository.get_definition(&id).expect("File not found");
+            // The client claims a revision from before the file existed, so there is no base to compare with.
+        client_fd.revision = Some(1);
+        client_fd.checksum = Some(Util::checksum(b"client content"));
+
+        let patch = Patcher::get_patch(1, &[client_fd], "test_client", &mut repository, |_| true, |_| true).await.expect("No patch");
+        let change = patch.changes.iter()
+                .find(|c| c.file.id.as_deref() == Some(id.as_str()))
+                .expect("No change for the file");
+        assert!(matches!(change.change, ChangeType::Conflict));
+        assert!(change.conflict_copy.is_some());
+    }
+
+    #[rocket::async_test]
+    async fn test_retried_conflict_reuses_copy() {
+        let mut repository = FileRepository::in_memory();
+        let id = repository.create_empty(&test_def("retried_file.txt")).await.expect("Unable to create empty file");
+        let mut client_fd = repository.get_definition(&id).expect("File not found");
+        let client_rev = repository.get_revision();
+        repository.update_stream(&client_fd, &mut b"server content".as_slice(), u64::MAX).await
+                .expect("Unable to update file");
+        client_fd.checksum = Some(Util::checksum(b"client content"));
+
+        let first = Patcher::get_patch(client_rev, &[client_fd.clone()], "test_client", &mut repository, |_| true, |_| true).await.expect("No patch");
+        let files = repository.get_all_entries().len();
+        let retried = Patcher::get_patch(client_rev, &[client_fd.clone()], "test_client", &mut repository, |_| true, |_| true).await.expect("No patch");
+        let copy_id = |patch: &ChangePatch| patch.changes.iter()
+                .find_map(|c| c.conflict_copy.as_ref())
+                .and_then(|copy| copy.id.clone())
+                .expect("No conflict copy");
+        assert_eq!(copy_id(&first), copy_id(&retried));
+        assert_eq!(repository.get_all_entries().len(), files);
+
+            // A deleted copy gets a successor that retries keep reusing too.
+        repository.delete(&copy_id(&first)).await.expect("Unable to delete copy");
+        let recreated = Patcher::get_patch(client_rev, &[client_fd.clone()], "test_client", &mut repository, |_| true, |_| true).await.expect("No patch");
+        let retried = Patcher::get_patch(client_rev, &[client_fd], "test_client", &mut repository, |_| true, |_| true).await.expect("No patch");
+        assert_ne!(copy_id(&recreated), copy_id(&first));
+        assert_eq!(copy_id(&recreated), copy_id(&retried));
+        assert_eq!(repository.get_all_entries().len(), files);
+    }
+}
+
+#[cfg(test)]
+mod headers_tests {
+    use std::time::Duration;
+    use std::time::UNIX_EPOCH;
+    use crate::model::FileDefinition;
+    use crate::headers::Range;
+    use crate::headers::IfMatch;
+    use crate::headers::IfNoneMatch;
+    use crate::headers::RangeSelection;
+    use crate::headers::IfModifiedSince;
+    use crate::headers::etag_for;
+    use crate::headers::is_not_modified;
+
+    fn test_def() -> FileDefinition {
+        let mut file_def = FileDefinition::with_checksum("test_id".to_string(), "test_file.txt".to_string(),
+                "test_dir".to_string(), "abc123".to_string());
+        file_def.revision = Some(7);
+        file_def
+    }
+
+    #[test]
+    fn test_etag_for() {
+        let etag = etag_for(&test_def());
+        assert_eq!(etag.name(), "ETag");
+        assert_eq!(etag.value(), "\"abc123\"");
+    }
+
+    #[test]
+    fn test_if_match() {
+        let file_def = test_def();
+        assert!(IfMatch::from_header(None).matches(&file_def));
+        assert!(IfMatch::from_header(Some("*")).matches(&file_def));
+        assert!(IfMatch::from_header(Some("\"abc123\"")).matches(&file_def));
+        assert!(IfMatch::from_header(Some("W/\"old\", \"abc123\"")).matches(&file_def));
+        assert!(IfMatch::from_header(Some("\"rev-7\"")).matches(&file_def));
+        assert!(!IfMatch::from_header(Some("\"old\"")).matches(&file_def));
+        assert!(!IfMatch::from_header(Some("\"rev-6\"")).matches(&file_def));
+        assert!(!IfMatch::from_header(Some("7")).matches(&file_def));
+        assert!(!IfMatch::from_header(Some("W/\"abc123\"")).matches(&file_def));
+    }
+    #[test]
+    fn test_not_modified() {
+        let mut file_def = test_def();
+        file_def.last_update = Some(UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let no_tags = IfNoneMatch::from_header(None);
+        let no_date = IfModifiedSince::from_header(None);
+        assert!(!is_not_modified(&file_def, &no_tags, &no_date));
+
+        assert!(is_not_modified(&file_def, &IfNoneMatch::from_header(Some("\"abc123\"")), &no_date));
+        assert!(is_not_modified(&file_def, &IfNoneMatch::from_header(Some("W/\"abc123\"")), &no_date));
+        assert!(!is_not_modified(&file_def, &IfNoneMatch::from_header(Some("\"old\"")), &no_date));
+
+        let same_date = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let older_date = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(999_999));
+        assert!(is_not_modified(&file_def, &no_tags, &IfModifiedSince::from_header(Some(&same_date))));
+        assert!(!is_not_modified(&file_def, &no_tags, &IfModifiedSince::from_header(Some(&older_date))));
+        assert!(!is_not_modified(&file_def, &no_tags, &IfModifiedSince::from_header(Some("not a date"))));
+
+            // If-None-Match wins over If-Modified-Since.
+        assert!(!is_not_modified(&file_def, &IfNoneMatch::from_header(Some("\"old\"")),
+                &IfModifiedSince::from_header(Some(&same_date))));
+    }
+    #[test]
+    fn test_range_resolve() {
+        let file_def = test_def();
+        let resolve = |range: Option<&str>, if_range: Option<&str>| Range::from_headers(range, if_range).resolve(&file_def, 1000);
+
+        assert_eq!(resolve(None, None), RangeSelection::Full);
+        assert_eq!(resolve(Some("bytes=0-499"), None), RangeSelection::Partial(vec![(0, 499)]));
+        assert_eq!(resolve(Some("bytes=900-"), None), RangeSelection::Partial(vec![(900, 999)]));
+        assert_eq!(resolve(Some("bytes=-100"), None), RangeSelection::Partial(vec![(900, 999)]));
+        assert_eq!(resolve(Some("bytes=990-2000"), None), RangeSelection::Partial(vec![(990, 999)]));
+        assert_eq!(resolve(Some("bytes=0-9, 20-29"), None), RangeSelection::Partial(vec![(0, 9), (20, 29)]));
+        assert_eq!(resolve(Some("bytes=1000-"), None), RangeSelection::Unsatisfiable);
+        assert_eq!(resolve(Some("bytes=9-0"), None), RangeSelection::Full);
+        assert_eq!(resolve(Some("lines=0-9"), None), RangeSelection::Full);
+
+        assert_eq!(resolve(Some("bytes=0-9"), Some("\"abc123\"")), RangeSelection::Partial(vec![(0, 9)]));
+        assert_eq!(resolve(Some("bytes=0-9"), Some("\"old\"")), RangeSelection::Full);
+    }
+}
+
+#[cfg(test)]
+mod upload_tests {
+    use crate::util::Util;
+    use crate::model::UploadSession;
+    use crate::upload::UploadManager;
+    use super::TempDir;
+
+    fn test_request(content: &[u8]) -> UploadSession {
+        UploadSession {
+            id: None,
+            file_id: "test_upload_file".to_string(),
+            size: content.len() as u64,
+            checksum: Util::checksum(content),
+            offset: None,
+            base_revision: None,
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_chunked_upload() {
+        let dir = TempDir::new("test_chunked_upload");
+        let content = b"first chunk, second chunk";
+        let uploads = UploadManager::new(dir.path());
+        let session = uploads.open(&test_request(content)).await.expect("Unable to open session");
+        let id = session.id.clone().expect("No session id");
+        assert_eq!(session.offset, Some(0));
+
+        let session = uploads.append(&session, &mut &content[..13]).await.expect("Unable to append chunk");
+        assert_eq!(session.offset, Some(13));
+        assert!(uploads.verify(&session).await.is_err());
+
+            // Resuming picks up the offset from disk.
+        let session = uploads.get(&id).await.expect("Session not found");
+        assert_eq!(session.offset, Some(13));
+        let session = uploads.append(&session, &mut &content[13..]).await.expect("Unable to append chunk");
+        assert!(uploads.verify(&session).await.is_ok());
+
+        uploads.remove(&id).await.expect("Unable to remove session");
+        assert!(uploads.get(&id).await.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_upload_checksum_mismatch() {
+        let dir = TempDir::new("test_upload_checksum_mismatch");
+        let uploads = UploadManager::new(dir.path());
+        let mut request = test_request(b"content");
+        request.checksum = Util::checksum(b"something else");
+        let session = uploads.open(&request).await.expect("Unable to open session");
+        let session = uploads.append(&session, &mut &b"content"[..]).await.expect("Unable to append chunk");
+        assert!(uploads.verify(&session).await.is_err());
+        uploads.remove(session.id.as_ref().unwrap()).await.expect("Unable to remove session");
+    }
+
+    #[rocket::async_test]
+    async fn test_upload_chunk_over_declared_size() {
+        let dir = TempDir::new("test_upload_chunk_over_declared_size");
+        let uploads = UploadManager::new(dir.path());
+        let session = uploads.open(&test_request(b"short")).await.expect("Unable to open session");
+        let result = uploads.append(&session, &mut &b"much too long"[..]).await;
+        assert!(result.is_err());
+        let session = uploads.get(session.id.as_ref().unwrap()).await.expect("Session not found");
+        assert_eq!(session.offset, Some(5));
+        uploads.remove(session.id.as_ref().unwrap()).await.expect("Unable to remove session");
+    }
+
+    #[rocket::async_test]
+    async fn test_upload_rejects_path_ids() {
+        let dir = TempDir::new("test_upload_rejects_path_ids");
+        let uploads = UploadManager::new(dir.path());
+        assert!(uploads.get("../.sync-state").await.is_err());
+    }
+}
+
+#[cfg(test)]
+mod delta_tests {
+    use rocket::data::Limits;
+    use rocket::data::ToByteUnit;
+    use crate::util::Util;
+    use crate::config::Config;
+    use crate::config::Storage;
+    use crate::delta::Delta;
+    use crate::model::FileDelta;
+    use crate::model::FileSignature;
+    use crate::model::FileDefinition;
+    use crate::model::DeltaInstruction;
+    use crate::repository::FileRepository;
+
+    fn test_content(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| ((i * 31 + seed as usize) % 251) as u8).collect()
+    }
+
+    #[rocket::async_test]
+    async fn test_signature_blocks() {
+        let content = test_content(2500, 1);
+        let signature = Delta::signature(&mut content.as_slice(), 1000).await.expect("Unable to build signature");
+        assert_eq!(signature.size, 2500);
+        assert_eq!(signature.blocks.len(), 3);
+        assert_eq!(signature.checksum, Some(Util::checksum(&content)));
+        assert_eq!(signature.blocks[2].strong, Util::checksum(&content[2000..]));
+    }
+
+    #[rocket::async_test]
+    async fn test_diff_reuses_unchanged_blocks() {
+        let base = test_content(4096 * 8 + 100, 7);
+        let mut target = base.clone();
+        target.splice(5000..5000, b"inserted bytes".iter().copied());
+        target[20000] ^= 0xff;
+
+        let signature = Delta::signature(&mut base.as_slice(), 4096).await.expect("Unable to build signature");
+        let delta = Delta::diff(&signature, &mut target.as_slice()).await.expect("Unable to build delta");
+        assert_eq!(delta.target_checksum, Some(Util::checksum(&target)));
+
+        let literal_len: usize = delta.instructions.iter()
+                .filter_map(|i| match i {
+                    DeltaInstruction::Literal { data } => Some(Delta::decode_literal(data).unwrap().len()),
+                    _ => None,
+                })
+                .sum();
+        assert!(literal_len < 4096 * 3, "Too much literal data: {literal_len}");
+            // Last, shorter block is matched too.
+        assert!(matches!(delta.instructions.last(), Some(DeltaInstruction::Copy { block: 8, .. })));
+    }
+
+    #[rocket::async_test]
+    async fn test_apply_delta_in_repository() {
+        let mut repository = FileRepository::in_memory();
+        let file_def = FileDefinition::new(String::new(), "test_delta.bin".to_string(), "test_delta_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        let base = test_content(10_000, 3);
+        let file_def = repository.get_definition(&id).unwrap();
+        repository.update_stream(&file_def, &mut base.as_slice(), u64::MAX).await.expect("Unable to update file");
+
+        let mut target = base.clone();
+        target.truncate(9_000);
+        target.extend_from_slice(b"new tail");
+        let signature = Delta::signature(&mut base.as_slice(), 1024).await.unwrap();
+        let delta = Delta::diff(&signature, &mut target.as_slice()).await.unwrap();
+        repository.apply_delta(&id, &delta).await.expect("Unable to apply delta");
+
+        let updated = repository.get_definition(&id).unwrap();
+        assert_eq!(updated.checksum, Some(Util::checksum(&target)));
+        assert_eq!(updated.size, Some(target.len() as u64));
+
+            // Base has moved on, so the same delta no longer applies.
+        assert!(repository.apply_delta(&id, &delta).await.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_untrusted_block_sizes_rejected() {
+        let signature = FileSignature { block_size: u64::MAX, size: 0, checksum: None, blocks: Vec::new() };
+        assert!(Delta::diff(&signature, &mut b"content".as_slice()).await.is_err());
+
+        let mut repository = FileRepository::in_memory();
+        let file_def = FileDefinition::new(String::new(), "test_delta_overflow.bin".to_string(), "test_delta_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        let copy = |block, count| FileDelta {
+            block_size: Delta::MAX_BLOCK_SIZE,
+            base_checksum: None,
+            target_checksum: None,
+            instructions: vec![DeltaInstruction::Copy { block, count }]
+        };
+        assert!(repository.apply_delta(&id, &copy(u64::MAX, 1)).await.is_err());
+        assert!(repository.apply_delta(&id, &copy(0, u64::MAX)).await.is_err());
+        assert!(repository.apply_delta(&id, &FileDelta { block_size: 1, ..copy(0, 1) }).await.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_repeated_copies_stop_at_file_limit() {
+        let config = Config { storage: Storage::Memory, limits: Limits::default().limit("file", 1000.bytes()), ..Config::default() };
+        let mut repository = FileRepository::new(config);
+        let file_def = FileDefinition::new(String::new(), "test_delta_limit.bin".to_string(), "test_delta_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        let file_def = repository.get_definition(&id).expect("File not found");
+        repository.update_stream(&file_def, &mut test_content(600, 5).as_slice(), u64::MAX).await.expect("Unable to update file");
+        let copies = |times| FileDelta {
+            block_size: Delta::MIN_BLOCK_SIZE,
+            base_checksum: None,
+            target_checksum: None,
+            instructions: (0..times).map(|_| DeltaInstruction::Copy { block: 0, count: 2 }).collect()
+        };
+
+        assert!(repository.apply_delta(&id, &copies(2)).await.is_err());
+        assert_eq!(repository.get_definition(&id).expect("File not found").size, Some(600));
+        repository.apply_delta(&id, &copies(1)).await.expect("Unable to apply delta");
+    }
+}
+
+#[cfg(test)]
+mod chunk_store_tests {
+    use rocket::tokio::io::AsyncReadExt;
+    use crate::util::Util;
+    use crate::model::FileDefinition;
+    use crate::io_manager::IOManager;
+    use crate::chunk_store::ChunkedIOManager;
+    use super::TempDir;
+
+    fn test_content(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        }).collect()
+    }
+
+    async fn read_all(io_manager: &ChunkedIOManager, file_def: &FileDefinition, offset: u64) -> Vec<u8> {
+        let (mut reader, _) = io_manager.open_file_content(file_def, offset).await.expect("Unable to open file");
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).await.expect("Unable to read file");
+        content
+    }
+
+    #[rocket::async_test]
+    async fn test_chunked_round_trip() {
+        let dir = TempDir::new("test_chunked_round_trip");
+        let io_manager = ChunkedIOManager::new(dir.path());
+        let file_def = FileDefinition::new("test_chunk_rt".to_string(), "big.bin".to_string(), "test_dir".to_string());
+        let content = test_content(1_500_000, 1);
+        let stored = io_manager.store_file_stream(&file_def, &mut content.as_slice(), u64::MAX).await
+                .expect("Unable to store file");
+        assert_eq!(stored.size, content.len() as u64);
+        assert_eq!(stored.checksum, Util::checksum(&content));
+
+        assert_eq!(read_all(&io_manager, &file_def, 0).await, content);
+        assert_eq!(read_all(&io_manager, &file_def, 1_000_001).await, &content[1_000_001..]);
+        io_manager.delete_file(&file_def).await.expect("Unable to delete file");
+        assert!(io_manager.open_file_content(&file_def, 0).await.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_chunked_shared_chunks_survive_delete() {
+        let dir = TempDir::new("test_chunked_shared_chunks_survive_delete");
+        let io_manager = ChunkedIOManager::new(dir.path());
+        let first = FileDefinition::new("test_chunk_a".to_string(), "a.bin".to_string(), "test_dir".to_string());
+        let second = FileDefinition::new("test_chunk_b".to_string(), "b.bin".to_string(), "test_dir".to_string());
+        let content = test_content(700_000, 2);
+        let mut edited = content.clone();
+        edited[600_000] ^= 0xff;
+        io_manager.store_file_stream(&first, &mut content.as_slice(), u64::MAX).await.expect("Unable to store file");
+        io_manager.store_file_stream(&second, &mut edited.as_slice(), u64::MAX).await.expect("Unable to store file");
+
+        io_manager.delete_file(&first).await.expect("Unable to delete file");
+        assert_eq!(read_all(&io_manager, &second, 0).await, edited);
+        io_manager.delete_file(&second).await.expect("Unable to delete file");
+    }
+
+    #[rocket::async_test]
+    async fn test_chunked_empty_and_limit() {
+        let dir = TempDir::new("test_chunked_empty_and_limit");
+        let io_manager = ChunkedIOManager::new(dir.path());
+        let file_def = FileDefinition::new("test_chunk_empty".to_string(), "e.bin".to_string(), "test_dir".to_string());
+        io_manager.create_empty(&file_def).await.expect("Unable to create file");
+        assert!(read_all(&io_manager, &file_def, 0).await.is_empty());
+        let content = test_content(1000, 3);
+        assert!(io_manager.store_file_stream(&file_def, &mut content.as_slice(), 10).await.is_err());
+        io_manager.delete_file(&file_def).await.expect("Unable to delete file");
+    }
+
+    #[rocket::async_test]
+    async fn test_chunked_limit_releases_chunks() {
+        let dir = TempDir::new("test_chunked_limit_releases_chunks");
+        let io_manager = ChunkedIOManager::new(dir.path());
+        let kept = FileDefinition::new("test_chunk_kept".to_string(), "k.bin".to_string(), "test_dir".to_string());
+        let content = test_content(600_000, 6);
+        io_manager.store_file_stream(&kept, &mut content.as_slice(), u64::MAX).await.expect("Unable to store file");
+        let chunks = || -> Vec<String> {
+            std::fs::read_dir(format!("{}/.chunks", dir.path())).expect("No chunks dir")
+                    .flat_map(|dir| std::fs::read_dir(dir.expect("Unreadable dir").path()).expect("Unreadable dir"))
+                    .map(|chunk| chunk.expect("Unreadable chunk").file_name().to_string_lossy().to_string())
+                    .collect()
+        };
+        let before = chunks();
+        assert!(before.iter().all(|hash| hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())));
+
+            // Chunks already stored before the limit is hit go, those shared with the kept file stay.
+        let mut too_big = content.clone();
+        too_big.extend(test_content(1_000_000, 7));
+        let rejected = FileDefinition::new("test_chunk_rejected".to_string(), "x.bin".to_string(), "test_dir".to_string());
+        assert!(io_manager.store_file_stream(&rejected, &mut too_big.as_slice(), 1_200_000).await.is_err());
+        let mut after = chunks();
+        after.sort();
+        let mut before = before;
+        before.sort();
+        assert_eq!(after, before);
+        assert_eq!(read_all(&io_manager, &kept, 0).await, content);
+    }
+
+    #[rocket::async_test]
+    async fn test_chunks_outlive_open_readers() {
+        let dir = TempDir::new("test_chunks_outlive_open_readers");
+        let io_manager = ChunkedIOManager::new(dir.path());
+        let file_def = FileDefinition::new("test_chunk_read".to_string(), "r.bin".to_string(), "test_dir".to_string());
+        let content = test_content(300_000, 4);
+        io_manager.store_file_stream(&file_def, &mut content.as_slice(), u64::MAX).await.expect("Unable to store file");
+
+        let (mut reader, _) = io_manager.open_file_content(&file_def, 0).await.expect("Unable to open file");
+        io_manager.delete_file(&file_def).await.expect("Unable to delete file");
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).await.expect("Chunks removed mid-read");
+        assert_eq!(read, content);
+        drop(reader);
+
+            // Once nothing reads them, the next collection removes them.
+        let other = FileDefinition::new("test_chunk_other".to_string(), "o.bin".to_string(), "test_dir".to_string());
+        io_manager.store_file_stream(&other, &mut test_content(1000, 5).as_slice(), u64::MAX).await.expect("Unable to store file");
+        io_manager.delete_file(&other).await.expect("Unable to delete file");
+        let chunks: usize = std::fs::read_dir(format!("{}/.chunks", dir.path())).expect("No chunks dir")
+                .map(|dir| std::fs::read_dir(dir.expect("Unreadable dir").path()).expect("Unreadable dir").count())
+                .sum();
+        assert_eq!(chunks, 0);
+    }
+}
+
+
+#[cfg(test)]
+mod versions_tests {
+    use rocket::tokio::io::AsyncReadExt;
+    use crate::config::Config;
+    use crate::config::Storage;
+    use crate::model::FileDefinition;
+    use crate::repository::FileRepository;
+
+    async fn read_version(repository: &FileRepository, version: &FileDefinition) -> Vec<u8> {
+        let (mut reader, _) = repository.open_definition(version, 0).await.expect("Unable to open version");
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).await.expect("Unable to read version");
+        content
+    }
+
+    #[rocket::async_test]
+    async fn test_updates_keep_prior_versions() {
+        let mut repository = FileRepository::in_memory();
+        let file_def = FileDefinition::new(String::new(), "versioned.txt".to_string(), "test_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        let file_def = repository.get_definition(&id).expect("File not found");
+        repository.update_stream(&file_def, &mut b"first".as_slice(), u64::MAX).await.expect("Unable to update file");
+        repository.update_stream(&file_def, &mut b"second".as_slice(), u64::MAX).await.expect("Unable to update file");
+
+        let versions = repository.get_versions(&id).expect("No versions");
+        assert_eq!(versions.len(), 3);
+        assert_eq!(read_version(&repository, &versions[0]).await, b"");
+        assert_eq!(read_version(&repository, &versions[1]).await, b"first");
+        assert_eq!(read_version(&repository, &versions[2]).await, b"second");
+        repository.delete(&id).await.expect("Unable to delete file");
+    }
+
+    #[rocket::async_test]
+    async fn test_failed_update_keeps_versions() {
+        let config = Config { storage: Storage::Memory, version_retention: 1, ..Config::default() };
+        let mut repository = FileRepository::new(config);
+        let file_def = FileDefinition::new(String::new(), "retained.txt".to_string(), "test_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        let file_def = repository.get_definition(&id).expect("File not found");
+        repository.update_stream(&file_def, &mut b"first".as_slice(), u64::MAX).await.expect("Unable to update file");
+
+        assert!(repository.update_stream(&file_def, &mut b"too long".as_slice(), 2).await.is_err());
+        let versions = repository.get_versions(&id).expect("No versions");
+        assert_eq!(versions.len(), 2);
+        assert_eq!(read_version(&repository, &versions[0]).await, b"");
+    }
+
+    #[rocket::async_test]
+    async fn test_restore_version_records_update() {
+        let mut repository = FileRepository::in_memory();
+        let file_def = FileDefinition::new(String::new(), "restored.txt".to_string(), "test_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        let file_def = repository.get_definition(&id).expect("File not found");
+        repository.update_stream(&file_def, &mut b"good".as_slice(), u64::MAX).await.expect("Unable to update file");
+        let good_rev = repository.get_revision();
+        repository.update_stream(&file_def, &mut b"bad sync".as_slice(), u64::MAX).await.expect("Unable to update file");
+
+        let restored = repository.restore_version(&id, good_rev).await.expect("Unable to restore version");
+        assert_eq!(restored.revision, Some(repository.get_revision()));
+        assert_eq!(read_version(&repository, &restored).await, b"good");
+        assert_eq!(repository.get_versions(&id).expect("No versions").len(), 4);
+        assert!(repository.restore_version(&id, 999).await.is_err());
+        repository.delete(&id).await.expect("Unable to delete file");
+    }
+}
+
+
+#[cfg(test)]
+mod trash_tests {
+    use crate::model::ChangeType;
+    use crate::model::FileDefinition;
+    use crate::repository::FileRepository;
+
+    #[rocket::async_test]
+    async fn test_deleted_file_can_be_restored() {
+        let mut repository = FileRepository::in_memory();
+        let file_def = FileDefinition::new(String::new(), "trashed.txt".to_string(), "test_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        let file_def = repository.get_definition(&id).expect("File not found");
+        repository.update_stream(&file_def, &mut b"keep me".as_slice(), u64::MAX).await.expect("Unable to update file");
+        repository.delete(&id).await.expect("Unable to delete file");
+        assert!(!repository.exists(&id));
+        assert_eq!(repository.get_trash().len(), 1);
+
+        let restored = repository.restore_trashed(&id).await.expect("Unable to restore file");
+        assert_eq!(restored.revision, Some(repository.get_revision()));
+        assert!(matches!(repository.get_changes_since(repository.get_revision() - 1)[0].change, ChangeType::Create));
+        assert!(repository.get_trash().is_empty());
+        let (_, len) = repository.open_file(&id, 0).await.expect("Unable to open restored file");
+        assert_eq!(len, 7);
+        repository.delete(&id).await.expect("Unable to delete file");
+    }
+
+    #[rocket::async_test]
+    async fn test_restore_refuses_name_taken() {
+        let mut repository = FileRepository::in_memory();
+        let file_def = FileDefinition::new(String::new(), "taken.txt".to_string(), "test_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        repository.delete(&id).await.expect("Unable to delete file");
+        let new_id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+
+        assert!(repository.restore_trashed(&id).await.is_err());
+        assert!(repository.restore_trashed("missing").await.is_err());
+        repository.delete(&new_id).await.expect("Unable to delete file");
+    }
+}
+
+
+#[cfg(test)]
+mod snapshot_tests {
+    use rocket::tokio::io::AsyncReadExt;
+    use crate::model::ChangeType;
+    use crate::model::FileDefinition;
+    use crate::repository::FileRepository;
+
+    async fn create_with(repository: &mut FileRepository, name: &str, content: &[u8]) -> String {
+        let file_def = FileDefinition::new(String::new(), name.to_string(), "test_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        let file_def = repository.get_definition(&id).expect("File not found");
+        repository.update_stream(&file_def, &mut &content[..], u64::MAX).await.expect("Unable to update file");
+        id
+    }
+
+    async fn read_file(repository: &FileRepository, id: &str) -> Vec<u8> {
+        let (mut reader, _) = repository.open_file(id, 0).await.expect("Unable to open file");
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).await.expect("Unable to read file");
+        content
+    }
+
+    #[rocket::async_test]
+    async fn test_restore_snapshot() {
+        let mut repository = FileRepository::in_memory();
+        let edited = create_with(&mut repository, "snap_edited.txt", b"original").await;
+        let deleted = create_with(&mut repository, "snap_deleted.txt", b"still here").await;
+        let snapshot = repository.create_snapshot("before").await.expect("Unable to create snapshot");
+        assert_eq!(snapshot.revision, repository.get_revision());
+        assert_eq!(repository.get_snapshot_files("before").expect("No snapshot").len(), 2);
+        assert!(repository.create_snapshot("before").await.is_err());
+
+        let edited_def = repository.get_definition(&edited).expect("File not found");
+        repository.update_stream(&edited_def, &mut b"bulk change".as_slice(), u64::MAX).await.expect("Unable to update file");
+        repository.delete(&deleted).await.expect("Unable to delete file");
+        let added = create_with(&mut repository, "snap_added.txt", b"new").await;
+
+        let patch = repository.restore_snapshot("before").await.expect("Unable to restore snapshot");
+        assert_eq!(patch.revision, repository.get_revision());
+        assert_eq!(patch.changes.len(), 3);
+        assert!(patch.changes.iter().any(|c| matches!(c.change, ChangeType::Delete) && c.file.id.as_deref() == Some(added.as_str())));
+        assert!(!repository.exists(&added));
+        assert_eq!(read_file(&repository, &edited).await, b"original");
+        assert_eq!(read_file(&repository, &deleted).await, b"still here");
+
+        repository.delete_snapshot("before").await.expect("Unable to delete snapshot");
+        assert!(repository.get_snapshots().is_empty());
+        repository.delete(&edited).await.expect("Unable to delete file");
+        repository.delete(&deleted).await.expect("Unable to delete file");
+    }
 }
 
 
+#[cfg(test)]
+mod memory_store_tests {
+    use rocket::tokio::io::AsyncReadExt;
+    use crate::util::Util;
+    use crate::model::FileDefinition;
+    use crate::io_manager::IOManager;
+    use crate::memory_store::InMemoryIOManager;
+
+    #[rocket::async_test]
+    async fn test_memory_round_trip() {
+        let io_manager = InMemoryIOManager::default();
+        let file_def = FileDefinition::new("test_memory".to_string(), "m.txt".to_string(), "test_dir".to_string());
+        let stored = io_manager.store_file_stream(&file_def, &mut b"in memory".as_slice(), u64::MAX).await
+                .expect("Unable to store file");
+        assert_eq!(stored.checksum, Util::checksum(b"in memory"));
+        assert!(io_manager.store_file_stream(&file_def, &mut b"too long".as_slice(), 4).await.is_err());
+
+        let (mut reader, len) = io_manager.open_file_content(&file_def, 3).await.expect("Unable to open file");
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).await.expect("Unable to read file");
+        assert_eq!(len, 9);
+        assert_eq!(content, b"memory");
+
+        io_manager.trash_file(&file_def).await.expect("Unable to trash file");
+        assert!(io_manager.open_file_content(&file_def, 0).await.is_err());
+        io_manager.restore_trashed(&file_def).await.expect("Unable to restore file");
+        io_manager.delete_file(&file_def).await.expect("Unable to delete file");
+    }
+}
+
+
+#[cfg(test)]
+mod server_tests {
+    use rocket::http::Header;
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+    use crate::model::ChangeType;
+    use crate::model::ChangePatch;
+    use crate::model::MintedToken;
+    use crate::model::UploadSession;
+    use crate::model::VerifyReport;
+    use crate::config::Config;
+    use crate::config::Storage;
+    use crate::registry::RepositoryRegistry;
+    use crate::repository::FileRepository;
+    use crate::auth::Tokens;
+    use super::TempDir;
+
+    /// Server on an in-memory repository, keeping its tokens, grants and uploads in `dir`.
+    async fn test_client(dir: &TempDir) -> Client {
+        let config = Config { storage: Storage::Memory, base_path: dir.path().to_string(), ..Config::default() };
+        let registry = RepositoryRegistry::with_default(FileRepository::new(config));
+        Client::tracked(crate::build(registry)).await.expect("Unable to start server")
+    }
+
+    /// Mints an admin token on the server, returning the header carrying it.
+    async fn admin_token(client: &Client) -> Header<'static> {
+        let tokens = client.rocket().state::<Tokens>().expect("No token store");
+        let minted = tokens.lock().await.mint("test", true, None).expect("Unable to mint token");
+        Header::new("Authorization", format!("Bearer {}", minted.token))
+    }
+
+    #[rocket::async_test]
+    async fn test_embedded_server_round_trip() {
+        let dir = TempDir::new("test_embedded_server_round_trip");
+        let client = test_client(&dir).await;
+        let auth = admin_token(&client).await;
+        let created = client.post("/api/v1/repos/default/file").header(auth.clone())
+                .body(r#"{"name":"embedded.txt","path":"test_dir"}"#)
+                .dispatch().await;
+        assert_eq!(created.status(), Status::Created);
+        let id = created.headers().get_one("Location").expect("No location").to_string();
+
+        let updated = client.put(format!("/api/v1/repos/default/file/{id}")).header(auth.clone()).body("hello").dispatch().await;
+        assert_eq!(updated.status(), Status::Accepted);
+        let fetched = client.get(format!("/api/v1/repos/default/file/{id}")).header(auth.clone()).dispatch().await;
+        assert_eq!(fetched.into_string().await.as_deref(), Some("hello"));
+
+        let patch = client.post("/api/v1/repos/default/patch/0").header(auth.clone()).body("[]").dispatch().await
+                .into_json::<ChangePatch>().await.expect("No patch");
+        assert_eq!(patch.revision, 2);
+        assert_eq!(patch.changes.len(), 1);
+
+        let report = client.post("/admin/repos/default/verify").header(auth.clone()).dispatch().await
+                .into_json::<VerifyReport>().await.expect("No report");
+        assert_eq!(report.checked, 1);
+        assert!(report.is_clean());
+        assert_eq!(client.post("/api/v1/repos/other").header(auth.clone()).dispatch().await.status(), Status::Created);
+        let reports = client.post("/admin/verify").header(auth.clone()).dispatch().await
+                .into_json::<std::collections::BTreeMap<String, VerifyReport>>().await.expect("No reports");
+        assert_eq!(reports.keys().collect::<Vec<_>>(), ["default", "other"]);
+        assert_eq!(reports["default"].checked, 1);
+        assert!(reports.values().all(|report| report.is_clean()));
+    }
+
+    #[rocket::async_test]
+    async fn test_chunked_upload_over_http() {
+        let dir = TempDir::new("test_chunked_upload_over_http");
+        let client = test_client(&dir).await;
+        let auth = admin_token(&client).await;
+        let created = client.post("/api/v1/repos/default/file").header(auth.clone())
+                .body(r#"{"name":"uploaded.txt","path":"test_dir"}"#)
+                .dispatch().await;
+        let id = created.headers().get_one("Location").expect("No location").to_string();
+
+        let too_large = format!(r#"{{"file_id":"{id}","size":{},"checksum":"0"}}"#, u64::MAX);
+        assert_eq!(client.post("/api/v1/repos/default/upload").header(auth.clone()).body(too_large).dispatch().await.status(),
+                Status::PayloadTooLarge);
+        let request = format!(r#"{{"file_id":"{id}","size":11,"checksum":"{}"}}"#, crate::util::Util::checksum(b"hello world"));
+        let session = client.post("/api/v1/repos/default/upload").header(auth.clone()).body(request).dispatch().await
+                .into_json::<UploadSession>().await.expect("No session");
+        let session_path = format!("/api/v1/repos/default/upload/{}", session.id.expect("No session id"));
+        for (offset, chunk) in [(0, "hello"), (5, " world")] {
+            let appended = client.put(format!("{session_path}/{offset}")).header(auth.clone()).body(chunk).dispatch().await;
+            assert_eq!(appended.status(), Status::Ok);
+        }
+        let committed = client.post(format!("{session_path}/commit")).header(auth.clone()).dispatch().await;
+        assert_eq!(committed.status(), Status::Accepted);
+        let fetched = client.get(format!("/api/v1/repos/default/file/{id}")).header(auth.clone()).dispatch().await;
+        assert_eq!(fetched.into_string().await.as_deref(), Some("hello world"));
+
+        let request = format!(r#"{{"file_id":"{id}","size":5,"checksum":"{}"}}"#, crate::util::Util::checksum(b"stale"));
+        let stale = client.post("/api/v1/repos/default/upload").header(auth.clone()).body(request).dispatch().await
+                .into_json::<UploadSession>().await.expect("No session");
+        let stale_path = format!("/api/v1/repos/default/upload/{}", stale.id.expect("No session id"));
+        assert_eq!(client.put(format!("{stale_path}/0")).header(auth.clone()).body("stale").dispatch().await.status(), Status::Ok);
+        assert_eq!(client.put(format!("/api/v1/repos/default/file/{id}")).header(auth.clone()).body("newer").dispatch().await.status(),
+                Status::Accepted);
+        assert_eq!(client.post(format!("{stale_path}/commit")).header(auth.clone()).dispatch().await.status(),
+                Status::PreconditionFailed);
+        let fetched = client.get(format!("/api/v1/repos/default/file/{id}")).header(auth.clone()).dispatch().await;
+        assert_eq!(fetched.into_string().await.as_deref(), Some("newer"));
+    }
+
+    #[rocket::async_test]
+    async fn test_requests_need_a_token() {
+        let dir = TempDir::new("test_requests_need_a_token");
+        let client = test_client(&dir).await;
+        let missing = client.get("/api/v1/repos/default/trash").dispatch().await;
+        assert_eq!(missing.status(), Status::Unauthorized);
+        assert_eq!(missing.headers().get_one("WWW-Authenticate"), Some("Bearer"));
+        let invalid = client.get("/api/v1/repos/default/trash")
+                .header(Header::new("Authorization", "Bearer not-a-token")).dispatch().await;
+        assert_eq!(invalid.status(), Status::Unauthorized);
+
+        let auth = admin_token(&client).await;
+        let minted = client.post("/admin/tokens?name=client").header(auth.clone()).dispatch().await
+                .into_json::<MintedToken>().await.expect("No token");
+        assert!(!minted.details.admin);
+        let user = Header::new("Authorization", format!("Bearer {}", minted.token));
+        assert_eq!(client.get("/api/v1/repos/default/trash").header(user.clone()).dispatch().await.status(), Status::Ok);
+        assert_eq!(client.get("/admin/tokens").header(user.clone()).dispatch().await.status(), Status::Forbidden);
+        assert_eq!(client.post("/api/v1/repos/other").header(user.clone()).dispatch().await.status(), Status::Forbidden);
+
+        let revoke = format!("/admin/tokens/{}", minted.details.id);
+        assert_eq!(client.delete(revoke).header(auth.clone()).dispatch().await.status(), Status::Accepted);
+        assert_eq!(client.get("/api/v1/repos/default/trash").header(user).dispatch().await.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn test_grants_limit_user_tokens() {
+        let dir = TempDir::new("test_grants_limit_user_tokens");
+        let client = test_client(&dir).await;
+        let auth = admin_token(&client).await;
+        let mut ids = Vec::new();
+        for path in ["docs", "docs/drafts", "private"] {
+            let created = client.post("/api/v1/repos/default/file").header(auth.clone())
+                    .body(format!(r#"{{"name":"granted.txt","path":"{path}"}}"#))
+                    .dispatch().await;
+            ids.push(created.headers().get_one("Location").expect("No location").to_string());
+        }
+        let (docs, drafts, private) = (&ids[0], &ids[1], &ids[2]);
+
+        let user = client.put("/admin/users/alice").header(auth.clone()).body(r#"{"name":"alice","groups":["team"]}"#).dispatch().await;
+        assert_eq!(user.status(), Status::Accepted);
+        for grant in [r#"{"group":"team","repository":"default","path":"docs","permission":"read"}"#,
+                    r#"{"user":"alice","repository":"*","path":"/docs/drafts/","permission":"write"}"#] {
+            assert_eq!(client.post("/admin/grants").header(auth.clone()).body(grant).dispatch().await.status(), Status::Created);
+        }
+        let minted = client.post("/admin/tokens?name=laptop&user=alice").header(auth.clone()).dispatch().await
+                .into_json::<MintedToken>().await.expect("No token");
+        let alice = Header::new("Authorization", format!("Bearer {}", minted.token));
+
+        let get = |id: &String| client.get(format!("/api/v1/repos/default/file/{id}")).header(alice.clone());
+        assert_eq!(get(docs).dispatch().await.status(), Status::Ok);
+        assert_eq!(client.post("/admin/tokens?name=ghost&user=nobody").header(auth.clone()).dispatch().await.status(), Status::BadRequest);
+        let userless = client.post("/admin/tokens?name=plain").header(auth.clone()).dispatch().await
+                .into_json::<MintedToken>().await.expect("No token");
+        let response = client.get(format!("/api/v1/repos/default/file/{docs}"))
+                .header(Header::new("Authorization", format!("Bearer {}", userless.token)))
+                .dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client.get("/api/v1/repos/default/snapshots/missing/files")
+                .header(Header::new("Authorization", format!("Bearer {}", userless.token)))
+                .dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(get(private).dispatch().await.status(), Status::Forbidden);
+        let put = |id: &String| client.put(format!("/api/v1/repos/default/file/{id}")).header(alice.clone()).body("changed");
+        assert_eq!(put(docs).dispatch().await.status(), Status::Forbidden);
+        assert_eq!(put(drafts).dispatch().await.status(), Status::Accepted);
+        let delete = |id: &String| client.delete(format!("/api/v1/repos/default/file/{id}")).header(alice.clone());
+        assert_eq!(delete(private).dispatch().await.status(), Status::Forbidden);
+
+        let patch = client.post("/api/v1/repos/default/patch/0").header(alice.clone()).body("[]").dispatch().await
+                .into_json::<ChangePatch>().await.expect("No patch");
+        let mut paths: Vec<String> = patch.changes.iter().map(|change| change.file.path.clone()).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["docs".to_string(), "docs/drafts".to_string()]);
+            // Claiming a readable path for a file elsewhere doesn't bring it into view.
+        let claimed = format!(r#"[{{"id":"{private}","name":"granted.txt","path":"docs","size":0,"revision":1}}]"#);
+        let patch = client.post("/api/v1/repos/default/patch/1").header(alice.clone()).body(claimed).dispatch().await
+                .into_json::<ChangePatch>().await.expect("No patch");
+        assert!(patch.changes.iter().all(|change| change.file.id.as_ref() != Some(private)));
+        let names = client.get("/api/v1/repos").header(alice.clone()).dispatch().await
+                .into_json::<Vec<String>>().await.expect("No repository list");
+        assert_eq!(names, vec!["default".to_string()]);
+
+            // Upload sessions on files alice can't write stay out of her reach.
+        let session = client.post("/api/v1/repos/default/upload").header(auth.clone())
+                .body(format!(r#"{{"file_id":"{docs}","size":1,"checksum":"0"}}"#))
+                .dispatch().await
+                .into_json::<UploadSession>().await.expect("No session");
+        let session_path = format!("/api/v1/repos/default/upload/{}", session.id.expect("No session id"));
+        assert_eq!(client.get(&session_path).header(alice.clone()).dispatch().await.status(), Status::Forbidden);
+        assert_eq!(client.put(format!("{session_path}/0")).header(alice.clone()).body("x").dispatch().await.status(), Status::Forbidden);
+        assert_eq!(client.delete(&session_path).header(alice.clone()).dispatch().await.status(), Status::Forbidden);
+        assert_eq!(client.get(&session_path).header(auth.clone()).dispatch().await.status(), Status::Ok);
+
+            // Diverged edits of a file alice can only read are a conflict without a copy, so she keeps her own.
+        assert_eq!(client.put(format!("/api/v1/repos/default/file/{docs}")).header(auth.clone()).body("server").dispatch().await.status(),
+                Status::Accepted);
+        let client_fd = format!(r#"[{{"id":"{docs}","name":"granted.txt","path":"docs","size":6,"checksum":"client","revision":1}}]"#);
+        let patch = client.post("/api/v1/repos/default/patch/1").header(alice.clone()).body(client_fd).dispatch().await
+                .into_json::<ChangePatch>().await.expect("No patch");
+        let change = patch.changes.iter().find(|change| change.file.id.as_ref() == Some(docs)).expect("No change for docs");
+        assert!(matches!(change.change, ChangeType::Conflict));
+        assert!(patch.changes.iter().all(|change| change.conflict_copy.is_none() && !change.file.name.contains("conflict")));
+        let bad_client = client.post("/api/v1/repos/default/patch/1?client=../x").header(alice.clone()).body("[]").dispatch().await;
+        assert_eq!(bad_client.status(), Status::BadRequest);
+
+        assert_eq!(client.delete("/admin/users/alice").header(auth.clone()).dispatch().await.status(), Status::Accepted);
+        assert_eq!(get(docs).dispatch().await.status(), Status::Forbidden);
+    }
+
+    #[rocket::async_test]
+    async fn test_named_repositories_are_independent() {
+        let dir = TempDir::new("test_named_repositories_are_independent");
+        let client = test_client(&dir).await;
+        let auth = admin_token(&client).await;
+        assert_eq!(client.post("/api/v1/repos/project-a").header(auth.clone()).dispatch().await.status(), Status::Created);
+        assert_eq!(client.post("/api/v1/repos/project-a").header(auth.clone()).dispatch().await.status(), Status::BadRequest);
+        assert_eq!(client.post("/api/v1/repos/bad.name").header(auth.clone()).dispatch().await.status(), Status::BadRequest);
+        let names = client.get("/api/v1/repos").header(auth.clone()).dispatch().await
+                .into_json::<Vec<String>>().await.expect("No repository list");
+        assert_eq!(names, vec!["default".to_string(), "project-a".to_string()]);
+
+        for _ in 0..2 {
+            let created = client.post("/api/v1/repos/project-a/file").header(auth.clone())
+                    .body(r#"{"name":"named.txt","path":"test_dir"}"#)
+                    .dispatch().await;
+            assert_eq!(created.status(), Status::Created);
+            let id = created.headers().get_one("Location").expect("No location").to_string();
+            assert_eq!(client.delete(format!("/api/v1/repos/project-a/file/{id}")).header(auth.clone()).dispatch().await.status(), Status::Accepted);
+        }
+        let named_patch = client.post("/api/v1/repos/project-a/patch/0").header(auth.clone()).body("[]").dispatch().await
+                .into_json::<ChangePatch>().await.expect("No patch");
+        assert_eq!(named_patch.revision, 4);
+        let default_patch = client.post("/api/v1/repos/default/patch/0").header(auth.clone()).body("[]").dispatch().await
+                .into_json::<ChangePatch>().await.expect("No patch");
+        assert_eq!(default_patch.revision, 0);
+
+        assert_eq!(client.get("/api/v1/repos/missing/trash").header(auth.clone()).dispatch().await.status(), Status::NotFound);
+        assert_eq!(client.delete("/api/v1/repos/default").header(auth.clone()).dispatch().await.status(), Status::BadRequest);
+        assert_eq!(client.delete("/api/v1/repos/project-a").header(auth.clone()).dispatch().await.status(), Status::Accepted);
+        assert_eq!(client.get("/api/v1/repos/project-a/trash").header(auth.clone()).dispatch().await.status(), Status::NotFound);
+        assert_eq!(client.delete("/api/v1/repos/project-a").header(auth.clone()).dispatch().await.status(), Status::NotFound);
+        assert_eq!(client.post("/api/v1/repos/project-a").header(auth.clone()).dispatch().await.status(), Status::Created);
+    }
+
+    #[rocket::async_test]
+    async fn test_default_repository_aliases() {
+        let dir = TempDir::new("test_default_repository_aliases");
+        let client = test_client(&dir).await;
+        let auth = admin_token(&client).await;
+        let created = client.post("/api/v1/file").header(auth.clone())
+                .body(r#"{"name":"aliased.txt","path":"test_dir"}"#)
+                .dispatch().await;
+        assert_eq!(created.status(), Status::Created);
+        let id = created.headers().get_one("Location").expect("No location").to_string();
+        assert_eq!(client.put(format!("/api/v1/file/{id}")).header(auth.clone()).body("aliased").dispatch().await.status(),
+                Status::Accepted);
+        let fetched = client.get(format!("/api/v1/repos/default/file/{id}")).header(auth.clone()).dispatch().await;
+        assert_eq!(fetched.into_string().await.as_deref(), Some("aliased"));
+
+        let patch = client.post("/api/v1/patch/0?client=laptop").header(auth.clone()).body("[]").dispatch().await
+                .into_json::<ChangePatch>().await.expect("No patch");
+        assert_eq!(patch.revision, 2);
+        assert_eq!(client.get("/api/v1/trash").header(auth.clone()).dispatch().await.status(), Status::Ok);
+        assert_eq!(client.get("/api/v1/files").header(auth.clone()).dispatch().await.status(), Status::NotFound);
+    }
+}
+
+
+#[cfg(test)]
+mod verify_tests {
+    use crate::util::Util;
+    use crate::model::ChangeType;
+    use crate::model::FileDefinition;
+    use crate::config::Config;
+    use crate::repository::FileRepository;
+    use crate::io_manager::FolderIOManager;
+    use super::TempDir;
+
+    #[rocket::async_test]
+    async fn test_verify_and_repair() {
+        let dir = TempDir::new("test_verify_and_repair");
+        let config = Config { base_path: dir.path().to_string(), ..Config::default() };
+        let mut repository = FileRepository::with_io_manager(config, FolderIOManager::new(dir.path()), None);
+        let mut ids = Vec::new();
+        for name in ["test_verify_ok.txt", "test_verify_changed.txt", "test_verify_missing.txt"] {
+            let file_def = FileDefinition::new(String::new(), name.to_string(), "test_dir".to_string());
+            let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+            let file_def = repository.get_definition(&id).expect("File not found");
+            repository.update_stream(&file_def, &mut b"content".as_slice(), u64::MAX).await.expect("Unable to update file");
+            ids.push(id);
+        }
+        let changed = repository.get_definition(&ids[1]).expect("File not found");
+        std::fs::write(Util::full_path(dir.path(), &changed), b"tampered content").expect("Unable to tamper with file");
+        let missing = repository.get_definition(&ids[2]).expect("File not found");
+        let missing_version = repository.get_versions(&ids[2]).expect("File not found")[0].revision.expect("No revision");
+        let missing_version_path = Util::version_path(dir.path(), &missing, missing_version);
+        assert!(std::path::Path::new(&missing_version_path).exists());
+        std::fs::remove_file(Util::full_path(dir.path(), &missing)).expect("Unable to remove file");
+        let orphan = FileDefinition::new("test_verify_orphan".to_string(), String::new(), String::new());
+        std::fs::write(Util::full_path(dir.path(), &orphan), b"orphan").expect("Unable to write orphan");
+
+        let report = repository.verify(false).await.expect("Unable to verify");
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].id, missing.id);
+        assert_eq!(report.mismatched.len(), 1);
+        assert_eq!(report.mismatched[0].size, 16);
+        assert!(report.orphans.contains(&"test_verify_orphan".to_string()));
+        assert!(!report.repaired);
+        assert_eq!(repository.get_revision(), 6);
+
+        let report = repository.verify(true).await.expect("Unable to repair");
+        assert!(report.repaired);
+        assert!(!repository.exists(&ids[2]));
+        assert!(!std::path::Path::new(&missing_version_path).exists());
+        assert_eq!(repository.get_definition(&ids[1]).expect("File not found").size, Some(16));
+        let changes = repository.get_changes_since(6);
+        assert!(matches!(changes[0].change, ChangeType::Delete));
+        assert!(matches!(changes[1].change, ChangeType::Update));
+
+        let report = repository.verify(false).await.expect("Unable to verify");
+        assert!(report.missing.is_empty() && report.mismatched.is_empty());
+    }
+}
+
+
+#[cfg(test)]
+mod scrubber_tests {
+    use std::sync::Arc;
+    use rocket::http::Header;
+    use rocket::http::Status;
+    use rocket::tokio::sync::Mutex;
+    use rocket::local::asynchronous::Client;
+    use crate::util::Util;
+    use crate::model::FileDefinition;
+    use crate::scrubber::Scrubber;
+    use crate::config::Config;
+    use crate::config::ReadVerification;
+    use crate::registry::RepositoryRegistry;
+    use crate::repository::FileRepository;
+    use crate::io_manager::FolderIOManager;
+    use crate::io_manager::StorageIOManager;
+    use crate::auth::Tokens;
+    use super::TempDir;
+
+    /// Mints an admin token on the server, returning the header carrying it.
+    async fn admin_token(client: &Client) -> Header<'static> {
+        let tokens = client.rocket().state::<Tokens>().expect("No token store");
+        let minted = tokens.lock().await.mint("test", true, None).expect("Unable to mint token");
+        Header::new("Authorization", format!("Bearer {}", minted.token))
+    }
+
+    #[rocket::async_test]
+    async fn test_scrub_quarantines_corrupt_content() {
+        let dir = TempDir::new("test_scrub_quarantines_corrupt_content");
+        let config = Config { base_path: dir.path().to_string(), ..Config::default() };
+        let mut repository = FileRepository::with_io_manager(config, StorageIOManager::Folder(FolderIOManager::new(dir.path())), None);
+        let file_def = FileDefinition::new(String::new(), "test_scrub.txt".to_string(), "test_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        let file_def = repository.get_definition(&id).expect("File not found");
+        repository.update_stream(&file_def, &mut b"content".as_slice(), u64::MAX).await.expect("Unable to update file");
+        assert!(repository.content_matches(&repository.get_definition(&id).expect("File not found")).await
+                .expect("Unable to check content"));
+
+        std::fs::write(Util::full_path(dir.path(), &file_def), b"c0ntent").expect("Unable to corrupt file");
+        let repository = Arc::new(Mutex::new(repository));
+        assert_eq!(Scrubber::scrub(&repository, true).await, vec![id.clone()]);
+        assert!(repository.lock().await.is_quarantined(&id));
+
+        let repository = Arc::into_inner(repository).expect("Repository still shared").into_inner();
+        let client = Client::tracked(crate::build(RepositoryRegistry::with_default(repository))).await.expect("Unable to start server");
+        let auth = admin_token(&client).await;
+        let fetched = client.get(format!("/api/v1/repos/default/file/{id}")).header(auth.clone()).dispatch().await;
+        assert_eq!(fetched.status(), Status::InternalServerError);
+
+        let updated = client.put(format!("/api/v1/repos/default/file/{id}")).header(auth.clone()).body("replaced").dispatch().await;
+        assert_eq!(updated.status(), Status::Accepted);
+        let fetched = client.get(format!("/api/v1/repos/default/file/{id}")).header(auth.clone()).dispatch().await;
+        assert_eq!(fetched.into_string().await.as_deref(), Some("replaced"));
+    }
+
+    #[rocket::async_test]
+    async fn test_read_verification_stops_corrupt_content() {
+        let dir = TempDir::new("test_read_verification_stops_corrupt_content");
+        let config = Config { base_path: dir.path().to_string(), read_verification: ReadVerification::Always, ..Config::default() };
+        let mut repository = FileRepository::with_io_manager(config, StorageIOManager::Folder(FolderIOManager::new(dir.path())), None);
+        let file_def = FileDefinition::new(String::new(), "test_read.txt".to_string(), "test_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        let file_def = repository.get_definition(&id).expect("File not found");
+        repository.update_stream(&file_def, &mut b"content".as_slice(), u64::MAX).await.expect("Unable to update file");
+
+        let client = Client::tracked(crate::build(RepositoryRegistry::with_default(repository))).await.expect("Unable to start server");
+        let auth = admin_token(&client).await;
+        let fetched = client.get(format!("/api/v1/repos/default/file/{id}")).header(auth.clone()).dispatch().await;
+        assert_eq!(fetched.into_string().await.as_deref(), Some("content"));
+
+        std::fs::write(Util::full_path(dir.path(), &file_def), b"c0ntent").expect("Unable to corrupt file");
+        let fetched = client.get(format!("/api/v1/repos/default/file/{id}")).header(auth.clone()).dispatch().await;
+        assert_ne!(fetched.into_string().await.as_deref(), Some("c0ntent"));
+        let ranged = client.get(format!("/api/v1/repos/default/file/{id}")).header(auth.clone())
+                .header(Header::new("Range", "bytes=0-2")).dispatch().await;
+        assert_eq!(ranged.status(), Status::InternalServerError);
+    }
+}
+
+
+#[cfg(test)]
+mod s3_store_tests {
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use std::collections::BTreeMap;
+    use std::collections::HashMap;
+    use rocket::State;
+    use rocket::Request;
+    use rocket::data::Data;
+    use rocket::data::ToByteUnit;
+    use rocket::http::Header;
+    use rocket::http::Status;
+    use rocket::fairing::AdHoc;
+    use rocket::request::Outcome;
+    use rocket::request::FromRequest;
+    use rocket::tokio::sync::oneshot;
+    use rocket::tokio::io::AsyncReadExt;
+    use crate::util::Util;
+    use crate::config::S3Settings;
+    use crate::model::FileDefinition;
+    use crate::io_manager::IOManager;
+    use crate::s3_store::S3IOManager;
+
+    /// Bare bones stand-in for an S3 endpoint, keeping objects in memory.
+    #[derive(Default)]
+    struct FakeBucket {
+        objects: Mutex<HashMap<String, Vec<u8>>>,
+        uploads: Mutex<HashMap<String, BTreeMap<u32, Vec<u8>>>>
+    }
+
+    struct S3Headers {
+        range: Option<u64>,
+        copy_source: Option<String>,
+        copy_range: Option<(usize, usize)>
+    }
+    #[rocket::async_trait]
+    impl<'r> FromRequest<'r> for S3Headers {
+        type Error = ();
+        async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+            let headers = request.headers();
+            if !headers.get_one("Authorization").is_some_and(|a| a.starts_with("AWS4-HMAC-SHA256 Credential=test/")) {
+                return Outcome::Error((Status::Forbidden, ()));
+            }
+            let range = headers.get_one("Range")
+                    .and_then(|r| r.strip_prefix("bytes=")?.strip_suffix('-')?.parse().ok());
+            let copy_range = headers.get_one("x-amz-copy-source-range")
+                    .and_then(|r| r.strip_prefix("bytes=")?.split_once('-'))
+                    .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)));
+            let copy_source = headers.get_one("x-amz-copy-source")
+                    .map(|s| s.trim_start_matches('/').split_once('/').expect("No bucket").1.to_string());
+            Outcome::Success(S3Headers { range, copy_source, copy_range })
+        }
+    }
+
+    fn key(path: PathBuf) -> String {
+        path.to_str().expect("Invalid key").to_string()
+    }
+
+    #[derive(Responder)]
+    struct Stored {
+        body: String,
+        etag: Header<'static>
+    }
+
+    #[get("/<_bucket>/<path..>")]
+    fn get_object(_bucket: &str, path: PathBuf, headers: S3Headers, bucket: &State<FakeBucket>) -> Result<Vec<u8>, Status> {
+        let objects = bucket.objects.lock().unwrap();
+        let object = objects.get(&key(path)).ok_or(Status::NotFound)?;
+        Ok(object[headers.range.unwrap_or(0) as usize..].to_vec())
+    }
+
+    #[allow(non_snake_case)]
+    #[put("/<_bucket>/<path..>?<partNumber>&<uploadId>", data = "<data>")]
+    async fn put_object(_bucket: &str, path: PathBuf, partNumber: Option<u32>, uploadId: Option<&str>, headers: S3Headers,
+                data: Data<'_>, bucket: &State<FakeBucket>) -> Result<Stored, Status> {
+        let mut content = data.open(64.mebibytes()).into_bytes().await.map_err(|_| Status::BadRequest)?.into_inner();
+        if let Some(source) = &headers.copy_source {
+            let objects = bucket.objects.lock().unwrap();
+            let object = objects.get(source).ok_or(Status::NotFound)?;
+            content = match headers.copy_range {
+                Some((start, end)) => object[start..=end].to_vec(),
+                None => object.clone(),
+            };
+        }
+        let etag = format!("\"{}\"", Util::checksum(&content));
+        match (partNumber, uploadId) {
+            (Some(number), Some(upload_id)) => {
+                let mut uploads = bucket.uploads.lock().unwrap();
+                uploads.get_mut(upload_id).ok_or(Status::NotFound)?.insert(number, content);
+            },
+            _ => {
+                bucket.objects.lock().unwrap().insert(key(path), content);
+            },
+        }
+        let body = format!("<CopyPartResult><ETag>{etag}</ETag></CopyPartResult>");
+        Ok(Stored { body, etag: Header::new("ETag", etag) })
+    }
+
+    #[allow(non_snake_case)]
+    #[post("/<_bucket>/<path..>?<uploads>&<uploadId>", data = "<data>")]
+    async fn post_object(_bucket: &str, path: PathBuf, uploads: Option<&str>, uploadId: Option<&str>, _auth: S3Headers,
+                data: Data<'_>, bucket: &State<FakeBucket>) -> Result<String, Status> {
+        if uploads.is_some() {
+            let upload_id = Util::new_id();
+            bucket.uploads.lock().unwrap().insert(upload_id.clone(), BTreeMap::new());
+            return Ok(format!("<InitiateMultipartUploadResult><UploadId>{upload_id}</UploadId></InitiateMultipartUploadResult>"));
+        }
+        let body = data.open(1.mebibytes()).into_string().await.map_err(|_| Status::BadRequest)?.into_inner();
+        let parts = bucket.uploads.lock().unwrap().remove(uploadId.ok_or(Status::BadRequest)?).ok_or(Status::NotFound)?;
+        if body.matches("<Part>").count() != parts.len() {
+            return Err(Status::BadRequest);
+        }
+        bucket.objects.lock().unwrap().insert(key(path), parts.into_values().flatten().collect());
+        Ok("<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".to_string())
+    }
+
+    #[allow(non_snake_case)]
+    #[delete("/<_bucket>/<path..>?<uploadId>")]
+    fn delete_object(_bucket: &str, path: PathBuf, uploadId: Option<&str>, _auth: S3Headers, bucket: &State<FakeBucket>) -> Status {
+        match uploadId {
+            Some(upload_id) => {
+                bucket.uploads.lock().unwrap().remove(upload_id);
+            },
+            None => {
+                bucket.objects.lock().unwrap().remove(&key(path));
+            },
+        }
+        Status::NoContent
+    }
+
+    /// Starts a fake bucket on a free port and returns settings pointing at it.
+    async fn start_fake_s3() -> S3Settings {
+        let (sender, receiver) = oneshot::channel();
+        let sender = Mutex::new(Some(sender));
+        let figment = rocket::Config::figment()
+                .merge(("port", 0))
+                .merge(("log_level", "off"));
+        let server = rocket::custom(figment)
+                .manage(FakeBucket::default())
+                .mount("/", routes![get_object, put_object, post_object, delete_object])
+                .attach(AdHoc::on_liftoff("Port", move |rocket| Box::pin(async move {
+                    if let Some(sender) = sender.lock().unwrap().take() {
+                        let _ = sender.send(rocket.config().port);
+                    }
+                })));
+        rocket::tokio::spawn(server.launch());
+        let port = receiver.await.expect("Fake S3 didn't start");
+
+        S3Settings {
+            endpoint: format!("http://127.0.0.1:{port}"),
+            bucket: "bucket".to_string(),
+            prefix: "test/".to_string(),
+            region: "us-east-1".to_string(),
+            access_key: "test".to_string(),
+            secret_key: "secret".to_string(),
+            part_size: S3IOManager::MIN_PART_SIZE
+        }
+    }
+
+    async fn read_all(io_manager: &S3IOManager, file_def: &FileDefinition, offset: u64) -> Vec<u8> {
+        let (mut reader, _) = io_manager.open_file_content(file_def, offset).await.expect("Unable to open file");
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).await.expect("Unable to read file");
+        content
+    }
+
+    #[rocket::async_test]
+    async fn test_s3_round_trip() {
+        let io_manager = S3IOManager::new(start_fake_s3().await);
+        let file_def = FileDefinition::new("test_s3".to_string(), "s3.txt".to_string(), "test_dir".to_string());
+        io_manager.create_empty(&file_def).await.expect("Unable to create file");
+        assert!(read_all(&io_manager, &file_def, 0).await.is_empty());
+
+        let stored = io_manager.store_file_stream(&file_def, &mut b"small object".as_slice(), u64::MAX).await
+                .expect("Unable to store file");
+        assert_eq!(stored.checksum, Util::checksum(b"small object"));
+        assert_eq!(read_all(&io_manager, &file_def, 6).await, b"object");
+
+        io_manager.store_version(&file_def, 1).await.expect("Unable to store version");
+        io_manager.trash_file(&file_def).await.expect("Unable to trash file");
+        assert!(io_manager.open_file_content(&file_def, 0).await.is_err());
+        io_manager.restore_trashed(&file_def).await.expect("Unable to restore file");
+        let (_, len) = io_manager.open_version_content(&file_def, 1, 0).await.expect("Unable to open version");
+        assert_eq!(len, 12);
+        io_manager.delete_version(&file_def, 1).await.expect("Unable to delete version");
+        io_manager.delete_file(&file_def).await.expect("Unable to delete file");
+        assert!(io_manager.open_file_content(&file_def, 0).await.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_s3_multipart_upload() {
+        let io_manager = S3IOManager::new(start_fake_s3().await);
+        let file_def = FileDefinition::new("test_s3_big".to_string(), "big.bin".to_string(), "test_dir".to_string());
+        let content: Vec<u8> = (0..11 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let stored = io_manager.store_file_stream(&file_def, &mut content.as_slice(), u64::MAX).await
+                .expect("Unable to store file");
+        assert_eq!(stored.size, content.len() as u64);
+        assert_eq!(stored.checksum, Util::checksum(&content));
+        assert_eq!(read_all(&io_manager, &file_def, 0).await, content);
+
+        assert!(io_manager.store_file_stream(&file_def, &mut content.as_slice(), 6 * 1024 * 1024).await.is_err());
+        assert_eq!(read_all(&io_manager, &file_def, 0).await, content);
+    }
+}
+
+
+#[cfg(test)]
+mod metadata_tests {
+    use std::collections::HashMap;
+    use crate::model::FileChange;
+    use crate::model::ChangeType;
+    use crate::model::FileDefinition;
+    use crate::model::RevisionHistory;
+    use crate::model::FileRepositoryState;
+    use crate::metadata::SqliteStore;
+    use crate::metadata::MetadataStore;
+    use crate::config::Config;
+    use crate::repository::FileRepository;
+    use crate::memory_store::InMemoryIOManager;
+    use super::TempDir;
+
+    fn store_path(dir: &TempDir) -> String {
+        format!("{}/state.db", dir.path())
+    }
+
+    #[rocket::async_test]
+    async fn test_changes_survive_reload() {
+        let dir = TempDir::new("test_changes_survive_reload");
+        let store = SqliteStore::open(&store_path(&dir)).expect("Unable to open store");
+        let mut repository = FileRepository::with_io_manager(Config::default(), InMemoryIOManager::default(), Some(MetadataStore::Sqlite(store)));
+        let file_def = FileDefinition::new(String::new(), "kept.txt".to_string(), "test_dir".to_string());
+        let kept = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        let file_def = FileDefinition::new(String::new(), "gone.txt".to_string(), "test_dir".to_string());
+        let gone = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        repository.delete(&gone).await.expect("Unable to delete file");
+
+        let store = SqliteStore::open(&store_path(&dir)).expect("Unable to reopen store");
+        let (state, contents) = store.load().expect("Unable to load state");
+        assert_eq!(state.current_revision, 3);
+        assert!(matches!(state.history.revisions[2].change, ChangeType::Delete));
+        assert!(contents.contains_key(&kept));
+        assert!(!contents.contains_key(&gone));
+        assert!(state.trash.contains_key(&gone));
+    }
+
+    #[rocket::async_test]
+    async fn test_quarantine_survives_reload() {
+        let dir = TempDir::new("test_quarantine_survives_reload");
+        let store = SqliteStore::open(&store_path(&dir)).expect("Unable to open store");
+        let mut repository = FileRepository::with_io_manager(Config::default(), InMemoryIOManager::default(), Some(MetadataStore::Sqlite(store)));
+        let file_def = FileDefinition::new(String::new(), "corrupt.txt".to_string(), "test_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        repository.quarantine(&id).expect("Unable to quarantine file");
+
+        let store = SqliteStore::open(&store_path(&dir)).expect("Unable to reopen store");
+        let (state, _) = store.load().expect("Unable to load state");
+        assert_eq!(state.quarantined.get(&id), Some(&1));
+
+        let file_def = repository.get_definition(&id).expect("File not found");
+        repository.update_stream(&file_def, &mut b"replaced".as_slice(), u64::MAX).await.expect("Unable to update file");
+        assert!(!repository.is_quarantined(&id));
+        let store = SqliteStore::open(&store_path(&dir)).expect("Unable to reopen store");
+        let (state, _) = store.load().expect("Unable to load state");
+        assert!(state.quarantined.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn test_changes_write_only_touched_rows() {
+        let dir = TempDir::new("test_changes_write_only_touched_rows");
+        let store = SqliteStore::open(&store_path(&dir)).expect("Unable to open store");
+        let mut repository = FileRepository::with_io_manager(Config::default(), InMemoryIOManager::default(), Some(MetadataStore::Sqlite(store)));
+        let mut ids = Vec::new();
+        for name in ["first.txt", "second.txt"] {
+            let file_def = FileDefinition::new(String::new(), name.to_string(), "test_dir".to_string());
+            let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+            let file_def = repository.get_definition(&id).expect("File not found");
+            repository.update_stream(&file_def, &mut b"content".as_slice(), u64::MAX).await.expect("Unable to update file");
+            ids.push(id);
+        }
+        let connection = rusqlite::Connection::open(store_path(&dir)).expect("Unable to open database");
+        connection.execute("DELETE FROM versions WHERE file_id = ?1", [&ids[0]]).expect("Unable to delete row");
+
+        let file_def = repository.get_definition(&ids[1]).expect("File not found");
+        repository.update_stream(&file_def, &mut b"changed".as_slice(), u64::MAX).await.expect("Unable to update file");
+        let store = SqliteStore::open(&store_path(&dir)).expect("Unable to reopen store");
+        let (state, _) = store.load().expect("Unable to load state");
+        assert!(!state.versions.contains_key(&ids[0]));
+        assert_eq!(state.versions[&ids[1]].len(), 2);
+    }
+
+    #[test]
+    fn test_import_legacy_state() {
+        let dir = TempDir::new("test_import_legacy_state");
+        let store = SqliteStore::open(&store_path(&dir)).expect("Unable to open store");
+        let mut file_def = FileDefinition::new("legacy".to_string(), "old.txt".to_string(), "test_dir".to_string());
+        file_def.revision = Some(1);
+        let state = FileRepositoryState {
+            current_revision: 1,
+            history: RevisionHistory { revisions: vec![FileChange::new(file_def.clone(), ChangeType::Create)] },
+            versions: HashMap::new(),
+            trash: HashMap::new(),
+            snapshots: Vec::new(),
+            snapshot_files: HashMap::new(),
+            quarantined: HashMap::new()
+        };
+        let contents = HashMap::from([("legacy".to_string(), file_def)]);
+        assert!(store.is_empty().expect("Unable to query store"));
+        store.import(&state, &contents).expect("Unable to import state");
+
+        assert!(!store.is_empty().expect("Unable to query store"));
+        let (loaded, loaded_contents) = store.load().expect("Unable to load state");
+        assert_eq!(loaded.current_revision, 1);
+        assert_eq!(loaded_contents["legacy"].name, "old.txt");
+    }
+
+    #[rocket::async_test]
+    async fn test_extras_rows_follow_changes() {
+        let dir = TempDir::new("test_extras_rows_follow_changes");
+        let store = SqliteStore::open(&store_path(&dir)).expect("Unable to open store");
+        let mut repository = FileRepository::with_io_manager(Config::default(), InMemoryIOManager::default(), Some(MetadataStore::Sqlite(store)));
+        let file_def = FileDefinition::new(String::new(), "restored.txt".to_string(), "test_dir".to_string());
+        let restored = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        repository.delete(&restored).await.expect("Unable to delete file");
+        repository.restore_trashed(&restored).await.expect("Unable to restore file");
+        for name in ["first", "second", "third"] {
+            repository.create_snapshot(name).await.expect("Unable to create snapshot");
+        }
+        repository.delete_snapshot("second").await.expect("Unable to delete snapshot");
+
+        let store = SqliteStore::open(&store_path(&dir)).expect("Unable to reopen store");
+        let (state, _) = store.load().expect("Unable to load state");
+        assert!(state.trash.is_empty());
+        let names: Vec<&str> = state.snapshots.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["first", "third"]);
+        assert_eq!(state.snapshot_files.len(), 2);
+    }
+
+    #[test]
+    fn test_legacy_extras_migrated() {
+        let dir = TempDir::new("test_legacy_extras_migrated");
+        drop(SqliteStore::open(&store_path(&dir)).expect("Unable to open store"));
+        let connection = rusqlite::Connection::open(store_path(&dir)).expect("Unable to open database");
+        let trashed = r#"{"gone":{"file":{"name":"gone.txt","path":"test_dir","id":"gone","size":0,"checksum":null,"last_update":null,"revision":1},
+                "deleted_at":{"secs_since_epoch":0,"nanos_since_epoch":0}}}"#;
+        connection.execute("INSERT INTO extras (key, value) VALUES ('trash', ?1)", [trashed]).expect("Unable to write extras");
+        drop(connection);
+
+        let store = SqliteStore::open(&store_path(&dir)).expect("Unable to reopen store");
+        let (state, _) = store.load().expect("Unable to load state");
+        assert!(state.trash.contains_key("gone"));
+        let store = SqliteStore::open(&store_path(&dir)).expect("Unable to reopen store");
+        assert!(store.load().expect("Unable to load state").0.trash.contains_key("gone"));
+    }
+
+    #[rocket::async_test]
+    async fn test_failed_save_rolls_back() {
+        let dir = TempDir::new("test_failed_save_rolls_back");
+        let store = SqliteStore::open(&store_path(&dir)).expect("Unable to open store");
+        let mut repository = FileRepository::with_io_manager(Config::default(), InMemoryIOManager::default(), Some(MetadataStore::Sqlite(store)));
+        let file_def = FileDefinition::new(String::new(), "kept.txt".to_string(), "test_dir".to_string());
+        let id = repository.create_empty(&file_def).await.expect("Unable to create empty file");
+        let file_def = repository.get_definition(&id).expect("File not found");
+
+        let connection = rusqlite::Connection::open(store_path(&dir)).expect("Unable to open database");
+        connection.execute_batch("DROP TABLE revisions;").expect("Unable to break database");
+        drop(connection);
+
+        let result = repository.update_stream(&file_def, &mut b"lost".as_slice(), u64::MAX).await;
+        assert!(result.is_err());
+        assert_eq!(repository.get_revision(), 1);
+        assert_eq!(repository.get_definition(&id).expect("File not found").size, Some(0));
+        let (_, len) = repository.open_file(&id, 0).await.expect("Unable to open file");
+        assert_eq!(len, 0);
+
+        assert!(repository.delete(&id).await.is_err());
+        assert!(repository.exists(&id));
+        assert!(repository.get_trash().is_empty());
+    }
+}
+
+
+#[cfg(test)]
+mod journal_tests {
+    use std::io::Write;
+    use crate::model::ChangeType;
+    use crate::model::FileDefinition;
+    use crate::journal::JournalStore;
+    use crate::metadata::MetadataStore;
+    use crate::config::Config;
+    use crate::repository::FileRepository;
+    use crate::memory_store::InMemoryIOManager;
+    use super::TempDir;
+
+    fn open_repository(dir: &TempDir, compaction: u64) -> FileRepository<InMemoryIOManager> {
+        let store = JournalStore::open(dir.path(), compaction).expect("Unable to open journal");
+        FileRepository::with_io_manager(Config::default(), InMemoryIOManager::default(), Some(MetadataStore::Journal(store)))
+    }
+
+    async fn create(repository: &mut FileRepository<InMemoryIOManager>, name: &str) -> String {
+        let file_def = FileDefinition::new(String::new(), name.to_string(), "test_dir".to_string());
+        repository.create_empty(&file_def).await.expect("Unable to create empty file")
+    }
+
+    #[rocket::async_test]
+    async fn test_journal_replay() {
+        let dir = TempDir::new("test_journal_replay");
+        let mut repository = open_repository(&dir, 1000);
+        let kept = create(&mut repository, "kept.txt").await;
+        let gone = create(&mut repository, "gone.txt").await;
+        repository.delete(&gone).await.expect("Unable to delete file");
+
+        let store = JournalStore::open(dir.path(), 1000).expect("Unable to reopen journal");
+        let (state, contents) = store.load().expect("Unable to load state");
+        assert_eq!(state.current_revision, 3);
+        assert!(matches!(state.history.revisions[2].change, ChangeType::Delete));
+        assert!(contents.contains_key(&kept));
+        assert!(!contents.contains_key(&gone));
+        assert!(state.trash.contains_key(&gone));
+    }
+
+    #[rocket::async_test]
+    async fn test_journal_keeps_quarantine() {
+        let dir = TempDir::new("test_journal_keeps_quarantine");
+        let mut repository = open_repository(&dir, 1000);
+        let id = create(&mut repository, "corrupt.txt").await;
+        repository.quarantine(&id).expect("Unable to quarantine file");
+
+        let store = JournalStore::open(dir.path(), 1000).expect("Unable to reopen journal");
+        let (state, _) = store.load().expect("Unable to load state");
+        assert!(state.quarantined.contains_key(&id));
+    }
+
+    #[rocket::async_test]
+    async fn test_journal_truncates_torn_tail() {
+        let dir = TempDir::new("test_journal_truncates_torn_tail");
+        let mut repository = open_repository(&dir, 1000);
+        let kept = create(&mut repository, "kept.txt").await;
+        let log = format!("{}/journal.log", dir.path());
+        let good_len = std::fs::metadata(&log).expect("No journal").len();
+        let mut file = std::fs::OpenOptions::new().append(true).open(&log).expect("Unable to open journal");
+        file.write_all(&[200, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, b'{']).expect("Unable to write torn record");
+        drop(file);
+
+        let store = JournalStore::open(dir.path(), 1000).expect("Unable to reopen journal");
+        let (state, contents) = store.load().expect("Unable to load state");
+        assert_eq!(state.current_revision, 1);
+        assert!(contents.contains_key(&kept));
+        assert_eq!(std::fs::metadata(&log).expect("No journal").len(), good_len);
+    }
+
+    #[rocket::async_test]
+    async fn test_journal_without_ids_fails_to_load() {
+        let dir = TempDir::new("test_journal_without_ids_fails_to_load");
+        let mut repository = open_repository(&dir, 1);
+        create(&mut repository, "kept.txt").await;
+        let snapshot = format!("{}/snapshot.json", dir.path());
+        let mut compacted: serde_json::Value = serde_json::from_slice(&std::fs::read(&snapshot).expect("No snapshot"))
+                .expect("Invalid snapshot");
+        compacted["contents"][0]["id"] = serde_json::Value::Null;
+        std::fs::write(&snapshot, serde_json::to_vec(&compacted).expect("Unable to serialize snapshot")).expect("Unable to write snapshot");
+
+        let store = JournalStore::open(dir.path(), 1).expect("Unable to reopen journal");
+        assert!(store.load().is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_journal_compaction() {
+        let dir = TempDir::new("test_journal_compaction");
+        let mut repository = open_repository(&dir, 2);
+        for i in 0..5 {
+            create(&mut repository, &format!("file{i}.txt")).await;
+        }
+        assert!(std::path::Path::new(dir.path()).join("snapshot.json").is_file());
+
+        let store = JournalStore::open(dir.path(), 2).expect("Unable to reopen journal");
+        let (state, contents) = store.load().expect("Unable to load state");
+        assert_eq!(state.current_revision, 5);
+        assert_eq!(contents.len(), 5);
+    }
+
+    #[rocket::async_test]
+    async fn test_journal_keeps_changes_when_compaction_fails() {
+        let dir = TempDir::new("test_journal_keeps_changes_when_compaction_fails");
+        let mut repository = open_repository(&dir, 1);
+        std::fs::create_dir_all(format!("{}/snapshot.json.tmp", dir.path())).expect("Unable to block snapshot");
+        let restored = create(&mut repository, "restored.txt").await;
+        repository.delete(&restored).await.expect("Unable to delete file");
+        repository.restore_trashed(&restored).await.expect("Unable to restore file");
+
+        let store = JournalStore::open(dir.path(), 1).expect("Unable to reopen journal");
+        let (state, contents) = store.load().expect("Unable to load state");
+        assert_eq!(state.current_revision, repository.get_revision());
+        assert!(contents.contains_key(&restored));
+        assert!(state.trash.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use crate::config::Config;
+    use crate::config::Storage;
+    use crate::config::Metadata;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_flags_override_file() {
+        let (config, _) = Config::load(&args(&["--storage", "chunked", "--version-retention", "3", "--verify"]))
+                .expect("Unable to load config");
+        assert_eq!(config.storage, Storage::Chunked);
+        assert_eq!(config.version_retention, 3);
+        assert_eq!(config.metadata, Metadata::Sqlite);
+        assert_eq!(config.get_state_path(), config.base_path);
+    }
+
+    #[test]
+    fn test_invalid_values_rejected() {
+        assert!(Config::load(&args(&["--storage", "bogus"])).is_err());
+        assert!(Config::load(&args(&["--metadata", "postgres"])).is_err());
+        assert!(Config::load(&args(&["--read-verification", "sometimes"])).is_err());
+        assert!(Config::load(&args(&["--scrub-action", "delete"])).is_err());
+        assert!(Config::load(&args(&["--read-sample-rate", "2"])).is_err());
+        assert!(Config::load(&args(&["--storage", "s3"])).is_err());
+        assert!(Config::load(&args(&["--storage"])).is_err());
+        assert!(Config::load(&args(&["unexpected"])).is_err());
+    }
+}
+
+
+#[cfg(test)]
+mod auth_tests {
+    use crate::auth::TokenStore;
+    use crate::access::Caller;
+    use crate::config::Config;
+    use crate::model::Grant;
+    use crate::model::Permission;
+    use super::TempDir;
+
+    #[test]
+    fn test_tokens_persist_hashed() {
+        let dir = TempDir::new("test_tokens_persist_hashed");
+        let config = Config { base_path: dir.path().to_string(), ..Config::default() };
+        let minted = TokenStore::open(&config).expect("Unable to open tokens")
+                .mint("client", false, None).expect("Unable to mint token");
+
+        let saved = std::fs::read_to_string(TokenStore::get_default_path(&config.base_path)).expect("No tokens file");
+        assert!(!saved.contains(&minted.token));
+        let mut tokens = TokenStore::open(&config).expect("Unable to reopen tokens");
+        let token = tokens.authenticate(&minted.token).expect("Token not accepted");
+        assert_eq!(token.name, "client");
+        assert!(tokens.authenticate("not-a-token").is_none());
+
+        assert!(tokens.revoke(&minted.details.id).expect("Unable to revoke token"));
+        let mut tokens = TokenStore::open(&config).expect("Unable to reopen tokens");
+        assert!(tokens.authenticate(&minted.token).is_none());
+    }
+
+    #[test]
+    fn test_token_changes_reach_running_stores() {
+        let dir = TempDir::new("test_token_changes_reach_running_stores");
+        let config = Config { base_path: dir.path().to_string(), ..Config::default() };
+        let mut server = TokenStore::open(&config).expect("Unable to open tokens");
+        let kept = server.mint("kept", true, None).expect("Unable to mint token");
+
+            // As `--mint-token` and `--revoke-token` would, from another process.
+        let mut cli = TokenStore::open(&config).expect("Unable to open tokens");
+        let minted = cli.mint("cli", false, None).expect("Unable to mint token");
+        assert!(server.authenticate(&minted.token).is_some());
+        assert!(cli.revoke(&minted.details.id).expect("Unable to revoke token"));
+        assert!(server.authenticate(&minted.token).is_none());
+
+            // Saving from the server keeps what the other process changed.
+        server.mint("later", false, None).expect("Unable to mint token");
+        let names: Vec<String> = TokenStore::open(&config).expect("Unable to reopen tokens").get_tokens().into_iter()
+                .map(|token| token.name)
+                .collect();
+        assert_eq!(names, vec!["kept".to_string(), "later".to_string()]);
+        assert!(cli.authenticate(&kept.token).is_some());
+    }
+
+    #[test]
+    fn test_grants_match_path_prefixes() {
+        let grant = |path: &str, permission| Grant {
+            id: None, user: Some("alice".to_string()), group: None,
+            repository: "default".to_string(), path: path.to_string(), permission
+        };
+        let caller = Caller::with_grants(vec![grant("docs", Permission::Read), grant("docs/drafts", Permission::Write)]);
+        assert_eq!(caller.permission("default", "docs"), Some(Permission::Read));
+        assert_eq!(caller.permission("default", "docs/drafts/old"), Some(Permission::Write));
+        assert_eq!(caller.permission("default", "docsx"), None);
+        assert_eq!(caller.permission("other", "docs"), None);
+        assert!(!caller.can("default", "docs", Permission::Write));
+        assert!(Caller::unrestricted().can("other", "anything", Permission::Admin));
+
+        assert_eq!(caller.permission("default", "./docs//drafts/"), Some(Permission::Write));
+        assert_eq!(caller.permission("default", "docs/drafts/../../private"), None);
+        assert!(!Caller::unrestricted().can("default", "docs/..", Permission::Read));
+    }
+}
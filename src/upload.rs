@@ -0,0 +1,163 @@
+
+use std::path::Path;
+use std::sync::Arc;
+use std::collections::HashMap;
+
+use rocket::tokio::fs;
+use rocket::tokio::fs::File;
+use rocket::tokio::fs::OpenOptions;
+use rocket::tokio::io::AsyncRead;
+use rocket::tokio::io::AsyncReadExt;
+use rocket::tokio::io::AsyncWriteExt;
+use rocket::tokio::sync::Mutex;
+use rocket::tokio::sync::OwnedMutexGuard;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::util::Util;
+use crate::model::UploadSession;
+
+
+/// Staging area for chunked uploads, kept on local disk so sessions survive restarts.
+/// Each session is a `<id>.json` descriptor next to the `<id>.part` content received so far.
+pub struct UploadManager {
+    base_path: String,
+    locks: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>      // Per session, while someone holds them.
+}
+impl UploadManager {
+    const BUFFER_SIZE: usize = 64 * 1024;
+
+    pub fn new(base_path: &str) -> Self {
+        Self { base_path: base_path.to_string(), locks: std::sync::Mutex::default() }
+    }
+
+    /// Waits for exclusive use of the session, so its chunks are appended and committed in order.
+    /// Other sessions go on in the meantime.
+    pub async fn lock_session(&self, id: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().expect("Upload locks poisoned");
+                // Locks nobody holds or waits for anymore are only referenced from here.
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(id.to_string()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+
+    pub async fn open(&self, request: &UploadSession) -> Result<UploadSession, String> {
+        fs::create_dir_all(self.get_uploads_path()).await.map_err(|e| e.to_string())?;
+
+        let mut session = request.clone();
+        let mut id = Util::new_id();
+        while Path::new(&self.get_descriptor_path(&id)).exists() {
+            id = Util::new_id();
+        }
+        session.id = Some(id.clone());
+        session.offset = None;
+
+        let descriptor = serde_json::to_string(&session).expect("Upload session serialization error.");
+        fs::write(self.get_descriptor_path(&id), descriptor).await.map_err(|e| e.to_string())?;
+        File::create(self.get_part_path(&id)).await.map_err(|e| e.to_string())?;
+
+        session.offset = Some(0);
+        Ok(session)
+    }
+
+    /// Session descriptor with `offset` set to the number of bytes received so far.
+    pub async fn get(&self, id: &str) -> Result<UploadSession, String> {
+        if !Self::is_valid_id(id) {
+            return Err("Upload session not found".to_string());
+        }
+        let descriptor = fs::read(self.get_descriptor_path(id)).await
+                .map_err(|_| "Upload session not found".to_string())?;
+        let mut session: UploadSession = serde_json::from_slice(&descriptor).map_err(|e| e.to_string())?;
+        let received = fs::metadata(self.get_part_path(id)).await.map_err(|e| e.to_string())?.len();
+        session.offset = Some(received);
+
+        Ok(session)
+    }
+
+    /// Appends `content` to the session, which must currently be at `offset`.
+    pub async fn append(&self, session: &UploadSession, content: &mut (dyn AsyncRead + Send + Unpin)) -> Result<UploadSession, String> {
+        let id = session.id.as_ref().ok_or("No id in upload session".to_string())?;
+        let mut received = session.offset.unwrap_or(0);
+
+        let mut file = OpenOptions::new().append(true).open(self.get_part_path(id)).await
+                .map_err(|e| e.to_string())?;
+        let mut buffer = vec![0u8; Self::BUFFER_SIZE];
+        loop {
+            let read = content.read(&mut buffer).await.map_err(|e| e.to_string())?;
+            if read == 0 {
+                break;
+            }
+            if received + read as u64 > session.size {
+                    // Keep what fits so the session stays consistent with its declared size.
+                let fits = (session.size - received) as usize;
+                file.write_all(&buffer[..fits]).await.map_err(|e| e.to_string())?;
+                file.flush().await.map_err(|e| e.to_string())?;
+                return Err("Chunk exceeds declared upload size".to_string());
+            }
+            file.write_all(&buffer[..read]).await.map_err(|e| e.to_string())?;
+            received += read as u64;
+        }
+        file.flush().await.map_err(|e| e.to_string())?;
+
+        let mut updated = session.clone();
+        updated.offset = Some(received);
+        Ok(updated)
+    }
+
+    /// Checks that the staged content is complete and matches the declared checksum.
+    pub async fn verify(&self, session: &UploadSession) -> Result<(), String> {
+        if session.offset != Some(session.size) {
+            return Err(format!("Upload incomplete: {} of {} bytes received",
+                    session.offset.unwrap_or(0), session.size));
+        }
+
+        let mut content = self.open_content(session).await?;
+        let mut hasher = Xxh3::new();
+        let mut buffer = vec![0u8; Self::BUFFER_SIZE];
+        loop {
+            let read = content.read(&mut buffer).await.map_err(|e| e.to_string())?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+
+        let checksum = Util::format_checksum(hasher.digest());
+        if checksum != session.checksum {
+            return Err(format!("Checksum mismatch: expected {}, got {}", session.checksum, checksum));
+        }
+        Ok(())
+    }
+
+    pub async fn open_content(&self, session: &UploadSession) -> Result<File, String> {
+        let id = session.id.as_ref().ok_or("No id in upload session".to_string())?;
+        File::open(self.get_part_path(id)).await.map_err(|e| e.to_string())
+    }
+
+    pub async fn remove(&self, id: &str) -> Result<(), String> {
+        if !Self::is_valid_id(id) {
+            return Err("Upload session not found".to_string());
+        }
+        let _ = fs::remove_file(self.get_part_path(id)).await;
+        fs::remove_file(self.get_descriptor_path(id)).await
+                .map_err(|_| "Upload session not found".to_string())
+    }
+
+    fn is_valid_id(id: &str) -> bool {
+        !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric())
+    }
+
+    fn get_uploads_path(&self) -> String {
+        let binding = Path::new(&self.base_path).join(".uploads");
+        binding.to_str().unwrap().to_string()
+    }
+    fn get_descriptor_path(&self, id: &str) -> String {
+        let binding = Path::new(&self.get_uploads_path()).join(format!("{id}.json"));
+        binding.to_str().unwrap().to_string()
+    }
+    fn get_part_path(&self, id: &str) -> String {
+        let binding = Path::new(&self.get_uploads_path()).join(format!("{id}.part"));
+        binding.to_str().unwrap().to_string()
+    }
+}
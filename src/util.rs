@@ -1,36 +1,109 @@
 
+use std::fs::File;
+use std::io::Write;
 use std::path::Path;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use rand::Rng;
 use rocket::tokio::fs;
 use xxhash_rust::xxh3;
 use rand::distributions::Alphanumeric;
 
-use crate::config::Config;
 use crate::model::FileDefinition;
 
 
 pub struct Util;
 impl Util {
-    pub async fn validate_path(path: &str) -> bool {
-        let path = Path::new(&Config::get_base_path()).join(path);
+    pub async fn validate_path(base_path: &str, path: &str) -> bool {
+        let path = Path::new(base_path).join(path);
         if !path.exists() {
             fs::create_dir_all(&path).await.expect("Unable to create directory");
         }
 
         path.exists()
     }
-    pub fn full_path(file_def: &FileDefinition) -> String {
-        let path = Path::new(&Config::get_base_path())
+    /// Path for a new scratch file under the base path; the caller removes it when done.
+    pub async fn temp_path(base_path: &str) -> Result<String, String> {
+        let dir = Path::new(base_path).join(".tmp");
+        fs::create_dir_all(&dir).await.map_err(|e| e.to_string())?;
+        let path = dir.join(Self::new_id());
+        Ok(path.to_str().expect("Invalid path").to_string())
+    }
+    pub fn validate_file(path: &str) -> bool {
+        Path::new(path).is_file()
+    }
+    pub fn full_path(base_path: &str, file_def: &FileDefinition) -> String {
+        let path = Path::new(base_path)
+                    .join(file_def.id.as_ref().expect("No id in File Definition"));
+
+        path.to_str().expect("Invalid path").to_string()
+    }
+    /// Where version `revision` of the file is kept by the folder layout.
+    pub fn version_path(base_path: &str, file_def: &FileDefinition, revision: u64) -> String {
+        let path = Path::new(base_path)
+                    .join(".versions")
+                    .join(file_def.id.as_ref().expect("No id in File Definition"))
+                    .join(revision.to_string());
+
+        path.to_str().expect("Invalid path").to_string()
+    }
+    /// Where the folder layout keeps the content of a deleted file until purged.
+    pub fn trash_path(base_path: &str, file_def: &FileDefinition) -> String {
+        let path = Path::new(base_path)
+                    .join(".trash")
                     .join(file_def.id.as_ref().expect("No id in File Definition"));
 
         path.to_str().expect("Invalid path").to_string()
     }
+    /// Replaces the small file at `path` with `content` through a synced temp file, creating its directory if needed.
+    pub fn write_file_atomic(path: &str, content: &[u8]) -> Result<(), String> {
+        let dir = Path::new(path).parent().expect("File without parent dir");
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        let temp_path = format!("{path}.tmp");
+        let mut temp = File::create(&temp_path).map_err(|e| e.to_string())?;
+        temp.write_all(content).map_err(|e| e.to_string())?;
+        temp.sync_all().map_err(|e| e.to_string())?;
+        std::fs::rename(&temp_path, path).map_err(|e| e.to_string())?;
+        File::open(dir).and_then(|dir| dir.sync_all()).map_err(|e| e.to_string())
+    }
+    #[allow(dead_code)]
+    pub fn split_full_path(full_path: &str) -> (String, String) {
+        let path = Path::new(full_path);
+        let parent = path.parent()
+                    .and_then(|p| p.to_str())
+                    .unwrap_or_default();
+        let name = path.file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or_default();
+
+        (parent.to_string(), name.to_string())
+    }
 
     pub fn checksum(content: &[u8]) -> String {
-        let digest = xxh3::xxh3_64(content);
+        Self::format_checksum(xxh3::xxh3_64(content))
+    }
+    pub fn format_checksum(digest: u64) -> String {
         format!("{:x}", digest)
     }
+    /// Formats `time` as a UTC `YYYY-MM-DD` date.
+    pub fn format_date(time: SystemTime) -> String {
+        let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let days = (secs / 86_400) as i64;
+
+            // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        format!("{:04}-{:02}-{:02}", year, month, day)
+    }
     pub fn new_id() -> String {
         rand::thread_rng()
             .sample_iter(&Alphanumeric)